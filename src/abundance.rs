@@ -0,0 +1,248 @@
+use rand::distr::{weighted::WeightedIndex, Distribution};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use rayon::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::ops::AddAssign;
+
+use crate::big_exp_float::BigExpFloat;
+use crate::classifier::Classifier;
+use crate::database::Database;
+use crate::report::ReadCounts;
+use crate::taxonomy::Taxonomy;
+use crate::utility::compute_total_kmers;
+
+/// How reads are simulated from the reference files to learn where their reads are classified.
+/// A simulated read is a sample of k-mers drawn from the rows that contain the file, weighted by
+/// the number of k-mers that use each row, and it is classified the same way skim-classify --lca
+/// classifies reads.
+#[derive(Debug, Clone, Copy)]
+pub struct Simulation {
+    pub cutoff_threshold: BigExpFloat,
+    pub kmers_per_read: usize,
+    pub max_log_ratio: Option<f64>,
+    pub n_fixed: usize,
+    pub num_reads: usize,
+    pub seed: u64,
+}
+
+impl Simulation {
+    // For each file, the fraction of the reads simulated from it that are classified to the lowest
+    // common ancestor of several files, by the tax id of that ancestor
+    pub fn lca_fractions(
+        &self,
+        database: &Database,
+        lookup_table: &[BigExpFloat],
+        taxonomy: &Taxonomy,
+    ) -> Vec<HashMap<usize, f64>> {
+        let mut classifier = Classifier::with_lookup_table(
+            database,
+            self.cutoff_threshold,
+            self.n_fixed,
+            lookup_table,
+        );
+        classifier.set_lca(taxonomy, self.max_log_ratio);
+
+        // The rows that contain each file and the number of k-mers that use them
+        let rle_uses = database.kmer_index().count_rle_uses(database.num_rles());
+        let mut file_rows = vec![(vec![], vec![]); database.num_files()];
+        for (rle_index, uses) in rle_uses.into_iter().enumerate() {
+            if uses == 0 {
+                continue;
+            }
+            for file_index in database.rles().collect_indices(rle_index) {
+                let (rows, weights) = &mut file_rows[file_index as usize];
+                rows.push(rle_index as u32);
+                weights.push(uses);
+            }
+        }
+
+        file_rows
+            .into_par_iter()
+            .enumerate()
+            .map(|(file_index, (rows, weights))| {
+                let mut lca_counts = HashMap::new();
+                // A file without k-mers has no reads to simulate
+                let distribution = match WeightedIndex::new(&weights) {
+                    Ok(distribution) => distribution,
+                    Err(_) => return HashMap::new(),
+                };
+                let mut rng = StdRng::seed_from_u64(self.seed.wrapping_add(file_index as u64));
+                let mut state = classifier.new_state();
+                for _ in 0..self.num_reads {
+                    let rows = (0..self.kmers_per_read)
+                        .map(|_| rows[distribution.sample(&mut rng)] as usize);
+                    let classification = classifier.classify_rows(&mut state, rows);
+                    // Reads classified to a single file are not classified to an ancestor
+                    if let Some(lca_tax_id) = classification
+                        .tax_id
+                        .filter(|_| classification.file_indices.len() > 1)
+                    {
+                        *lca_counts.entry(lca_tax_id).or_insert(0_usize) += 1;
+                    }
+                }
+                lca_counts
+                    .into_iter()
+                    .map(|(tax_id, count)| (tax_id, count as f64 / self.num_reads as f64))
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+// The expected number of k-mers (or syncmers) queried for a read of the given length
+pub fn expected_kmers_per_read(
+    read_len: usize,
+    kmer_len: usize,
+    syncmer_info: Option<(usize, usize)>,
+) -> usize {
+    let num_positions = read_len.saturating_sub(kmer_len - 1);
+    match syncmer_info {
+        Some(_) => {
+            let syncmer_density = compute_total_kmers(kmer_len, syncmer_info) as f64
+                / compute_total_kmers(kmer_len, None) as f64;
+            (num_positions as f64 * syncmer_density).round() as usize
+        }
+        None => num_positions,
+    }
+}
+
+/// The estimated reads and relative abundance of a reference file, or of every file with a tax id
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Abundance {
+    // Reads redistributed from the lowest common ancestors of several files
+    pub added_reads: f64,
+    // Reads classified to only this file
+    pub assigned_reads: usize,
+    // Fraction of the genomes in the sample, after correcting the reads for genome length
+    pub relative_abundance: f64,
+}
+
+impl Abundance {
+    pub fn estimated_reads(&self) -> f64 {
+        self.assigned_reads as f64 + self.added_reads
+    }
+}
+
+impl AddAssign for Abundance {
+    fn add_assign(&mut self, other: Self) {
+        self.added_reads += other.added_reads;
+        self.assigned_reads += other.assigned_reads;
+        self.relative_abundance += other.relative_abundance;
+    }
+}
+
+/// Estimates the abundance of every file of the database, like Bracken.
+/// The reads classified to the lowest common ancestor of several files are redistributed to each
+/// file in proportion to the fraction of its simulated reads classified to that ancestor, times
+/// the reads classified to only that file. The estimated reads of each file are then divided by
+/// its p-value (its fraction of all possible k-mers), which is proportional to its genome length.
+/// Returns the abundances and the number of classified reads that could not be given to any file.
+pub fn estimate_abundances(
+    database: &Database,
+    counts: &ReadCounts,
+    lca_fractions: &[HashMap<usize, f64>],
+) -> (Vec<Abundance>, usize) {
+    let mut abundances = database
+        .files()
+        .iter()
+        .map(|file| Abundance {
+            assigned_reads: counts.files.get(file).copied().unwrap_or(0),
+            ..Default::default()
+        })
+        .collect::<Vec<Abundance>>();
+
+    let num_assigned = abundances
+        .iter()
+        .map(|abundance| abundance.assigned_reads)
+        .sum::<usize>();
+    let num_lca = counts.lca_tax_ids.values().sum::<usize>();
+    // Reads classified to files that are not in the database
+    let mut num_left_out = counts.num_classified() - num_assigned - num_lca;
+
+    for (tax_id, count) in &counts.lca_tax_ids {
+        let fractions = lca_fractions
+            .iter()
+            .map(|file_fractions| file_fractions.get(tax_id).copied().unwrap_or(0.0))
+            .collect::<Vec<f64>>();
+        let mut weights = fractions
+            .iter()
+            .zip(&abundances)
+            .map(|(fraction, abundance)| fraction * abundance.assigned_reads as f64)
+            .collect::<Vec<f64>>();
+        // Without reads classified to only one of the files, files are weighted by how often their
+        // simulated reads get the tax id
+        if weights.iter().sum::<f64>() == 0.0 {
+            weights = fractions;
+        }
+        let total_weight = weights.iter().sum::<f64>();
+        if total_weight == 0.0 {
+            num_left_out += count;
+            continue;
+        }
+        for (abundance, weight) in abundances.iter_mut().zip(weights) {
+            abundance.added_reads += *count as f64 * weight / total_weight;
+        }
+    }
+
+    // Correct for genome length, since longer genomes have more reads
+    let genome_counts = abundances
+        .iter()
+        .zip(database.p_values())
+        .map(|(abundance, p)| match *p > 0.0 {
+            true => abundance.estimated_reads() / p,
+            false => 0.0,
+        })
+        .collect::<Vec<f64>>();
+    let total_genomes = genome_counts.iter().sum::<f64>();
+    if total_genomes > 0.0 {
+        for (abundance, genome_count) in abundances.iter_mut().zip(genome_counts) {
+            abundance.relative_abundance = genome_count / total_genomes;
+        }
+    }
+
+    (abundances, num_left_out)
+}
+
+// Adds up the abundances of the files with the same tax id
+pub fn sum_by_tax_id(abundances: &[Abundance], tax_ids: &[usize]) -> BTreeMap<usize, Abundance> {
+    let mut tax_id_abundances = BTreeMap::new();
+    for (abundance, tax_id) in abundances.iter().zip(tax_ids) {
+        *tax_id_abundances
+            .entry(*tax_id)
+            .or_insert_with(Abundance::default) += *abundance;
+    }
+    tax_id_abundances
+}
+
+/// Writes the abundances of (name, tax id, abundance) rows, where each line is:
+///     relative abundance (%) | estimated reads | assigned reads | added reads | tax id | name
+/// Rows without estimated reads are left out, and the rest are sorted by decreasing abundance.
+pub fn write_abundance_report<W: Write>(
+    writer: &mut W,
+    rows: &[(String, usize, Abundance)],
+) -> io::Result<()> {
+    let mut rows = rows
+        .iter()
+        .filter(|(_name, _tax_id, abundance)| abundance.estimated_reads() > 0.0)
+        .collect::<Vec<&(String, usize, Abundance)>>();
+    rows.sort_by(|a, b| {
+        b.2.relative_abundance
+            .total_cmp(&a.2.relative_abundance)
+            .then(a.1.cmp(&b.1))
+    });
+    for (name, tax_id, abundance) in rows {
+        writeln!(
+            writer,
+            "{:6.2}\t{:.2}\t{}\t{:.2}\t{}\t{}",
+            abundance.relative_abundance * 100.0,
+            abundance.estimated_reads(),
+            abundance.assigned_reads,
+            abundance.added_reads,
+            tax_id,
+            name
+        )?;
+    }
+    Ok(())
+}
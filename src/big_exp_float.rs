@@ -114,6 +114,11 @@ impl BigExpFloat {
     pub fn as_f64(&self) -> f64 {
         self.float as f64 * 2.0_f64.powi(self.exp)
     }
+
+    // Unlike as_f64, this does not underflow for very small values
+    pub fn log10(&self) -> f64 {
+        (self.float as f64).log10() + self.exp as f64 * std::f64::consts::LOG10_2
+    }
 }
 
 impl Mul for BigExpFloat {
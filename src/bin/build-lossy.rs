@@ -1,9 +1,9 @@
 use clap::Parser;
 use skim::database::Database;
-use skim::io::{create_output_file, dump_data_to_file, load_data_from_file};
+use skim::io::create_output_file;
 use skim::tracing::start_skim_tracing_subscriber;
 use std::path::Path;
-use tracing::info;
+use tracing::{info, warn};
 
 /// Creates a lossy compressed skim datbase (.cdb) file from a skim database (.db) file.
 #[derive(Parser)]
@@ -45,7 +45,15 @@ fn main() {
     let output_file = create_output_file(output_loc_path, "skim.cdb");
 
     info!("loading database at {:?}", database_path);
-    let mut database = load_data_from_file::<Database>(database_path);
+    let mut database = Database::load_from_file(database_path).unwrap_or_else(|e| panic!("{}", e));
+    info!("loaded {}", database.header());
+
+    if let Some(previous_level) = database.header().compression_level {
+        warn!(
+            "the database was already compressed using compression level: {}",
+            previous_level
+        );
+    }
 
     info!(
         "compressing database using compression level: {}",
@@ -54,7 +62,9 @@ fn main() {
     database.lossy_compression(compression_level);
 
     info!("dumping to file...");
-    dump_data_to_file(&database, output_file).expect("could not output database to file");
+    database
+        .dump_to_file(output_file)
+        .expect("could not output database to file");
 
     info!("done!");
 }
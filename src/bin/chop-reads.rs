@@ -1,10 +1,11 @@
 use bio::io::{fasta, fastq};
 use clap::Parser;
+use skim::input::SequenceFormat;
 use skim::io::create_output_file;
 use skim::tracing::start_skim_tracing_subscriber;
-use skim::utility::{get_fasta_iter_of_file, get_fastq_iter_of_file};
+use skim::utility::get_sequence_iter_of_file;
 use std::path::Path;
-use tracing::info;
+use tracing::{info, warn};
 
 /// Chop the input reads to the desired length
 #[derive(Parser)]
@@ -12,7 +13,7 @@ use tracing::info;
 #[clap(author = "Trevor S. <trevor.schneggenburger@gmail.com>")]
 struct Args {
     #[arg(short, long, action)]
-    /// Specify this flag if the file is a fasta file
+    /// Deprecated and ignored, since the format of the reads file is detected
     fasta: bool,
 
     #[arg(short, long, default_value_t = 180)]
@@ -26,7 +27,8 @@ struct Args {
     output_location: String,
 
     #[arg()]
-    /// Fasta reads file to chop
+    /// FASTA or FASTQ reads file to chop, which may be compressed.
+    /// The chopped reads are written in the same format
     reads: String,
 }
 
@@ -41,32 +43,38 @@ fn main() {
     let reads_path = Path::new(&args.reads);
 
     if args.fasta {
-        let output_file = create_output_file(output_loc_path, "chopped.fasta");
-        let mut writer = fasta::Writer::new(output_file);
+        warn!("--fasta is deprecated and ignored, since the format of the reads file is detected");
+    }
 
-        let mut fasta_reads_iter = get_fasta_iter_of_file(reads_path);
+    let mut reads_iter = get_sequence_iter_of_file(reads_path);
 
-        while let Some(Ok(read)) = fasta_reads_iter.next() {
-            let seq = if read.seq().len() < chop_length {
-                read.seq()
-            } else {
-                &read.seq()[..chop_length]
-            };
-            writer.write(read.id(), read.desc(), seq).unwrap();
-        }
-    } else {
-        let output_file = create_output_file(output_loc_path, "chopped.fastq");
-        let mut writer = fastq::Writer::new(output_file);
+    match reads_iter.format() {
+        SequenceFormat::Fasta => {
+            let output_file = create_output_file(output_loc_path, "chopped.fasta");
+            let mut writer = fasta::Writer::new(output_file);
 
-        let mut fastq_reads_iter = get_fastq_iter_of_file(reads_path);
+            while let Some(Ok(read)) = reads_iter.next() {
+                let seq = if read.seq().len() < chop_length {
+                    read.seq()
+                } else {
+                    &read.seq()[..chop_length]
+                };
+                writer.write(read.id(), read.desc(), seq).unwrap();
+            }
+        }
+        SequenceFormat::Fastq => {
+            let output_file = create_output_file(output_loc_path, "chopped.fastq");
+            let mut writer = fastq::Writer::new(output_file);
 
-        while let Some(Ok(read)) = fastq_reads_iter.next() {
-            let (seq, qual) = if read.seq().len() < chop_length {
-                (read.seq(), read.qual())
-            } else {
-                (&read.seq()[..chop_length], &read.qual()[..chop_length])
-            };
-            writer.write(read.id(), read.desc(), seq, qual).unwrap();
+            while let Some(Ok(read)) = reads_iter.next() {
+                let qual = read.qual().unwrap();
+                let (seq, qual) = if read.seq().len() < chop_length {
+                    (read.seq(), qual)
+                } else {
+                    (&read.seq()[..chop_length], &qual[..chop_length])
+                };
+                writer.write(read.id(), read.desc(), seq, qual).unwrap();
+            }
         }
     }
 
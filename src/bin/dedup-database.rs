@@ -0,0 +1,63 @@
+use clap::builder::RangedU64ValueParser;
+use clap::Parser;
+use skim::database::Database;
+use skim::io::create_output_file;
+use skim::tracing::start_skim_tracing_subscriber;
+use std::path::Path;
+use tracing::info;
+
+/// Converts a legacy skim database (.db/.cdb) file, written before databases had a header, to the
+/// current format, storing identical k-mer rows only once.
+/// Databases in the current format are already deduplicated.
+#[derive(Parser)]
+#[clap(version, about)]
+#[clap(author = "Trevor S. <trevor.schneggenburger@gmail.com>")]
+struct Args {
+    #[arg(short, long, default_value_t = std::env::current_dir().unwrap().to_str().unwrap().to_string(), verbatim_doc_comment)]
+    /// Where to write the deduplicated database file.
+    /// If a file is provided, the extension '.skim.db' ('.skim.cdb' if lossy compressed) is added.
+    /// If a directory is provided, 'skim.db' ('skim.cdb' if lossy compressed) will be the file name.
+    output_location: String,
+
+    #[arg(short, long, value_parser = RangedU64ValueParser::<usize>::new().range(1..=3), verbatim_doc_comment)]
+    /// The level the database was lossy compressed with by build-lossy, if it was (one of [1, 2, 3]).
+    /// Legacy databases do not record it, but lossy compressed databases cannot be added to or merged.
+    compression_level: Option<usize>,
+
+    #[arg()]
+    /// The legacy database file to deduplicate
+    database: String,
+}
+
+fn main() {
+    // Initialize the tracing subscriber to handle debug, info, warn, and error macro calls
+    start_skim_tracing_subscriber();
+
+    // Parse arguments from the command line
+    let args = Args::parse();
+    let database_path = Path::new(&args.database);
+    let output_loc_path = Path::new(&args.output_location);
+
+    info!(
+        "loading and deduplicating legacy database at {:?}",
+        database_path
+    );
+    let database = Database::load_legacy_from_file(database_path, args.compression_level)
+        .unwrap_or_else(|e| panic!("{}", e));
+    info!("loaded {}", database.header());
+
+    // Create the output file after loading, since it may overwrite the database. The extension of
+    // lossy compressed databases is kept.
+    let extension = match database.header().compression_level {
+        Some(_) => "skim.cdb",
+        None => "skim.db",
+    };
+    let output_file = create_output_file(output_loc_path, extension);
+
+    info!("dumping to file...");
+    database
+        .dump_to_file(output_file)
+        .expect("could not output database to file");
+
+    info!("done!");
+}
@@ -2,10 +2,11 @@ use clap::Parser;
 use indicatif::ParallelProgressIterator;
 use itertools::Itertools;
 use rayon::prelude::*;
-use roaring::RoaringBitmap;
+use roaring::RoaringTreemap;
+use skim::header::{FileHeader, FileKind};
 use skim::io::{create_output_file, dump_data_to_file, load_data_from_file, load_string2taxid};
 use skim::tracing::start_skim_tracing_subscriber;
-use skim::utility::create_bitmap;
+use skim::utility::{create_bitmap, parse_kmer_len};
 use std::path::Path;
 use tracing::info;
 
@@ -15,8 +16,8 @@ use tracing::info;
 #[clap(version, about)]
 #[clap(author = "Trevor S. <trevor.schneggenburger@gmail.com>")]
 struct Args {
-    #[arg(short, long, default_value_t = 15)]
-    /// Length of k-mer to use in the database
+    #[arg(short, long, default_value_t = 15, value_parser = parse_kmer_len)]
+    /// Length of k-mer to use in the database (at most 31)
     kmer_length: usize,
 
     #[arg(short, long, default_value_t = std::env::current_dir().unwrap().to_str().unwrap().to_string())]
@@ -82,8 +83,21 @@ fn main() {
     let output_file = create_output_file(output_loc_path, "skim.pd");
 
     info!("loading pairwise distances at {}", args.distances);
-    let (old_distances, old_file2taxid) =
-        load_data_from_file::<(Vec<Vec<u32>>, Vec<(String, usize)>)>(distances_path);
+    let (header, (old_distances, old_file2taxid)) =
+        load_data_from_file::<(Vec<Vec<u32>>, Vec<(String, usize)>)>(
+            distances_path,
+            FileKind::PairwiseDistances,
+        )
+        .unwrap_or_else(|e| panic!("{}", e));
+    info!("loaded {}", header);
+
+    // The new distances are only meaningful if they use the same k-mers as the old ones
+    if header.kmer_len != kmer_len || header.syncmer_info != syncmer_info {
+        panic!(
+            "the pairwise distances were built with k-mer length {} and syncmer info {:?}, but k-mer length {} and syncmer info {:?} were provided",
+            header.kmer_len, header.syncmer_info, kmer_len, syncmer_info
+        );
+    }
     let old_file2taxid_len = old_file2taxid.len();
 
     info!("loading new file2taxid at {:?}", new_file2taxid_path);
@@ -94,7 +108,7 @@ fn main() {
         .par_iter()
         .progress()
         .map(|(file, _taxid)| create_bitmap(old_ref_dir_path.join(file), kmer_len, syncmer_info))
-        .collect::<Vec<RoaringBitmap>>();
+        .collect::<Vec<RoaringTreemap>>();
 
     info!(
         "{} files need to be added, creating roaring bitmaps for new file2taxid...",
@@ -104,7 +118,7 @@ fn main() {
         .par_iter()
         .progress()
         .map(|(file, _taxid)| create_bitmap(new_ref_dir_path.join(file), kmer_len, syncmer_info))
-        .collect::<Vec<RoaringBitmap>>();
+        .collect::<Vec<RoaringTreemap>>();
 
     info!("filling out distance matrix...");
     let all_bitmaps = old_bitmaps
@@ -150,7 +164,11 @@ fn main() {
         .chain(new_distances.into_iter())
         .collect_vec();
 
-    dump_data_to_file(&(all_distances, all_file2taxid), output_file).unwrap();
+    let header = FileHeader {
+        num_files: all_file2taxid.len(),
+        ..header
+    };
+    dump_data_to_file(&header, &(all_distances, all_file2taxid), output_file).unwrap();
 
     info!("done!");
 }
@@ -0,0 +1,178 @@
+use clap::builder::RangedU64ValueParser;
+use clap::Parser;
+use skim::abundance::{
+    estimate_abundances, expected_kmers_per_read, sum_by_tax_id, write_abundance_report, Abundance,
+    Simulation,
+};
+use skim::big_exp_float::BigExpFloat;
+use skim::database::Database;
+use skim::io::create_output_file;
+use skim::report::ReadCounts;
+use skim::taxonomy::Taxonomy;
+use skim::tracing::start_skim_tracing_subscriber;
+use std::io::{BufWriter, Write};
+use std::ops::Neg;
+use std::path::Path;
+use tracing::{info, warn};
+
+/// Estimates the abundance of each reference in a sample from a readid2file (.r2f) written by skim-classify.
+/// Like Bracken, reads classified to the lowest common ancestor of several files (with --lca) are redistributed
+/// to those files using reads simulated from the database, and the reads of each file are corrected for its genome length.
+#[derive(Parser)]
+#[clap(version, about)]
+#[clap(author = "Trevor S. <trevor.schneggenburger@gmail.com>")]
+struct Args {
+    #[arg(short, long, default_value_t = 12, verbatim_doc_comment)]
+    /// The exponent, e, used in the equation 10^{-e} when classifying simulated reads.
+    /// Should match the exponent used by skim-classify.
+    exponent: i32,
+
+    #[arg(short, long, action)]
+    /// Report the abundance of each reference file, even if a taxonomy is available
+    files: bool,
+
+    #[arg(long, verbatim_doc_comment)]
+    /// The --lca-log-ratio used by skim-classify, if any.
+    lca_log_ratio: Option<f64>,
+
+    #[arg(short, long, default_value_t = 100, verbatim_doc_comment)]
+    /// The fixed number of trials to use in the binomial function.
+    /// Should match the value used by skim-classify.
+    n_fixed: usize,
+
+    #[arg(long, default_value_t = 1000, value_parser = RangedU64ValueParser::<usize>::new().range(1..), verbatim_doc_comment)]
+    /// The number of reads simulated from each reference file (at least 1).
+    num_simulated_reads: usize,
+
+    #[arg(short, long, default_value_t = std::env::current_dir().unwrap().to_str().unwrap().to_string(), verbatim_doc_comment)]
+    /// Where to write the abundance (.abundance) file.
+    /// If a file is provided, the extension '.skim.abundance' is added.
+    /// If a directory is provided, 'skim.abundance' will be the file name.
+    output_location: String,
+
+    #[arg(short = 'l', long, default_value_t = 1000, verbatim_doc_comment)]
+    /// The length of the simulated reads, which should be close to the typical length of the classified reads.
+    read_length: usize,
+
+    #[arg(long, default_value_t = 0)]
+    /// The seed of the random number generator used to simulate reads
+    seed: u64,
+
+    #[arg(long, verbatim_doc_comment)]
+    /// Directory with the NCBI taxonomy (nodes.dmp and names.dmp) used for classification.
+    /// Overrides the taxonomy embedded in the database.
+    taxonomy: Option<String>,
+
+    #[arg()]
+    /// The database (.db/.cdb) file used for classification
+    database: String,
+
+    #[arg()]
+    /// The readid2file (.r2f) file written by skim-classify
+    readid2file: String,
+}
+
+fn main() {
+    // Initialize the tracing subscriber to handle debug, info, warn, and error macro calls
+    start_skim_tracing_subscriber();
+
+    // Parse arguments from the command line
+    let args = Args::parse();
+    let database_path = Path::new(&args.database);
+    let output_loc_path = Path::new(&args.output_location);
+    let readid2file_path = Path::new(&args.readid2file);
+
+    // Create the output file so it errors if a bad output file is provided before computation
+    let mut writer = BufWriter::new(create_output_file(output_loc_path, "skim.abundance"));
+
+    info!("loading database at {:?}", database_path);
+    let database = Database::load_from_file_mmap(database_path).unwrap_or_else(|e| panic!("{}", e));
+    info!("loaded {}", database.header());
+
+    info!("counting reads in {:?}", readid2file_path);
+    let counts = ReadCounts::from_r2f(readid2file_path).unwrap_or_else(|e| panic!("{}", e));
+    info!(
+        "{} reads, {} classified",
+        counts.num_reads(),
+        counts.num_classified()
+    );
+
+    let taxonomy = match args.taxonomy {
+        Some(taxonomy_dir) => {
+            info!("loading taxonomy at {}", taxonomy_dir);
+            Some(
+                Taxonomy::from_ncbi(Path::new(&taxonomy_dir), database.tax_ids())
+                    .unwrap_or_else(|e| panic!("{}", e)),
+            )
+        }
+        None => database.taxonomy().cloned(),
+    };
+
+    // Only reads classified to several files need to be redistributed
+    let lca_fractions = if counts.lca_tax_ids.is_empty() {
+        vec![]
+    } else {
+        let taxonomy = taxonomy.as_ref().expect(
+            "reads classified with --lca require a taxonomy, but none is embedded in the database or provided with --taxonomy",
+        );
+        let header = database.header();
+        let simulation = Simulation {
+            cutoff_threshold: BigExpFloat::from_f64(10.0_f64.powi(args.exponent.neg())),
+            kmers_per_read: expected_kmers_per_read(
+                args.read_length,
+                header.kmer_len,
+                header.syncmer_info,
+            ),
+            max_log_ratio: args.lca_log_ratio,
+            n_fixed: args.n_fixed,
+            num_reads: args.num_simulated_reads,
+            seed: args.seed,
+        };
+
+        info!("computing lookup table...");
+        let lookup_table = database.compute_loookup_table(args.n_fixed as u64);
+
+        info!(
+            "simulating {} reads of {} k-mers from each file...",
+            simulation.num_reads, simulation.kmers_per_read
+        );
+        simulation.lca_fractions(&database, &lookup_table, taxonomy)
+    };
+
+    info!("estimating abundances...");
+    let (abundances, num_left_out) = estimate_abundances(&database, &counts, &lca_fractions);
+    if num_left_out > 0 {
+        warn!(
+            "{} classified reads could not be given to any file and are left out",
+            num_left_out
+        );
+    }
+
+    let rows = match taxonomy {
+        Some(taxonomy) if !args.files => sum_by_tax_id(&abundances, database.tax_ids())
+            .into_iter()
+            .map(|(tax_id, abundance)| {
+                (
+                    taxonomy.name(tax_id).unwrap_or_default().to_string(),
+                    tax_id,
+                    abundance,
+                )
+            })
+            .collect::<Vec<(String, usize, Abundance)>>(),
+        _ => database
+            .files()
+            .iter()
+            .cloned()
+            .zip(database.tax_ids().iter().copied())
+            .zip(abundances)
+            .map(|((file, tax_id), abundance)| (file, tax_id, abundance))
+            .collect(),
+    };
+
+    info!("writing abundances...");
+    write_abundance_report(&mut writer, &rows)
+        .and_then(|_| writer.flush())
+        .expect("could not write to output file");
+
+    info!("done!");
+}
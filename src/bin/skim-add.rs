@@ -0,0 +1,103 @@
+use clap::Parser;
+use indicatif::ParallelProgressIterator;
+use rayon::prelude::*;
+use roaring::RoaringTreemap;
+use skim::database::Database;
+use skim::io::{create_output_file, load_string2taxid};
+use skim::taxonomy::Taxonomy;
+use skim::tracing::start_skim_tracing_subscriber;
+use skim::utility::create_bitmap;
+use std::path::Path;
+use tracing::info;
+
+/// Adds the files of a file2taxid (.f2t) file to an existing skim database (.db) file.
+/// The new files are appended after the files already in the database.
+#[derive(Parser)]
+#[clap(version, about)]
+#[clap(author = "Trevor S. <trevor.schneggenburger@gmail.com>")]
+struct Args {
+    #[arg(short, long, default_value_t = std::env::current_dir().unwrap().to_str().unwrap().to_string(), verbatim_doc_comment)]
+    /// Where to write the updated database (.db) file.
+    /// If a file is provided, the extension '.skim.db' is added.
+    /// If a directory is provided, 'skim.db' will be the file name.
+    output_location: String,
+
+    #[arg(long, verbatim_doc_comment)]
+    /// Directory with the NCBI taxonomy (nodes.dmp and names.dmp) to embed in the database.
+    /// Required if the database has a taxonomy that does not contain the new tax ids.
+    taxonomy: Option<String>,
+
+    #[arg()]
+    /// The database (.db) file to add to
+    database: String,
+
+    #[arg()]
+    /// The file2taxid (.f2t) file of the files to add
+    file2taxid: String,
+
+    #[arg()]
+    /// Directory with the FASTA files to add
+    reference_directory: String,
+}
+
+fn main() {
+    // Initialize the tracing subscriber to handle debug, info, warn, and error macro calls
+    start_skim_tracing_subscriber();
+
+    // Parse arguments from the command line
+    let args = Args::parse();
+    let database_path = Path::new(&args.database);
+    let file2taxid_path = Path::new(&args.file2taxid);
+    let output_loc_path = Path::new(&args.output_location);
+    let ref_dir_path = Path::new(&args.reference_directory);
+
+    info!("loading database at {:?}", database_path);
+    let mut database = Database::load_from_file(database_path).unwrap_or_else(|e| panic!("{}", e));
+    let header = database.header();
+    info!("loaded {}", header);
+
+    // Load the file2taxid of the new files
+    info!("loading file2taxid at {}", args.file2taxid);
+    let (files, tax_ids): (Vec<String>, Vec<usize>) =
+        load_string2taxid(file2taxid_path).into_iter().unzip();
+
+    // Replace the taxonomy with one that covers both the old and the new tax ids
+    if let Some(taxonomy_dir) = args.taxonomy {
+        info!("loading taxonomy at {}", taxonomy_dir);
+        let all_tax_ids = [database.tax_ids(), &tax_ids[..]].concat();
+        let taxonomy = Taxonomy::from_ncbi(Path::new(&taxonomy_dir), &all_tax_ids)
+            .unwrap_or_else(|e| panic!("{}", e));
+        database
+            .set_taxonomy(taxonomy)
+            .unwrap_or_else(|e| panic!("{}", e));
+    }
+
+    info!("creating roaring bitmaps for {} new files...", files.len());
+    let bitmaps = files
+        .par_iter()
+        .progress()
+        .map(|file| {
+            create_bitmap(
+                ref_dir_path.join(file),
+                header.kmer_len,
+                header.syncmer_info,
+            )
+        })
+        .collect::<Vec<RoaringTreemap>>();
+
+    info!("adding files to database...");
+    database
+        .add_files(bitmaps, files, tax_ids)
+        .unwrap_or_else(|e| panic!("{}", e));
+
+    // Create the output file only once the files were added, since it may overwrite the database,
+    // which a rejected add would otherwise leave empty
+    let output_file = create_output_file(output_loc_path, "skim.db");
+
+    info!("dumping to file...");
+    database
+        .dump_to_file(output_file)
+        .expect("could not serialize database to file");
+
+    info!("done!");
+}
@@ -1,13 +1,16 @@
 use clap::Parser;
 use indicatif::ParallelProgressIterator;
 use rayon::prelude::*;
-use roaring::RoaringBitmap;
+use roaring::RoaringTreemap;
 use skim::consts::{DEFAULT_K, DEFAULT_S, DEFAULT_T};
 use skim::database::Database;
-use skim::io::{create_output_file, dump_data_to_file, load_string2taxid};
+use skim::io::{create_output_file, load_string2taxid};
+use skim::kmer_index::KmerIndexKind;
+use skim::shard::ShardConfig;
+use skim::taxonomy::Taxonomy;
 use skim::tracing::start_skim_tracing_subscriber;
-use skim::utility::create_bitmap;
-use std::path::Path;
+use skim::utility::{create_bitmap, parse_kmer_len, parse_memory_size};
+use std::path::{Path, PathBuf};
 use tracing::info;
 
 /// Creates a skim database (.db) file from a file2taxid (.f2t) file.
@@ -16,10 +19,24 @@ use tracing::info;
 #[clap(version, about)]
 #[clap(author = "Trevor S. <trevor.schneggenburger@gmail.com>")]
 struct Args {
-    #[arg(short, long, default_value_t = DEFAULT_K)]
-    /// Length of k-mer to use in the database
+    #[arg(short, long, value_enum, verbatim_doc_comment)]
+    /// Type of index used to look up k-mers in the database.
+    /// If not provided, the fastest index that fits in a reasonable amount of memory is used.
+    /// A forced kind whose table over all possible k-mers is larger than a sorted index is rejected.
+    index_kind: Option<KmerIndexKind>,
+
+    #[arg(short, long, default_value_t = DEFAULT_K, value_parser = parse_kmer_len)]
+    /// Length of k-mer to use in the database (at most 31)
     kmer_length: usize,
 
+    #[arg(short, long, value_parser = parse_memory_size, verbatim_doc_comment)]
+    /// Build the database in bounded memory by spilling k-mers to temporary files.
+    /// The value is the most memory (e.g. 512M or 16G) used for the k-mers of the files read at a time
+    /// (fewer files are read in parallel if needed, but the k-mers of one file are always held at once),
+    /// and for the k-mers of a single shard.
+    /// The final database must still fit in memory.
+    max_memory: Option<usize>,
+
     #[arg(short, long, default_value_t = std::env::current_dir().unwrap().to_str().unwrap().to_string(), verbatim_doc_comment)]
     /// Where to write the database (.db) file.
     /// If a file is provided, the extension '.skim.db' is added.
@@ -35,6 +52,16 @@ struct Args {
     /// 0 indicates no offset (open syncmers)
     syncmer_offset: usize,
 
+    #[arg(long, verbatim_doc_comment)]
+    /// Directory with the NCBI taxonomy (nodes.dmp and names.dmp) to embed in the database.
+    /// Only the tax ids of the file2taxid and their ancestors are kept.
+    taxonomy: Option<String>,
+
+    #[arg(long, verbatim_doc_comment)]
+    /// Where to write temporary files when --max-memory is provided.
+    /// Defaults to the directory of the output file.
+    temp_dir: Option<String>,
+
     #[arg()]
     /// The file2taxid (.f2t) file. Preferrably ordered (.o.f2t).
     file2taxid: String,
@@ -78,18 +105,74 @@ fn main() {
     let (files, tax_ids): (Vec<String>, Vec<usize>) =
         load_string2taxid(file2taxid_path).into_iter().unzip();
 
-    info!("creating roaring bitmaps for each file...");
-    let bitmaps = files
-        .par_iter()
-        .progress()
-        .map(|file| create_bitmap(ref_dir_path.join(file), kmer_len, syncmer_info))
-        .collect::<Vec<RoaringBitmap>>();
+    // Load the taxonomy before construction so that missing tax ids are found early
+    let taxonomy = args.taxonomy.map(|taxonomy_dir| {
+        info!("loading taxonomy at {}", taxonomy_dir);
+        let taxonomy = Taxonomy::from_ncbi(Path::new(&taxonomy_dir), &tax_ids)
+            .unwrap_or_else(|e| panic!("{}", e));
+        info!("keeping {} taxonomy nodes", taxonomy.len());
+        taxonomy
+    });
+
+    let mut database = match args.max_memory {
+        Some(max_memory) => {
+            let temp_dir = match args.temp_dir {
+                Some(temp_dir) => PathBuf::from(temp_dir),
+                None if output_loc_path.is_dir() => output_loc_path.to_path_buf(),
+                None => output_loc_path
+                    .parent()
+                    .filter(|parent| !parent.as_os_str().is_empty())
+                    .unwrap_or(Path::new("."))
+                    .to_path_buf(),
+            };
+            let config = ShardConfig {
+                max_memory,
+                temp_dir,
+            };
+
+            info!("constructing database in shards...");
+            Database::from_files_sharded(
+                ref_dir_path,
+                files,
+                tax_ids,
+                kmer_len,
+                syncmer_info,
+                args.index_kind,
+                &config,
+            )
+            .unwrap_or_else(|e| panic!("{}", e))
+        }
+        None => {
+            info!("creating roaring bitmaps for each file...");
+            let bitmaps = files
+                .par_iter()
+                .progress()
+                .map(|file| create_bitmap(ref_dir_path.join(file), kmer_len, syncmer_info))
+                .collect::<Vec<RoaringTreemap>>();
+
+            info!("constructing database...");
+            Database::from(
+                bitmaps,
+                files,
+                tax_ids,
+                kmer_len,
+                syncmer_info,
+                args.index_kind,
+            )
+            .unwrap_or_else(|e| panic!("{}", e))
+        }
+    };
 
-    info!("constructing database...");
-    let database = Database::from(bitmaps, files, tax_ids, kmer_len, syncmer_info);
+    if let Some(taxonomy) = taxonomy {
+        database
+            .set_taxonomy(taxonomy)
+            .unwrap_or_else(|e| panic!("{}", e));
+    }
 
     info!("dumping to file...");
-    dump_data_to_file(&database, output_file).expect("could not serialize database to file");
+    database
+        .dump_to_file(output_file)
+        .expect("could not serialize database to file");
 
     info!("done!");
 }
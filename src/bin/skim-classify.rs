@@ -1,15 +1,23 @@
 use clap::Parser;
-use rayon::prelude::*;
 use skim::big_exp_float::BigExpFloat;
+use skim::classifier::{Classification, Classifier, ClassifierState, Decision};
 use skim::database::Database;
-use skim::io::{create_output_file, load_data_from_file};
-use skim::tracing::start_skim_tracing_subscriber;
-use skim::utility::get_fastq_iter_of_file;
+use skim::input::{mate_id, PairedRecords, SequenceRecord};
+use skim::io::{
+    create_output_file, create_output_writer, exit_on_write_error, flush_periodically, is_stdio,
+    is_stream,
+};
+use skim::pipeline::{run_pipeline, Batches};
+use skim::report::{write_file_report, write_taxonomy_report, ReadCounts};
+use skim::taxonomy::Taxonomy;
+use skim::tracing::{start_skim_stderr_tracing_subscriber, start_skim_tracing_subscriber};
+use skim::utility::get_sequence_iter_of_file;
 use std::io::{BufWriter, Write};
 use std::ops::Neg;
 use std::path::Path;
-use std::sync::Mutex;
-use std::time::Instant;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 use tracing::{debug, info, warn};
 
 /// Classifies the input reads using a skim database (.db/.cdb) file.
@@ -18,34 +26,124 @@ use tracing::{debug, info, warn};
 #[clap(version, about)]
 #[clap(author = "Trevor S. <trevor.schneggenburger@gmail.com>")]
 struct Args {
+    #[arg(short, long, verbatim_doc_comment)]
+    /// The number of reads that each thread classifies at a time.
+    /// By default, 1024 reads, or a single read if the reads are read from stdin or a named pipe, so each read
+    /// is classified as soon as it arrives.
+    batch_size: Option<usize>,
+
+    #[arg(long, conflicts_with_all = ["interleaved", "mates"], verbatim_doc_comment)]
+    /// Simulate adaptive sampling by classifying each read in chunks of this many bases, stopping as soon as it
+    /// is classified or rejected (see --max-read-length). Each read is reported as classified by the bases so far.
+    chunk_size: Option<usize>,
+
     #[arg(short, long, default_value_t = 12, verbatim_doc_comment)]
     /// The exponent, e, used in the equation 10^{-e}.
     /// Any calculated p-value below 10^{-e} will result in a classification.
     exponent: i32,
 
+    #[arg(long, verbatim_doc_comment)]
+    /// Flush the readid2file at least every this many milliseconds, so a process reading it as it is written
+    /// (e.g. through stdout or a named pipe) sees each classification with low latency. 0 flushes after every batch of reads.
+    /// By default, output to stdout or a named pipe is flushed every 100 ms, and output to a file is only
+    /// flushed when its buffer is full.
+    flush_interval: Option<u64>,
+
+    #[arg(long, action, conflicts_with = "mates", verbatim_doc_comment)]
+    /// The reads file is an interleaved paired-end file, in which each R1 record is followed by its R2 record.
+    /// The mates of each pair are classified together, as with a mates file.
+    interleaved: bool,
+
+    #[arg(long, action, verbatim_doc_comment)]
+    /// Classify each read as the lowest common ancestor of every file with a p-value below the cutoff,
+    /// instead of the single file with the lowest p-value.
+    /// Requires a taxonomy, either embedded in the database or provided with --taxonomy.
+    lca: bool,
+
+    #[arg(long, requires = "lca", verbatim_doc_comment)]
+    /// Only include files in the lowest common ancestor whose p-value is within this many orders of
+    /// magnitude (log10) of the lowest p-value.
+    /// By default, every file with a p-value below the cutoff is included.
+    lca_log_ratio: Option<f64>,
+
+    #[arg(long, requires = "chunk_size", verbatim_doc_comment)]
+    /// With --chunk-size, only classify the first this many bases of each read (as chop-reads does), and reject
+    /// a read as soon as no file can reach the cutoff within that many bases.
+    max_read_length: Option<usize>,
+
     #[arg(short, long, default_value_t = 100, verbatim_doc_comment)]
     /// The fixed number of trials to use in the binomial function.
     n_fixed: usize,
 
+    #[arg(long, action, verbatim_doc_comment)]
+    /// Read the whole database into memory (verifying its checksum) instead of memory-mapping it.
+    /// Memory-mapping starts classification almost instantly and lets multiple processes share the database.
+    no_mmap: bool,
+
     #[arg(short, long, default_value_t = std::env::current_dir().unwrap().to_str().unwrap().to_string(), verbatim_doc_comment)]
     /// Where to write the readid2file (.r2f) file.
     /// If a file is provided, the extension '.skim.r2f' is added.
     /// If a directory is provided, 'skim.r2f' will be the file name.
+    /// If a named pipe is provided, it is written to as is.
+    /// If '-' is provided, the readid2file is written to stdout and logs to stderr.
     output_location: String,
 
+    #[arg(short, long, action, verbatim_doc_comment)]
+    /// Also write a Kraken2-style report (.report) next to the readid2file (.r2f).
+    /// Without a taxonomy, the number of reads classified to each reference file is reported instead.
+    report: bool,
+
+    #[arg(long, verbatim_doc_comment)]
+    /// Directory with the NCBI taxonomy (nodes.dmp and names.dmp) to use for --lca and --report.
+    /// Overrides the taxonomy embedded in the database.
+    taxonomy: Option<String>,
+
+    #[arg(long, verbatim_doc_comment)]
+    /// Also write the (at most) N files with the lowest p-values for each read to a .top file next to the readid2file (.r2f).
+    /// Each line is the read id, the rank of the file, the file, its tax id, its observed hits, its hits scaled to
+    /// n_fixed, the total number of k-mers queried, and the log10 of its p-value.
+    top: Option<usize>,
+
+    #[arg(short, long, verbatim_doc_comment)]
+    /// The number of threads classifying reads, besides the threads reading the reads and writing the output.
+    /// By default, the number of logical cores (or RAYON_NUM_THREADS).
+    threads: Option<usize>,
+
+    #[arg(long, action, verbatim_doc_comment)]
+    /// Write each batch of reads as soon as it is classified, instead of in the order of the reads file.
+    /// This avoids holding batches that finish before an earlier one, but the order of the readid2file
+    /// differs between runs.
+    unordered: bool,
+
     #[arg()]
     /// The database (.db/.cdb) file
     database: String,
 
     #[arg()]
-    /// FASTQ reads file to query
+    /// FASTA or FASTQ reads file to query, optionally compressed with gzip, bgzip, bzip2, xz or zstd.
+    /// If '-' is provided, the reads are read from stdin.
     reads: String,
+
+    #[arg(verbatim_doc_comment)]
+    /// FASTA or FASTQ file with the mates (R2) of the paired-end reads (R1) in the reads file.
+    /// The k-mer hits of both mates are pooled into a single classification per pair, reported under
+    /// their shared id (without a "/1" or "/2" suffix).
+    mates: Option<String>,
 }
 
-fn main() {
-    // Initialize the tracing subscriber to handle debug, info, warn, and error macro calls
-    start_skim_tracing_subscriber();
+// The output of classifying a batch of reads
+#[derive(Default)]
+struct ClassifiedBatch<'a> {
+    classifications: Vec<Option<(&'a str, usize)>>,
+    hit_lookup_time: f64,
+    num_bp: usize,
+    num_rejected: usize,
+    prob_calc_time: f64,
+    r2f_lines: String,
+    top_lines: String,
+}
 
+fn main() {
     // Parse arguments from the command line
     let args = Args::parse();
     let cutoff_threshold = BigExpFloat::from_f64(10.0_f64.powi(args.exponent.neg()));
@@ -53,68 +151,274 @@ fn main() {
     let output_loc_path = Path::new(&args.output_location);
     let reads_path = Path::new(&args.reads);
 
+    // Initialize the tracing subscriber to handle debug, info, warn, and error macro calls
+    // Logs go to stderr if the readid2file is written to stdout
+    if is_stdio(output_loc_path) {
+        start_skim_stderr_tracing_subscriber();
+    } else {
+        start_skim_tracing_subscriber();
+    }
+
+    // The report and candidates are written next to the readid2file, so it must be a file
+    if is_stream(output_loc_path) && (args.report || args.top.is_some()) {
+        panic!(
+            "--report and --top require an output location other than stdout ('-') or a named pipe"
+        );
+    }
+    if is_stdio(reads_path) && args.mates.as_deref().map(Path::new).is_some_and(is_stdio) {
+        panic!("only one of the reads and mates files can be read from stdin ('-')");
+    }
+    if args.chunk_size == Some(0) {
+        panic!("--chunk-size must be at least 1 bp");
+    }
+
+    // Also use the threads for the lookup table
+    if let Some(threads) = args.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .expect("could not create the thread pool");
+    }
+
     // Create the output file so it errors if a bad output file is provided before computation
-    let output_file = create_output_file(output_loc_path, "skim.r2f");
+    let output_file = create_output_writer(output_loc_path, "skim.r2f");
+    let flush_interval = match args.flush_interval {
+        Some(flush_interval) => Some(Duration::from_millis(flush_interval)),
+        None if is_stream(output_loc_path) => Some(Duration::from_millis(100)),
+        None => None,
+    };
+    let flush_every_batch = flush_interval.is_some_and(|interval| interval.is_zero());
 
-    // Create a mutex over a writer to allow multiple threads to write to the output file
+    // Create a mutex over a writer so it can be flushed while another thread writes to it
     let output_writer = Mutex::new(BufWriter::new(output_file));
-
-    let stats = Mutex::new((0, 0, 0.0, 0.0));
+    let mut top_writer = args
+        .top
+        .map(|_| BufWriter::new(create_output_file(output_loc_path, "skim.top")));
 
     info!("loading database at {:?}", database_path);
-    let database = load_data_from_file::<Database>(database_path);
+    let database = if args.no_mmap {
+        Database::load_from_file(database_path)
+    } else {
+        Database::load_from_file_mmap(database_path)
+    }
+    .unwrap_or_else(|e| panic!("{}", e));
+    info!("loaded {}", database.header());
+
+    // Find the taxonomy used to compute lowest common ancestors and the report
+    let taxonomy = match &args.taxonomy {
+        Some(taxonomy_dir) => {
+            info!("loading taxonomy at {}", taxonomy_dir);
+            Some(
+                Taxonomy::from_ncbi(Path::new(taxonomy_dir), database.tax_ids())
+                    .unwrap_or_else(|e| panic!("{}", e)),
+            )
+        }
+        None => database.taxonomy().cloned(),
+    };
+    let lca_taxonomy = match (&taxonomy, args.lca) {
+        (Some(taxonomy), true) => Some(taxonomy),
+        (None, true) => panic!(
+            "--lca requires a taxonomy, but none is embedded in the database or provided with --taxonomy"
+        ),
+        (_, false) => None,
+    };
 
     info!("computing lookup table...");
-    let lookup_table = database.compute_loookup_table(args.n_fixed as u64);
+    let mut classifier = Classifier::new(&database, cutoff_threshold, args.n_fixed);
+    if let Some(taxonomy) = lca_taxonomy {
+        classifier.set_lca(taxonomy, args.lca_log_ratio);
+    }
 
     info!(
         "classifying reads with maximum cutoff threshold {}...",
         10.0_f64.powi(args.exponent.neg())
     );
-    let read_iter = get_fastq_iter_of_file(reads_path);
+    // Each read is the records of its mates, which is a single record unless the reads are paired
+    let paired = args.mates.is_some() || args.interleaved;
+    let read_iter: Box<dyn Iterator<Item = Result<Vec<SequenceRecord>, String>> + Send> =
+        match &args.mates {
+            Some(mates) => Box::new(
+                PairedRecords::from_files(reads_path, Path::new(mates))
+                    .unwrap_or_else(|e| panic!("{}", e))
+                    .map(|pair| pair.map(|(mate_1, mate_2)| vec![mate_1, mate_2])),
+            ),
+            None if args.interleaved => Box::new(
+                PairedRecords::from_interleaved_file(reads_path)
+                    .unwrap_or_else(|e| panic!("{}", e))
+                    .map(|pair| pair.map(|(mate_1, mate_2)| vec![mate_1, mate_2])),
+            ),
+            None => Box::new(
+                get_sequence_iter_of_file(reads_path)
+                    .map(|record| record.map(|record| vec![record])),
+            ),
+        };
+    // Streamed reads are classified as they arrive, instead of waiting for a full batch
+    let batch_size = args.batch_size.unwrap_or(
+        if is_stream(reads_path) || args.mates.as_deref().map(Path::new).is_some_and(is_stream) {
+            1
+        } else {
+            1024
+        },
+    );
+    let num_threads = args.threads.unwrap_or_else(rayon::current_num_threads);
     let start_time = Instant::now();
 
-    read_iter
-        .par_bridge()
-        .into_par_iter()
-        .for_each(|record_result| match record_result {
-            Err(_) => {
-                warn!("error encountered while reading fastq file");
-                warn!("skipping the read that caused the error")
-            }
-            Ok(record) => {
-                let (classification, (hit_lookup_time, prob_calc_time)) =
-                    database.classify(record.seq(), cutoff_threshold, args.n_fixed, &lookup_table);
-
-                {
-                    let mut stats = stats.lock().unwrap();
-
-                    stats.0 += 1;
-                    stats.1 += record.seq().len();
-                    stats.2 += hit_lookup_time;
-                    stats.3 += prob_calc_time;
-                }
+    // Classify the reads of a batch, keeping the lines to write in order
+    let classify_batch =
+        |state: &mut ClassifierState, batch: Vec<Result<Vec<SequenceRecord>, String>>| {
+            let mut classified = ClassifiedBatch::default();
+            for mates_result in batch {
+                let mates = match mates_result {
+                    Ok(mates) => mates,
+                    // Skipping a pair would put every pair after it out of sync
+                    Err(e) if paired => panic!("could not read paired-end reads: {}", e),
+                    Err(_) => {
+                        warn!("error encountered while reading reads file");
+                        warn!("skipping the read that caused the error");
+                        continue;
+                    }
+                };
+                let sequences = mates.iter().map(|mate| mate.seq()).collect::<Vec<&[u8]>>();
+                let read_id = if paired {
+                    mate_id(mates[0].id())
+                } else {
+                    mates[0].id()
+                };
 
-                // Write classification result to output file
-                let mut writer = output_writer.lock().unwrap();
-                match classification {
-                    Some((file, taxid)) => {
-                        writer
-                            .write(format!("C\t{}\t{}\t{}\n", record.id(), taxid, file).as_bytes())
-                            .expect("could not write to output file");
+                let result = match args.chunk_size {
+                    Some(chunk_size) => {
+                        // Add chunks of the read until there is a decision, and only count the
+                        // bases that were needed
+                        let sequence = match args.max_read_length {
+                            Some(max_read_length) => {
+                                &sequences[0][..sequences[0].len().min(max_read_length)]
+                            }
+                            None => sequences[0],
+                        };
+                        // An empty read has no chunks, and is unclassified
+                        let mut result = Classification::unclassified();
+                        classifier.start_read(state, args.max_read_length);
+                        for chunk in sequence.chunks(chunk_size) {
+                            let decision;
+                            (decision, result) = classifier.add_chunk(state, chunk);
+                            classified.hit_lookup_time += result.hit_lookup_time;
+                            classified.prob_calc_time += result.prob_calc_time;
+                            if decision == Decision::Rejected {
+                                classified.num_rejected += 1;
+                            }
+                            if decision != Decision::NeedsMoreData {
+                                break;
+                            }
+                        }
+                        classified.num_bp += state.read_len();
+                        result
                     }
                     None => {
-                        writer
-                            .write(format!("U\t{}\t0\t-\n", record.id()).as_bytes())
-                            .expect("could not write to output file");
+                        let result = classifier.classify(state, &sequences);
+                        classified.num_bp += sequences
+                            .iter()
+                            .map(|sequence| sequence.len())
+                            .sum::<usize>();
+                        classified.hit_lookup_time += result.hit_lookup_time;
+                        classified.prob_calc_time += result.prob_calc_time;
+                        result
                     }
                 };
+
+                // The files with the lowest p-values, from the hits of the read in the state
+                if let Some(num_candidates) = args.top {
+                    let candidates = classifier.top_candidates(state, num_candidates);
+                    for (rank, candidate) in candidates.iter().enumerate() {
+                        classified.top_lines.push_str(&format!(
+                            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.2}\n",
+                            read_id,
+                            rank + 1,
+                            database.files()[candidate.file_index],
+                            database.tax_ids()[candidate.file_index],
+                            candidate.hits,
+                            candidate.scaled_hits,
+                            result.n_total,
+                            candidate.p_value.log10()
+                        ));
+                    }
+                }
+
+                // In LCA mode, the file is only reported if it is the only one in the LCA
+                let classification = result
+                    .tax_id
+                    .map(|taxid| (result.file(&database).unwrap_or("-"), taxid));
+                match classification {
+                    Some((file, taxid)) => classified
+                        .r2f_lines
+                        .push_str(&format!("C\t{}\t{}\t{}\n", read_id, taxid, file)),
+                    None => classified
+                        .r2f_lines
+                        .push_str(&format!("U\t{}\t0\t-\n", read_id)),
+                }
+                classified.classifications.push(classification);
             }
-        });
+            classified
+        };
+
+    // Write the lines of each batch, counting its reads for the statistics and the report
+    let mut stats = (0, 0, 0.0, 0.0);
+    let mut num_rejected = 0;
+    let mut read_counts = ReadCounts::default();
+    let write_batch = |classified: ClassifiedBatch| {
+        stats.0 += classified.classifications.len();
+        stats.1 += classified.num_bp;
+        stats.2 += classified.hit_lookup_time;
+        stats.3 += classified.prob_calc_time;
+        num_rejected += classified.num_rejected;
+        if args.report {
+            for classification in classified.classifications {
+                match classification {
+                    Some((file, taxid)) => read_counts.add_classified(taxid, file),
+                    None => read_counts.add_unclassified(),
+                }
+            }
+        }
+
+        // Stop cleanly if the output is closed (e.g. piped to `head`)
+        let mut writer = output_writer.lock().unwrap();
+        writer
+            .write_all(classified.r2f_lines.as_bytes())
+            .unwrap_or_else(|e| exit_on_write_error(e));
+        if flush_every_batch {
+            writer.flush().unwrap_or_else(|e| exit_on_write_error(e));
+        }
+        if let Some(top_writer) = &mut top_writer {
+            top_writer
+                .write_all(classified.top_lines.as_bytes())
+                .unwrap_or_else(|e| exit_on_write_error(e));
+        }
+    };
+
+    // Flush the output on another thread until every read is classified
+    let (stop_flushing, stop) = mpsc::channel::<()>();
+    let pipeline_stats = thread::scope(|scope| {
+        if let Some(interval) = flush_interval.filter(|interval| !interval.is_zero()) {
+            let output_writer = &output_writer;
+            scope.spawn(move || flush_periodically(output_writer, interval, stop));
+        }
+
+        // The number of batches waiting between stages is bounded, so the reads are not all read
+        // into memory if classification is slower than reading
+        let pipeline_stats = run_pipeline(
+            Batches::new(read_iter, batch_size),
+            num_threads,
+            2 * num_threads,
+            !args.unordered,
+            || classifier.new_state(),
+            classify_batch,
+            write_batch,
+        );
+        drop(stop_flushing);
+        pipeline_stats
+    });
 
     // Log throughput statisitcs of classification
     let classify_time = start_time.elapsed().as_secs_f64();
-    let stats = stats.into_inner().unwrap();
     info!("classification took: {} s", classify_time);
     info!(
         "{} total reads classified ({} reads/s)",
@@ -126,6 +430,34 @@ fn main() {
         stats.1,
         (stats.1 as f64 / classify_time) / 1_000_000.0
     );
+    if let Some(chunk_size) = args.chunk_size {
+        info!(
+            "{} reads rejected, {} bp classified per read in chunks of {} bp",
+            num_rejected,
+            stats.1 as f64 / stats.0.max(1) as f64,
+            chunk_size
+        );
+    }
+
+    // The throughput of each stage if it never waited for the others
+    let worker_time = pipeline_stats.work_time.as_secs_f64() / num_threads as f64;
+    for (stage, stage_time) in [
+        ("reading", pipeline_stats.read_time.as_secs_f64()),
+        ("classifying", worker_time),
+        ("writing", pipeline_stats.write_time.as_secs_f64()),
+    ] {
+        info!(
+            "{}: {} s ({} reads/s, {} Mbp/s)",
+            stage,
+            stage_time,
+            stats.0 as f64 / stage_time,
+            (stats.1 as f64 / stage_time) / 1_000_000.0
+        );
+    }
+    debug!(
+        "{} batches of at most {} reads",
+        pipeline_stats.num_batches, batch_size
+    );
     debug!(
         "total thread time spent looking up kmer hits: {} s",
         stats.2
@@ -139,7 +471,31 @@ fn main() {
         .into_inner()
         .expect("could not reclaim file writer at the end of execution")
         .flush()
+        .unwrap_or_else(|e| exit_on_write_error(e));
+    if let Some(mut top_writer) = top_writer {
+        top_writer.flush().expect("could not write to output file");
+    }
+
+    if args.report {
+        let mut writer = BufWriter::new(create_output_file(output_loc_path, "skim.report"));
+        match &taxonomy {
+            Some(taxonomy) => {
+                info!("writing taxonomy report...");
+                write_taxonomy_report(&mut writer, &read_counts, taxonomy)
+            }
+            None => {
+                info!("writing reference file report...");
+                write_file_report(
+                    &mut writer,
+                    &read_counts,
+                    database.files(),
+                    database.tax_ids(),
+                )
+            }
+        }
+        .and_then(|_| writer.flush())
         .expect("could not write to output file");
+    }
 
     info!("done!");
 }
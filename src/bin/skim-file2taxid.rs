@@ -2,11 +2,12 @@ use clap::Parser;
 use indicatif::ParallelProgressIterator;
 use itertools::Itertools;
 use rayon::prelude::*;
-use skim::consts::{DEFAULT_K, REF_SUBDIR};
+use skim::consts::{DEFAULT_K, MAX_PROB, REF_SUBDIR};
 use skim::io::{create_output_file, load_string2taxid, save_fasta_record_to_file};
 use skim::tracing::start_skim_tracing_subscriber;
 use skim::utility::{
-    compute_total_kmers, create_ref_subdir, get_fasta_files, get_fasta_iter_of_file, split_record,
+    compute_total_kmers, create_ref_subdir, get_fasta_files, get_fasta_iter_of_file,
+    parse_kmer_len, split_record,
 };
 use std::collections::HashMap;
 use std::io::{BufWriter, Write};
@@ -14,8 +15,6 @@ use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 use tracing::{info, warn};
 
-const MAX_PROB: f64 = 0.1;
-
 fn get_taxid(accession2taxid: &Option<HashMap<String, usize>>, accession: &str) -> usize {
     match accession2taxid {
         Some(accession2taxid) => *accession2taxid.get(accession).expect(&*format!(
@@ -32,8 +31,8 @@ fn get_taxid(accession2taxid: &Option<HashMap<String, usize>>, accession: &str)
 #[clap(version, about)]
 #[clap(author = "Trevor S. <trevor.schneggenburger@gmail.com>")]
 struct Args {
-    #[arg(short, long, default_value_t = DEFAULT_K)]
-    /// Length of k-mer to use in the database
+    #[arg(short, long, default_value_t = DEFAULT_K, value_parser = parse_kmer_len)]
+    /// Length of k-mer to use in the database (at most 31)
     kmer_length: usize,
 
     #[arg(short, long, default_value_t = std::env::current_dir().unwrap().to_str().unwrap().to_string(), verbatim_doc_comment)]
@@ -0,0 +1,341 @@
+use clap::Parser;
+use serde::Serialize;
+use skim::consts::MAX_PROB;
+use skim::database::Database;
+use skim::kmer_index::KmerIndexKind;
+use skim::rle::Block;
+use skim::tracing::start_skim_stderr_tracing_subscriber;
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::path::Path;
+use tracing::warn;
+
+/// Reports statistics about the contents of a skim database (.db/.cdb) file.
+#[derive(Parser)]
+#[clap(version, about)]
+#[clap(author = "Trevor S. <trevor.schneggenburger@gmail.com>")]
+struct Args {
+    #[arg(short, long)]
+    /// Output the statistics as JSON instead of text
+    json: bool,
+
+    #[arg()]
+    /// The database file to inspect
+    database: String,
+}
+
+#[derive(Serialize)]
+struct BlockCounts {
+    ones: usize,
+    total: usize,
+    uncompressed: usize,
+    zeros: usize,
+}
+
+#[derive(Serialize)]
+struct FileStats {
+    exceeds_max_prob: bool,
+    file: String,
+    num_kmers: usize,
+    p_value: f64,
+    tax_id: usize,
+}
+
+#[derive(Serialize)]
+struct MemoryStats {
+    index_bytes: usize,
+    index_kind: KmerIndexKind,
+    rle_block_bytes: usize,
+    rle_offset_bytes: usize,
+}
+
+// Rows with between `min_blocks` and `max_blocks` (inclusive) blocks
+#[derive(Serialize)]
+struct RowLengthBin {
+    max_blocks: usize,
+    min_blocks: usize,
+    num_kmers: usize,
+    num_rows: usize,
+}
+
+#[derive(Serialize)]
+struct TaxIdCount {
+    name: Option<String>,
+    num_files: usize,
+    rank: Option<String>,
+    tax_id: usize,
+}
+
+#[derive(Serialize)]
+struct DatabaseStats {
+    blocks: BlockCounts,
+    compression_level: Option<usize>,
+    files: Vec<FileStats>,
+    kmer_len: usize,
+    max_prob: f64,
+    memory: MemoryStats,
+    num_files: usize,
+    num_kmers: usize,
+    num_rows: usize,
+    row_lengths: Vec<RowLengthBin>,
+    smer_len: Option<usize>,
+    syncmer_offset: Option<usize>,
+    tax_ids: Vec<TaxIdCount>,
+    taxonomy_nodes: Option<usize>,
+}
+
+fn compute_stats(database: &Database) -> DatabaseStats {
+    let header = database.header();
+    let rles = database.rles();
+    let kmer_index = database.kmer_index();
+
+    // Per file statistics
+    let files = database
+        .count_kmers_per_file()
+        .into_iter()
+        .enumerate()
+        .map(|(index, num_kmers)| FileStats {
+            exceeds_max_prob: database.p_values()[index] > MAX_PROB,
+            file: database.files()[index].clone(),
+            num_kmers,
+            p_value: database.p_values()[index],
+            tax_id: database.tax_ids()[index],
+        })
+        .collect::<Vec<FileStats>>();
+
+    // Number of files with each tax id, most files first
+    let taxonomy = database.taxonomy();
+    let mut tax_id_counts = HashMap::new();
+    database.tax_ids().iter().for_each(|tax_id| {
+        *tax_id_counts.entry(*tax_id).or_insert(0_usize) += 1;
+    });
+    let mut tax_ids = tax_id_counts
+        .into_iter()
+        .map(|(tax_id, num_files)| TaxIdCount {
+            name: taxonomy
+                .and_then(|taxonomy| taxonomy.name(tax_id))
+                .map(str::to_string),
+            num_files,
+            rank: taxonomy
+                .and_then(|taxonomy| taxonomy.rank(tax_id))
+                .map(str::to_string),
+            tax_id,
+        })
+        .collect::<Vec<TaxIdCount>>();
+    tax_ids.sort_unstable_by_key(|count| (usize::MAX - count.num_files, count.tax_id));
+
+    // Count the stored blocks of each type and bin the rows by their number of blocks
+    // Rows are binned by powers of two: 1, 2-3, 4-7, ...
+    let rle_uses = kmer_index.count_rle_uses(rles.len());
+    let mut blocks = BlockCounts {
+        ones: 0,
+        total: rles.num_of_blocks(),
+        uncompressed: 0,
+        zeros: 0,
+    };
+    let mut row_lengths = vec![];
+    for (rle_index, uses) in rle_uses.into_iter().enumerate() {
+        let raw_blocks = rles.get_raw_blocks(rle_index);
+        raw_blocks
+            .iter()
+            .for_each(|block| match Block::from_u16(*block) {
+                Block::Ones(_) => blocks.ones += 1,
+                Block::Uncompressed(_) => blocks.uncompressed += 1,
+                Block::Zeros(_) => blocks.zeros += 1,
+            });
+
+        let bin = raw_blocks.len().max(1).ilog2() as usize;
+        while row_lengths.len() <= bin {
+            let min_blocks = 1 << row_lengths.len();
+            row_lengths.push(RowLengthBin {
+                max_blocks: (min_blocks << 1) - 1,
+                min_blocks,
+                num_kmers: 0,
+                num_rows: 0,
+            });
+        }
+        row_lengths[bin].num_kmers += uses as usize;
+        row_lengths[bin].num_rows += 1;
+    }
+
+    DatabaseStats {
+        blocks,
+        compression_level: header.compression_level,
+        files,
+        kmer_len: header.kmer_len,
+        max_prob: MAX_PROB,
+        memory: MemoryStats {
+            index_bytes: kmer_index.size_in_bytes(),
+            index_kind: kmer_index.kind(),
+            rle_block_bytes: rles.num_of_blocks() * size_of::<u16>(),
+            rle_offset_bytes: (rles.len() + 1) * size_of::<u64>(),
+        },
+        num_files: header.num_files,
+        num_kmers: kmer_index.num_kmers(),
+        num_rows: rles.len(),
+        row_lengths,
+        smer_len: header.syncmer_info.map(|(smer_len, _)| smer_len),
+        syncmer_offset: header
+            .syncmer_info
+            .map(|(_, syncmer_offset)| syncmer_offset),
+        tax_ids,
+        taxonomy_nodes: taxonomy.map(|taxonomy| taxonomy.len()),
+    }
+}
+
+// Formats a number of bytes using the largest unit that keeps the value at least 1
+fn format_bytes(bytes: usize) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2} {}", value, units[unit])
+}
+
+// Formats `part` as a percentage of `total`
+fn percent(part: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / total as f64
+    }
+}
+
+fn print_text(stats: &DatabaseStats) {
+    println!("parameters");
+    println!("  k-mer length:\t{}", stats.kmer_len);
+    match (stats.smer_len, stats.syncmer_offset) {
+        (Some(smer_len), Some(syncmer_offset)) => {
+            println!("  s-mer length:\t{}", smer_len);
+            println!("  syncmer offset:\t{}", syncmer_offset);
+        }
+        _ => println!("  syncmers:\tdisabled"),
+    }
+    match stats.compression_level {
+        Some(compression_level) => println!("  lossy compression level:\t{}", compression_level),
+        None => println!("  lossy compression level:\tnone"),
+    }
+
+    println!();
+    println!("contents");
+    println!("  files:\t{}", stats.num_files);
+    println!("  k-mers:\t{}", stats.num_kmers);
+    println!("  distinct rows:\t{}", stats.num_rows);
+    match stats.taxonomy_nodes {
+        Some(taxonomy_nodes) => println!("  taxonomy nodes:\t{}", taxonomy_nodes),
+        None => println!("  taxonomy:\tnone"),
+    }
+
+    println!();
+    println!("memory");
+    let total_bytes =
+        stats.memory.index_bytes + stats.memory.rle_block_bytes + stats.memory.rle_offset_bytes;
+    for (name, bytes) in [
+        (
+            format!("{:?} k-mer index", stats.memory.index_kind),
+            stats.memory.index_bytes,
+        ),
+        ("rle blocks".to_string(), stats.memory.rle_block_bytes),
+        ("rle offsets".to_string(), stats.memory.rle_offset_bytes),
+    ] {
+        println!(
+            "  {}:\t{} ({:.1}%)",
+            name,
+            format_bytes(bytes),
+            percent(bytes, total_bytes)
+        );
+    }
+    println!("  total:\t{}", format_bytes(total_bytes));
+
+    println!();
+    println!("blocks");
+    for (name, count) in [
+        ("zeros", stats.blocks.zeros),
+        ("ones", stats.blocks.ones),
+        ("uncompressed", stats.blocks.uncompressed),
+    ] {
+        println!(
+            "  {}:\t{} ({:.1}%)",
+            name,
+            count,
+            percent(count, stats.blocks.total)
+        );
+    }
+    println!("  total:\t{}", stats.blocks.total);
+
+    println!();
+    println!("row lengths (blocks\trows\tk-mers)");
+    for bin in &stats.row_lengths {
+        println!(
+            "  {}-{}\t{}\t{}",
+            bin.min_blocks, bin.max_blocks, bin.num_rows, bin.num_kmers
+        );
+    }
+
+    println!();
+    println!("tax ids (tax id\tfiles\trank\tname)");
+    for count in &stats.tax_ids {
+        println!(
+            "  {}\t{}\t{}\t{}",
+            count.tax_id,
+            count.num_files,
+            count.rank.as_deref().unwrap_or("-"),
+            count.name.as_deref().unwrap_or("-")
+        );
+    }
+
+    println!();
+    println!("files (file\ttax id\tk-mers\tp)");
+    for file in &stats.files {
+        println!(
+            "  {}\t{}\t{}\t{:e}{}",
+            file.file,
+            file.tax_id,
+            file.num_kmers,
+            file.p_value,
+            if file.exceeds_max_prob {
+                format!("\tEXCEEDS MAX PROB {}", stats.max_prob)
+            } else {
+                String::new()
+            }
+        );
+    }
+}
+
+fn main() {
+    // Initialize the tracing subscriber to handle debug, info, warn, and error macro calls, logging
+    // to stderr so that the report on stdout can be piped
+    start_skim_stderr_tracing_subscriber();
+
+    // Parse arguments from the command line
+    let args = Args::parse();
+    let database_path = Path::new(&args.database);
+
+    let database = Database::load_from_file(database_path).unwrap_or_else(|e| panic!("{}", e));
+
+    let stats = compute_stats(&database);
+
+    let num_exceeding = stats
+        .files
+        .iter()
+        .filter(|file| file.exceeds_max_prob)
+        .count();
+    if num_exceeding > 0 {
+        warn!(
+            "{} files contain more than {} of all possible k-mers and may need to be split",
+            num_exceeding, MAX_PROB
+        );
+    }
+
+    if args.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&stats).expect("could not serialize statistics")
+        );
+    } else {
+        print_text(&stats);
+    }
+}
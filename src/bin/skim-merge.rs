@@ -0,0 +1,60 @@
+use clap::Parser;
+use skim::database::Database;
+use skim::io::create_output_file;
+use skim::tracing::start_skim_tracing_subscriber;
+use std::path::Path;
+use tracing::info;
+
+/// Merges two skim database (.db) files built with the same parameters into one.
+/// The files of the second database are placed after the files of the first.
+#[derive(Parser)]
+#[clap(version, about)]
+#[clap(author = "Trevor S. <trevor.schneggenburger@gmail.com>")]
+struct Args {
+    #[arg(short, long, default_value_t = std::env::current_dir().unwrap().to_str().unwrap().to_string(), verbatim_doc_comment)]
+    /// Where to write the merged database (.db) file.
+    /// If a file is provided, the extension '.skim.db' is added.
+    /// If a directory is provided, 'skim.db' will be the file name.
+    output_location: String,
+
+    #[arg()]
+    /// The first database (.db) file
+    first_database: String,
+
+    #[arg()]
+    /// The second database (.db) file
+    second_database: String,
+}
+
+fn main() {
+    // Initialize the tracing subscriber to handle debug, info, warn, and error macro calls
+    start_skim_tracing_subscriber();
+
+    // Parse arguments from the command line
+    let args = Args::parse();
+    let first_path = Path::new(&args.first_database);
+    let second_path = Path::new(&args.second_database);
+    let output_loc_path = Path::new(&args.output_location);
+
+    info!("loading database at {:?}", first_path);
+    let mut database = Database::load_from_file(first_path).unwrap_or_else(|e| panic!("{}", e));
+    info!("loaded {}", database.header());
+
+    info!("loading database at {:?}", second_path);
+    let other = Database::load_from_file(second_path).unwrap_or_else(|e| panic!("{}", e));
+    info!("loaded {}", other.header());
+
+    info!("merging databases...");
+    database.merge(&other).unwrap_or_else(|e| panic!("{}", e));
+
+    // Create the output file only once the databases were merged, since it may overwrite one of
+    // them, which a rejected merge would otherwise leave empty
+    let output_file = create_output_file(output_loc_path, "skim.db");
+
+    info!("dumping to file...");
+    database
+        .dump_to_file(output_file)
+        .expect("could not serialize database to file");
+
+    info!("done!");
+}
@@ -1,5 +1,6 @@
 use clap::Parser;
 use skim::{
+    header::FileKind,
     io::{create_output_file, load_data_from_file},
     order::{greedy_ordering, ordering_statistics},
     tracing::start_skim_tracing_subscriber,
@@ -44,8 +45,12 @@ fn main() {
     let mut output_writer = BufWriter::new(create_output_file(output_loc_path, "skim.o.f2t"));
 
     info!("loading distances at {}", args.distances);
-    let (distances, file2taxid) =
-        load_data_from_file::<(Vec<Vec<u32>>, Vec<(String, usize)>)>(distances_file);
+    let (header, (distances, file2taxid)) = load_data_from_file::<(
+        Vec<Vec<u32>>,
+        Vec<(String, usize)>,
+    )>(distances_file, FileKind::PairwiseDistances)
+    .unwrap_or_else(|e| panic!("{}", e));
+    info!("loaded {}", header);
 
     info!("distances loaded! finding ordering...");
 
@@ -1,11 +1,12 @@
 use clap::Parser;
 use indicatif::ParallelProgressIterator;
 use rayon::prelude::*;
-use roaring::RoaringBitmap;
+use roaring::RoaringTreemap;
 use skim::consts::{DEFAULT_K, DEFAULT_S, DEFAULT_T};
+use skim::header::{FileHeader, FileKind};
 use skim::io::{create_output_file, dump_data_to_file, load_string2taxid};
 use skim::tracing::start_skim_tracing_subscriber;
-use skim::utility::create_bitmap;
+use skim::utility::{create_bitmap, parse_kmer_len};
 use std::path::Path;
 use tracing::info;
 
@@ -14,8 +15,8 @@ use tracing::info;
 #[clap(version, about)]
 #[clap(author = "Trevor S. <trevor.schneggenburger@gmail.com>")]
 struct Args {
-    #[arg(short, long, default_value_t = DEFAULT_K)]
-    /// Length of k-mer to use in the database
+    #[arg(short, long, default_value_t = DEFAULT_K, value_parser = parse_kmer_len)]
+    /// Length of k-mer to use in the database (at most 31)
     kmer_length: usize,
 
     #[arg(short, long, default_value_t = std::env::current_dir().unwrap().to_str().unwrap().to_string(), verbatim_doc_comment)]
@@ -79,7 +80,7 @@ fn main() {
         .par_iter()
         .progress()
         .map(|(file, _taxid)| create_bitmap(ref_dir_path.join(file), kmer_len, syncmer_info))
-        .collect::<Vec<RoaringBitmap>>();
+        .collect::<Vec<RoaringTreemap>>();
 
     info!("roaring bitmaps created, creating distance matrix...");
     let distances = bitmaps
@@ -104,7 +105,14 @@ fn main() {
         .collect::<Vec<Vec<u32>>>();
 
     info!("writing to output file...");
-    dump_data_to_file(&(distances, file2taxid), output_file)
+    let header = FileHeader {
+        compression_level: None,
+        kind: FileKind::PairwiseDistances,
+        kmer_len,
+        num_files: file2taxid.len(),
+        syncmer_info,
+    };
+    dump_data_to_file(&header, &(distances, file2taxid), output_file)
         .expect("could not output distances to file");
 
     info!("done!");
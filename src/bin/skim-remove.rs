@@ -0,0 +1,98 @@
+use clap::Parser;
+use skim::database::Database;
+use skim::io::create_output_file;
+use skim::tracing::start_skim_tracing_subscriber;
+use std::collections::HashSet;
+use std::path::Path;
+use tracing::info;
+
+/// Removes reference files from a skim database (.db/.cdb) file.
+/// K-mers that are no longer in any file are removed from the database.
+#[derive(Parser)]
+#[clap(version, about)]
+#[clap(author = "Trevor S. <trevor.schneggenburger@gmail.com>")]
+struct Args {
+    #[arg(short, long, default_value_t = std::env::current_dir().unwrap().to_str().unwrap().to_string(), verbatim_doc_comment)]
+    /// Where to write the updated database file.
+    /// If a file is provided, the extension '.skim.db' ('.skim.cdb' if lossy compressed) is added.
+    /// If a directory is provided, 'skim.db' ('skim.cdb' if lossy compressed) will be the file name.
+    output_location: String,
+
+    #[arg(short, long)]
+    /// Treat the values to remove as tax ids, removing every file with one of the tax ids
+    taxids: bool,
+
+    #[arg()]
+    /// The database file to remove files from
+    database: String,
+
+    #[arg(required = true)]
+    /// The files (or tax ids, if --taxids is provided) to remove
+    remove: Vec<String>,
+}
+
+fn main() {
+    // Initialize the tracing subscriber to handle debug, info, warn, and error macro calls
+    start_skim_tracing_subscriber();
+
+    // Parse arguments from the command line
+    let args = Args::parse();
+    let database_path = Path::new(&args.database);
+    let output_loc_path = Path::new(&args.output_location);
+
+    info!("loading database at {:?}", database_path);
+    let mut database = Database::load_from_file(database_path).unwrap_or_else(|e| panic!("{}", e));
+    info!("loaded {}", database.header());
+
+    // Find the index of every file to remove, failing if any value matches nothing
+    let file_indices = if args.taxids {
+        let tax_ids = args
+            .remove
+            .iter()
+            .map(|tax_id| {
+                tax_id
+                    .parse::<usize>()
+                    .unwrap_or_else(|_| panic!("could not parse tax id '{}'", tax_id))
+            })
+            .collect::<HashSet<usize>>();
+        tax_ids.iter().for_each(|tax_id| {
+            if !database.tax_ids().contains(tax_id) {
+                panic!("no files in the database have tax id {}", tax_id)
+            }
+        });
+        (0..database.num_files())
+            .filter(|index| tax_ids.contains(&database.tax_ids()[*index]))
+            .collect::<Vec<usize>>()
+    } else {
+        args.remove
+            .iter()
+            .map(|file| {
+                database
+                    .files()
+                    .iter()
+                    .position(|db_file| db_file == file)
+                    .unwrap_or_else(|| panic!("could not find {} in the database", file))
+            })
+            .collect::<Vec<usize>>()
+    };
+
+    info!("removing {} files from the database...", file_indices.len());
+    database
+        .remove_files(&file_indices)
+        .unwrap_or_else(|e| panic!("{}", e));
+
+    // Create the output file only once the files were removed, since it may overwrite the
+    // database, which a rejected removal would otherwise leave empty
+    let extension = match database.header().compression_level {
+        Some(_) => "skim.cdb",
+        None => "skim.db",
+    };
+    let output_file = create_output_file(output_loc_path, extension);
+
+    info!("dumping to file...");
+    database
+        .dump_to_file(output_file)
+        .expect("could not output database to file");
+
+    info!("done!");
+}
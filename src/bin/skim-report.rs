@@ -0,0 +1,90 @@
+use clap::Parser;
+use skim::database::Database;
+use skim::io::create_output_file;
+use skim::report::{write_file_report, write_taxonomy_report, ReadCounts};
+use skim::taxonomy::Taxonomy;
+use skim::tracing::start_skim_tracing_subscriber;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use tracing::info;
+
+/// Creates a Kraken2-style report (.report) from a readid2file (.r2f) written by skim-classify.
+/// Without a taxonomy, the number of reads classified to each reference file is reported instead.
+#[derive(Parser)]
+#[clap(version, about)]
+#[clap(author = "Trevor S. <trevor.schneggenburger@gmail.com>")]
+struct Args {
+    #[arg(short, long, action)]
+    /// Report the reads classified to each reference file, even if a taxonomy is available
+    files: bool,
+
+    #[arg(short, long, default_value_t = std::env::current_dir().unwrap().to_str().unwrap().to_string(), verbatim_doc_comment)]
+    /// Where to write the report (.report) file.
+    /// If a file is provided, the extension '.skim.report' is added.
+    /// If a directory is provided, 'skim.report' will be the file name.
+    output_location: String,
+
+    #[arg(long, verbatim_doc_comment)]
+    /// Directory with the NCBI taxonomy (nodes.dmp and names.dmp) to use for the report.
+    /// Overrides the taxonomy embedded in the database.
+    taxonomy: Option<String>,
+
+    #[arg()]
+    /// The database (.db/.cdb) file used for classification
+    database: String,
+
+    #[arg()]
+    /// The readid2file (.r2f) file written by skim-classify
+    readid2file: String,
+}
+
+fn main() {
+    // Initialize the tracing subscriber to handle debug, info, warn, and error macro calls
+    start_skim_tracing_subscriber();
+
+    // Parse arguments from the command line
+    let args = Args::parse();
+    let database_path = Path::new(&args.database);
+    let output_loc_path = Path::new(&args.output_location);
+    let readid2file_path = Path::new(&args.readid2file);
+
+    // Only the metadata of the database is needed, so it is memory-mapped
+    info!("loading database at {:?}", database_path);
+    let database = Database::load_from_file_mmap(database_path).unwrap_or_else(|e| panic!("{}", e));
+    info!("loaded {}", database.header());
+
+    info!("counting reads in {:?}", readid2file_path);
+    let counts = ReadCounts::from_r2f(readid2file_path).unwrap_or_else(|e| panic!("{}", e));
+    info!(
+        "{} reads, {} classified",
+        counts.num_reads(),
+        counts.num_classified()
+    );
+
+    let taxonomy = match args.taxonomy {
+        Some(taxonomy_dir) => {
+            info!("loading taxonomy at {}", taxonomy_dir);
+            Some(
+                Taxonomy::from_ncbi(Path::new(&taxonomy_dir), database.tax_ids())
+                    .unwrap_or_else(|e| panic!("{}", e)),
+            )
+        }
+        None => database.taxonomy().cloned(),
+    };
+
+    let mut writer = BufWriter::new(create_output_file(output_loc_path, "skim.report"));
+    match taxonomy {
+        Some(taxonomy) if !args.files => {
+            info!("writing taxonomy report...");
+            write_taxonomy_report(&mut writer, &counts, &taxonomy)
+        }
+        _ => {
+            info!("writing reference file report...");
+            write_file_report(&mut writer, &counts, database.files(), database.tax_ids())
+        }
+    }
+    .and_then(|_| writer.flush())
+    .expect("could not write to output file");
+
+    info!("done!");
+}
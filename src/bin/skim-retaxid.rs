@@ -0,0 +1,187 @@
+use clap::Parser;
+use skim::database::Database;
+use skim::io::{create_output_file, load_string2taxid};
+use skim::taxonomy::{load_ncbi_merged, Taxonomy};
+use skim::tracing::start_skim_tracing_subscriber;
+use skim::utility::get_fasta_iter_of_file;
+use std::collections::HashMap;
+use std::path::Path;
+use tracing::{info, warn};
+
+/// Updates the tax ids of the files in an existing skim database (.db) file, e.g. after an update of the NCBI taxonomy.
+/// Reports the files whose tax id changed (or was merged into another), and the files missing from the new mapping,
+/// which keep their tax id.
+#[derive(Parser)]
+#[clap(version, about)]
+#[clap(author = "Trevor S. <trevor.schneggenburger@gmail.com>")]
+struct Args {
+    #[arg(long, action)]
+    /// Only report the changes, without writing the updated database
+    dry_run: bool,
+
+    #[arg(short, long, default_value_t = std::env::current_dir().unwrap().to_str().unwrap().to_string(), verbatim_doc_comment)]
+    /// Where to write the updated database (.db) file.
+    /// If a file is provided, the extension '.skim.db' is added.
+    /// If a directory is provided, 'skim.db' will be the file name.
+    output_location: String,
+
+    #[arg(short, long, verbatim_doc_comment)]
+    /// Directory with the FASTA files of the database.
+    /// If provided, the mapping is a seqid2taxid, and the tax id of each file is found from its first record (as in skim-file2taxid).
+    reference_directory: Option<String>,
+
+    #[arg(long, verbatim_doc_comment)]
+    /// Directory with the updated NCBI taxonomy (nodes.dmp and names.dmp) to embed in the database.
+    /// Required if the database has a taxonomy that does not contain the new tax ids.
+    /// If it has a merged.dmp, files whose tax id was merged into another are reported as merged.
+    taxonomy: Option<String>,
+
+    #[arg()]
+    /// The database (.db) file to update
+    database: String,
+
+    #[arg()]
+    /// The new file2taxid (.f2t), or seqid2taxid with --reference-directory, of the form `<file/seqid>\t<taxid>`
+    string2taxid: String,
+}
+
+fn main() {
+    // Initialize the tracing subscriber to handle debug, info, warn, and error macro calls
+    start_skim_tracing_subscriber();
+
+    // Parse arguments from the command line
+    let args = Args::parse();
+    let database_path = Path::new(&args.database);
+    let output_loc_path = Path::new(&args.output_location);
+    let string2taxid_path = Path::new(&args.string2taxid);
+
+    info!("loading database at {:?}", database_path);
+    let mut database = Database::load_from_file(database_path).unwrap_or_else(|e| panic!("{}", e));
+    info!("loaded {}", database.header());
+
+    info!("loading mapping at {}", args.string2taxid);
+    let string2taxid = load_string2taxid(string2taxid_path);
+
+    // Find the new tax id of each file of the database, if it has one
+    let new_tax_ids: HashMap<&str, usize> = match &args.reference_directory {
+        Some(reference_directory) => {
+            let seqid2taxid = string2taxid.into_iter().collect::<HashMap<String, usize>>();
+            database
+                .files()
+                .iter()
+                .filter_map(|file| {
+                    let file_path = Path::new(reference_directory).join(file);
+                    let record = get_fasta_iter_of_file(&file_path)
+                        .next()
+                        .unwrap_or_else(|| panic!("no records found in fasta file {:?}", file_path))
+                        .unwrap_or_else(|e| {
+                            panic!("could not parse the fasta file {:?}: {}", file_path, e)
+                        });
+                    seqid2taxid
+                        .get(record.id())
+                        .map(|tax_id| (file.as_str(), *tax_id))
+                })
+                .collect()
+        }
+        None => {
+            let file2taxid = string2taxid.into_iter().collect::<HashMap<String, usize>>();
+            let num_unknown = file2taxid
+                .keys()
+                .filter(|file| !database.files().contains(file))
+                .count();
+            if num_unknown > 0 {
+                warn!(
+                    "{} files of the file2taxid are not in the database and are ignored",
+                    num_unknown
+                );
+            }
+            database
+                .files()
+                .iter()
+                .filter_map(|file| file2taxid.get(file).map(|tax_id| (file.as_str(), *tax_id)))
+                .collect()
+        }
+    };
+
+    // Files without a new tax id keep their old one
+    let missing = database
+        .files()
+        .iter()
+        .zip(database.tax_ids())
+        .filter(|(file, _tax_id)| !new_tax_ids.contains_key(file.as_str()))
+        .map(|(file, tax_id)| (file.clone(), *tax_id))
+        .collect::<Vec<(String, usize)>>();
+    for (file, tax_id) in &missing {
+        warn!("missing: {} keeps tax id {}", file, tax_id);
+    }
+    let file2taxid = database
+        .files()
+        .iter()
+        .filter_map(|file| {
+            new_tax_ids
+                .get(file.as_str())
+                .map(|tax_id| (file.clone(), *tax_id))
+        })
+        .collect::<Vec<(String, usize)>>();
+
+    // Load the taxonomy of the new tax ids, including the tax ids of the missing files
+    let (taxonomy, merged) = match &args.taxonomy {
+        Some(taxonomy_dir) => {
+            info!("loading taxonomy at {}", taxonomy_dir);
+            let taxonomy_path = Path::new(taxonomy_dir);
+            let all_tax_ids = database
+                .files()
+                .iter()
+                .zip(database.tax_ids())
+                .map(|(file, tax_id)| *new_tax_ids.get(file.as_str()).unwrap_or(tax_id))
+                .collect::<Vec<usize>>();
+            let taxonomy = Taxonomy::from_ncbi(taxonomy_path, &all_tax_ids)
+                .unwrap_or_else(|e| panic!("{}", e));
+            let merged = if taxonomy_path.join("merged.dmp").exists() {
+                load_ncbi_merged(taxonomy_path).unwrap_or_else(|e| panic!("{}", e))
+            } else {
+                HashMap::new()
+            };
+            (Some(taxonomy), merged)
+        }
+        None => (None, HashMap::new()),
+    };
+
+    info!("updating tax ids of {} files...", file2taxid.len());
+    let changes = database
+        .update_tax_ids(&file2taxid, taxonomy)
+        .unwrap_or_else(|e| panic!("{}", e));
+    let mut num_merged = 0;
+    for (file_index, old_tax_id) in &changes {
+        let file = &database.files()[*file_index];
+        let new_tax_id = database.tax_ids()[*file_index];
+        if merged.get(old_tax_id) == Some(&new_tax_id) {
+            num_merged += 1;
+            info!("merged: {} {} -> {}", file, old_tax_id, new_tax_id);
+        } else {
+            info!("changed: {} {} -> {}", file, old_tax_id, new_tax_id);
+        }
+    }
+    info!(
+        "{} files changed ({} merged), {} unchanged, {} missing",
+        changes.len(),
+        num_merged,
+        file2taxid.len() - changes.len(),
+        missing.len()
+    );
+
+    if args.dry_run {
+        info!("dry run, so the database is not written");
+        return;
+    }
+
+    // Create the output file after loading, since it may overwrite the database
+    let output_file = create_output_file(output_loc_path, "skim.db");
+
+    info!("dumping to file...");
+    database
+        .dump_to_file(output_file)
+        .expect("could not serialize database to file");
+
+    info!("done!");
+}
@@ -0,0 +1,223 @@
+use clap::Parser;
+use skim::big_exp_float::BigExpFloat;
+use skim::classifier::Classifier;
+use skim::database::Database;
+use skim::serve::{serve_connection, Session, TargetMode, Targets};
+use skim::taxonomy::Taxonomy;
+use skim::tracing::start_skim_tracing_subscriber;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::ops::Neg;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tracing::{info, warn};
+
+// How long to wait after a failed accept (e.g. when out of file descriptors) before the next one
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Serves adaptive sampling (Read Until) decisions from a skim database (.db/.cdb) file.
+/// Clients send chunks of reads over a Unix domain socket or a localhost TCP port, and get back whether to keep
+/// receiving chunks of each read, stop receiving them, or unblock (eject) the read, with its classification.
+#[derive(Parser)]
+#[clap(version, about)]
+#[clap(author = "Trevor S. <trevor.schneggenburger@gmail.com>")]
+struct Args {
+    #[arg(long, action, verbatim_doc_comment)]
+    /// Eject the reads of the targets and keep the others, instead of keeping the reads of the targets.
+    deplete: bool,
+
+    #[arg(short, long, default_value_t = 12, verbatim_doc_comment)]
+    /// The exponent, e, used in the equation 10^{-e}.
+    /// Any calculated p-value below 10^{-e} will result in a classification.
+    exponent: i32,
+
+    #[arg(long, verbatim_doc_comment)]
+    /// Reject a read as soon as no file can reach the cutoff within this many bases.
+    /// By default, a read is only rejected when the client ends it with an empty chunk.
+    max_read_length: Option<usize>,
+
+    #[arg(long, default_value_t = 4096, verbatim_doc_comment)]
+    /// The most reads of a client that are classified at a time (e.g. one per channel of a flow cell).
+    /// Beyond it, the read that has gone the longest without a chunk is evicted.
+    max_reads: usize,
+
+    #[arg(short, long, default_value_t = 100, verbatim_doc_comment)]
+    /// The fixed number of trials to use in the binomial function.
+    n_fixed: usize,
+
+    #[arg(long, action, verbatim_doc_comment)]
+    /// Read the whole database into memory (verifying its checksum) instead of memory-mapping it.
+    /// Memory-mapping starts serving almost instantly and lets multiple processes share the database.
+    no_mmap: bool,
+
+    #[arg(short, long, conflicts_with = "socket", verbatim_doc_comment)]
+    /// Listen on this TCP port of localhost (127.0.0.1) instead of a Unix domain socket.
+    port: Option<u16>,
+
+    #[arg(short, long, required_unless_present = "port", verbatim_doc_comment)]
+    /// The path of the Unix domain socket to listen on. A socket left at the path by a previous run is replaced.
+    socket: Option<String>,
+
+    #[arg(long, verbatim_doc_comment)]
+    /// Directory with the NCBI taxonomy (nodes.dmp and names.dmp), so that a tax id in the targets also
+    /// targets the files of its descendants. Overrides the taxonomy embedded in the database.
+    taxonomy: Option<String>,
+
+    #[arg()]
+    /// The database (.db/.cdb) file
+    database: String,
+
+    #[arg(verbatim_doc_comment)]
+    /// File with a tax id or the name of a file of the database on each line, whose reads are enriched
+    /// (or depleted with --deplete). Empty lines and lines starting with '#' are skipped.
+    targets: String,
+}
+
+fn main() {
+    // Parse arguments from the command line
+    let args = Args::parse();
+    let cutoff_threshold = BigExpFloat::from_f64(10.0_f64.powi(args.exponent.neg()));
+    let database_path = Path::new(&args.database);
+
+    start_skim_tracing_subscriber();
+
+    info!("loading database at {:?}", database_path);
+    let database = if args.no_mmap {
+        Database::load_from_file(database_path)
+    } else {
+        Database::load_from_file_mmap(database_path)
+    }
+    .unwrap_or_else(|e| panic!("{}", e));
+    info!("loaded {}", database.header());
+
+    // Find the taxonomy used to target the descendants of tax ids
+    let taxonomy = match &args.taxonomy {
+        Some(taxonomy_dir) => {
+            info!("loading taxonomy at {}", taxonomy_dir);
+            Some(
+                Taxonomy::from_ncbi(Path::new(taxonomy_dir), database.tax_ids())
+                    .unwrap_or_else(|e| panic!("{}", e)),
+            )
+        }
+        None => database.taxonomy().cloned(),
+    };
+
+    let mode = if args.deplete {
+        TargetMode::Deplete
+    } else {
+        TargetMode::Enrich
+    };
+    let targets = Targets::from_file(Path::new(&args.targets), &database, taxonomy.as_ref(), mode)
+        .unwrap_or_else(|e| panic!("{}", e));
+    if targets.num_targets() == 0 {
+        warn!("none of the files of the database are targets");
+    }
+    info!(
+        "{} of {} files are targets to {}",
+        targets.num_targets(),
+        database.num_files(),
+        if args.deplete { "deplete" } else { "enrich" }
+    );
+
+    info!("computing lookup table...");
+    let classifier = Classifier::new(&database, cutoff_threshold, args.n_fixed);
+
+    // Each client is served on its own thread, with the database and lookup table loaded once
+    thread::scope(|scope| {
+        let serve_client = |client: String,
+                            reader: Box<dyn Read + Send>,
+                            writer: Box<dyn Write + Send>| {
+            let (classifier, targets) = (&classifier, &targets);
+            scope.spawn(move || {
+                info!("{} connected", client);
+                let mut session = Session::new(
+                    classifier,
+                    targets,
+                    args.max_read_length,
+                    args.max_reads,
+                );
+                if let Err(e) = serve_connection(&mut session, reader, writer) {
+                    warn!("{}: {}", client, e);
+                }
+                let counts = session.action_counts();
+                info!(
+                    "{} disconnected after {} proceed, {} stop_receiving, and {} unblock decisions ({} reads without a decision)",
+                    client,
+                    counts.proceed,
+                    counts.stop_receiving,
+                    counts.unblock,
+                    session.num_reads()
+                );
+            });
+        };
+
+        match (&args.socket, args.port) {
+            (_, Some(port)) => {
+                let listener = TcpListener::bind(("127.0.0.1", port))
+                    .unwrap_or_else(|e| panic!("could not listen on port {}: {}", port, e));
+                info!("listening on {}", listener.local_addr().unwrap());
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            warn!("could not accept connection: {}", e);
+                            thread::sleep(ACCEPT_RETRY_DELAY);
+                            continue;
+                        }
+                    };
+                    // The client may already have disconnected, so its address is only logged
+                    let client = match stream.peer_addr() {
+                        Ok(addr) => format!("client {}", addr),
+                        Err(_) => "client (unknown address)".to_string(),
+                    };
+                    // Send each response as soon as it is written
+                    let reader = match stream.set_nodelay(true).and_then(|_| stream.try_clone()) {
+                        Ok(reader) => reader,
+                        Err(e) => {
+                            warn!("{}: could not configure connection: {}", client, e);
+                            continue;
+                        }
+                    };
+                    serve_client(client, Box::new(reader), Box::new(stream));
+                }
+            }
+            (Some(socket), None) => {
+                let socket_path = Path::new(socket);
+                if fs::metadata(socket_path).is_ok_and(|metadata| metadata.file_type().is_socket())
+                {
+                    fs::remove_file(socket_path).expect("could not remove the previous socket");
+                }
+                let listener = UnixListener::bind(socket_path).unwrap_or_else(|e| {
+                    panic!("could not listen on socket {:?}: {}", socket_path, e)
+                });
+                info!("listening on {:?}", socket_path);
+                let mut num_clients = 0;
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            warn!("could not accept connection: {}", e);
+                            thread::sleep(ACCEPT_RETRY_DELAY);
+                            continue;
+                        }
+                    };
+                    num_clients += 1;
+                    let client = format!("client {}", num_clients);
+                    let reader = match stream.try_clone() {
+                        Ok(reader) => reader,
+                        Err(e) => {
+                            warn!("{}: could not configure connection: {}", client, e);
+                            continue;
+                        }
+                    };
+                    serve_client(client, Box::new(reader), Box::new(stream));
+                }
+            }
+            (None, None) => unreachable!("clap requires a socket or a port"),
+        }
+    });
+}
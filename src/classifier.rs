@@ -0,0 +1,336 @@
+use std::borrow::Cow;
+use std::time::Instant;
+
+use crate::big_exp_float::BigExpFloat;
+use crate::database::{lowest_candidates, scaled_hits, Candidate, Database};
+use crate::taxonomy::Taxonomy;
+
+/// Classifies reads with a database, holding the lookup table and the settings of the
+/// classification so they can be shared by every thread. Each thread classifies reads with its
+/// own [`ClassifierState`], which reuses its buffers from one read to the next.
+pub struct Classifier<'a> {
+    cutoff_threshold: BigExpFloat,
+    database: &'a Database,
+    lca: Option<(&'a Taxonomy, Option<f64>)>,
+    lookup_table: Cow<'a, [BigExpFloat]>,
+    n_fixed: usize,
+}
+
+impl<'a> Classifier<'a> {
+    // Computes the lookup table of the database for `n_fixed` trials
+    pub fn new(database: &'a Database, cutoff_threshold: BigExpFloat, n_fixed: usize) -> Self {
+        Classifier {
+            cutoff_threshold,
+            database,
+            lca: None,
+            lookup_table: Cow::Owned(database.compute_loookup_table(n_fixed as u64)),
+            n_fixed,
+        }
+    }
+
+    // Uses a lookup table already computed with Database::compute_loookup_table for `n_fixed`
+    // trials
+    pub fn with_lookup_table(
+        database: &'a Database,
+        cutoff_threshold: BigExpFloat,
+        n_fixed: usize,
+        lookup_table: &'a [BigExpFloat],
+    ) -> Self {
+        assert_eq!(
+            database.num_files() * (n_fixed + 1),
+            lookup_table.len(),
+            "the lookup table was computed for a different database or n_fixed"
+        );
+        Classifier {
+            cutoff_threshold,
+            database,
+            lca: None,
+            lookup_table: Cow::Borrowed(lookup_table),
+            n_fixed,
+        }
+    }
+
+    // Classifies reads as the lowest common ancestor of every file whose probability is below the
+    // cutoff, as Database::classify_lca does, instead of the file with the lowest probability
+    pub fn set_lca(&mut self, taxonomy: &'a Taxonomy, max_log_ratio: Option<f64>) {
+        self.lca = Some((taxonomy, max_log_ratio));
+    }
+
+    pub fn database(&self) -> &'a Database {
+        self.database
+    }
+
+    pub fn lookup_table(&self) -> &[BigExpFloat] {
+        &self.lookup_table
+    }
+
+    pub fn n_fixed(&self) -> usize {
+        self.n_fixed
+    }
+
+    // Creates the buffers for classifying reads on one thread
+    pub fn new_state(&self) -> ClassifierState {
+        ClassifierState {
+            max_read_len: None,
+            n_total: 0,
+            num_hits: vec![0; self.database.num_files()],
+            read_len: 0,
+            touched: vec![],
+            window: vec![],
+        }
+    }
+
+    // Classifies a read, or the mates of a paired-end read with their k-mer hits pooled together
+    pub fn classify(&self, state: &mut ClassifierState, mates: &[&[u8]]) -> Classification {
+        self.classify_hits(state, self.database.kmer_rows(mates))
+    }
+
+    // Classifies a read made of the given rows of the database (one per k-mer), e.g. a read
+    // simulated from the rows of a file
+    pub(crate) fn classify_rows(
+        &self,
+        state: &mut ClassifierState,
+        rows: impl IntoIterator<Item = usize>,
+    ) -> Classification {
+        self.classify_hits(state, rows.into_iter().map(Some))
+    }
+
+    // Classifies a read from the row of each of its k-mers (None if the database does not contain
+    // the k-mer)
+    fn classify_hits(
+        &self,
+        state: &mut ClassifierState,
+        rows: impl IntoIterator<Item = Option<usize>>,
+    ) -> Classification {
+        self.start_read(state, None);
+
+        let hit_lookup_start = Instant::now();
+        state.n_total = self
+            .database
+            .add_hits(rows, &mut state.num_hits, &mut state.touched);
+        // Files are considered in the order of the database, as when every file is checked
+        state.touched.sort_unstable();
+        let hit_lookup_time = hit_lookup_start.elapsed().as_secs_f64();
+
+        self.classification(state, hit_lookup_time)
+    }
+
+    // Starts classifying a read whose sequence arrives in chunks (e.g. during adaptive sampling),
+    // which is rejected once it cannot be classified within `max_read_len` bases
+    pub fn start_read(&self, state: &mut ClassifierState, max_read_len: Option<usize>) {
+        assert_eq!(
+            self.database.num_files(),
+            state.num_hits.len(),
+            "the classifier state was created for a database with a different number of files"
+        );
+        state.reset();
+        state.max_read_len = max_read_len;
+    }
+
+    // Adds the next chunk of the read to its hits, and decides whether the read is classified by
+    // the bases so far, can no longer be classified, or needs more bases. Also returns the
+    // classification of the bases so far.
+    pub fn add_chunk(
+        &self,
+        state: &mut ClassifierState,
+        chunk: &[u8],
+    ) -> (Decision, Classification) {
+        let hit_lookup_start = Instant::now();
+
+        // Keep the last k - 1 bases of the previous chunks, so the k-mers spanning two chunks are
+        // counted exactly once
+        let overlap = state.window.len().min(self.database.kmer_len() - 1);
+        state.window.drain(..state.window.len() - overlap);
+        state.window.extend_from_slice(chunk);
+        state.read_len += chunk.len();
+        let window = std::mem::take(&mut state.window);
+        state.n_total += self.database.add_hits(
+            self.database.kmer_rows(&[&window]),
+            &mut state.num_hits,
+            &mut state.touched,
+        );
+        state.window = window;
+        state.touched.sort_unstable();
+        let hit_lookup_time = hit_lookup_start.elapsed().as_secs_f64();
+
+        let classification = self.classification(state, hit_lookup_time);
+        let decision = if classification.is_classified() {
+            Decision::Classified
+        } else if state
+            .max_read_len
+            .is_some_and(|max_read_len| !self.can_reach_cutoff(state, max_read_len))
+        {
+            Decision::Rejected
+        } else {
+            Decision::NeedsMoreData
+        };
+        (decision, classification)
+    }
+
+    // Whether any file could have a probability below the cutoff before the read is
+    // `max_read_len` bases long. At best, every remaining base adds a k-mer that hits the file,
+    // and the probabilities only decrease as the (scaled) hits increase. The exception is the
+    // probability of more than n_fixed hits, which is stored as zero and never below the cutoff,
+    // so one fewer hit is checked as well.
+    fn can_reach_cutoff(&self, state: &ClassifierState, max_read_len: usize) -> bool {
+        let remaining = max_read_len.saturating_sub(state.read_len);
+        if remaining == 0 {
+            return false;
+        }
+        let n_total = state.n_total + remaining;
+        state
+            .num_hits
+            .iter()
+            .enumerate()
+            .any(|(index, x_observed)| {
+                let x = scaled_hits(x_observed + remaining, n_total, self.n_fixed);
+                let start = index * (self.n_fixed + 1);
+                self.lookup_table[start + x.saturating_sub(1)..=start + x]
+                    .iter()
+                    .any(|probability| *probability < self.cutoff_threshold)
+            })
+    }
+
+    // Classifies the read from the hits in the state
+    fn classification(&self, state: &ClassifierState, hit_lookup_time: f64) -> Classification {
+        let prob_calc_start = Instant::now();
+        let probabilities = self.probabilities(state);
+        let (file_indices, tax_id) = match self.lca {
+            Some((taxonomy, max_log_ratio)) => match self.database.lca_files(
+                probabilities,
+                self.cutoff_threshold,
+                max_log_ratio,
+                taxonomy,
+            ) {
+                Some((file_indices, tax_id)) => (file_indices, Some(tax_id)),
+                None => (vec![], None),
+            },
+            None => match self
+                .database
+                .best_file(probabilities, self.cutoff_threshold)
+            {
+                Some(index) => (vec![index], Some(self.database.tax_ids()[index])),
+                None => (vec![], None),
+            },
+        };
+        let prob_calc_time = prob_calc_start.elapsed().as_secs_f64();
+
+        Classification {
+            file_indices,
+            hit_lookup_time,
+            n_total: state.n_total,
+            prob_calc_time,
+            tax_id,
+        }
+    }
+
+    // The (at most) `num_candidates` files with the lowest probabilities for the last read
+    // classified with the state, from lowest to highest
+    pub fn top_candidates(&self, state: &ClassifierState, num_candidates: usize) -> Vec<Candidate> {
+        lowest_candidates(
+            self.probabilities(state),
+            &state.num_hits,
+            state.n_total,
+            self.n_fixed,
+            num_candidates,
+        )
+    }
+
+    // The probability of each file with hits that is likely to be significant
+    fn probabilities<'s>(
+        &'s self,
+        state: &'s ClassifierState,
+    ) -> impl Iterator<Item = (usize, BigExpFloat)> + 's {
+        state.touched.iter().filter_map(move |index| {
+            self.database
+                .file_probability(
+                    *index,
+                    state.num_hits[*index],
+                    state.n_total,
+                    self.n_fixed,
+                    &self.lookup_table,
+                )
+                .map(|probability| (*index, probability))
+        })
+    }
+}
+
+/// The buffers of a thread classifying reads. Only the hits of files that the previous read hit
+/// are reset, rather than every file of the database.
+#[derive(Debug, Clone)]
+pub struct ClassifierState {
+    max_read_len: Option<usize>,
+    n_total: usize,
+    num_hits: Vec<usize>,
+    read_len: usize,
+    touched: Vec<usize>,
+    window: Vec<u8>,
+}
+
+impl ClassifierState {
+    // The number of bases of the read added so far with Classifier::add_chunk
+    pub fn read_len(&self) -> usize {
+        self.read_len
+    }
+
+    fn reset(&mut self) {
+        for index in self.touched.drain(..) {
+            self.num_hits[index] = 0;
+        }
+        self.max_read_len = None;
+        self.n_total = 0;
+        self.read_len = 0;
+        self.window.clear();
+    }
+}
+
+/// The decision for a read after a chunk of its sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// The read is classified by the bases so far
+    Classified,
+    /// The read cannot be classified within its maximum length
+    Rejected,
+    /// The read may still be classified with more bases
+    NeedsMoreData,
+}
+
+/// The classification of a read, or of the mates of a paired-end read
+#[derive(Debug, Clone, PartialEq)]
+pub struct Classification {
+    /// The files the read is classified to: the file with the lowest probability, or every file
+    /// in the lowest common ancestor. Empty if the read is unclassified.
+    pub file_indices: Vec<usize>,
+    pub hit_lookup_time: f64,
+    /// The total number of k-mers queried
+    pub n_total: usize,
+    pub prob_calc_time: f64,
+    /// The tax id of the file, or of the lowest common ancestor. None if the read is unclassified.
+    pub tax_id: Option<usize>,
+}
+
+impl Classification {
+    // The classification of a read without any k-mers (e.g. before its first chunk)
+    pub fn unclassified() -> Self {
+        Classification {
+            file_indices: vec![],
+            hit_lookup_time: 0.0,
+            n_total: 0,
+            prob_calc_time: 0.0,
+            tax_id: None,
+        }
+    }
+
+    pub fn is_classified(&self) -> bool {
+        self.tax_id.is_some()
+    }
+
+    // The file the read is classified to, unless it is unclassified or classified to the lowest
+    // common ancestor of several files
+    pub fn file<'a>(&self, database: &'a Database) -> Option<&'a str> {
+        match self.file_indices[..] {
+            [file_index] => Some(database.files()[file_index].as_str()),
+            _ => None,
+        }
+    }
+}
@@ -6,8 +6,13 @@ pub const DEFAULT_K: usize = 15;
 pub const DEFAULT_S: usize = 9;
 pub const DEFAULT_T: usize = 2;
 pub const DEFAULT_TOTAL_KMERS: usize = 63439162;
+// K-mers are stored in 64-bit keys using 2 bits per base
+pub const MAX_KMER_LEN: usize = 31;
 pub const TOTAL_CANONICAL_15MERS: usize = 536870912;
 
+// The largest fraction of all possible k-mers that a reference file should contain
+pub const MAX_PROB: f64 = 0.1;
+
 pub const REF_SUBDIR: &str = "skim";
 
 #[derive(Serialize, Deserialize)]
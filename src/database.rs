@@ -1,59 +1,441 @@
-use indicatif::{ParallelProgressIterator, ProgressIterator};
+use bincode::Options;
+use indicatif::{ParallelProgressIterator, ProgressBar};
+use memmap2::Mmap;
 use num_traits::Zero;
 use rayon::prelude::*;
-use roaring::RoaringBitmap;
+use roaring::RoaringTreemap;
 use serde::{Deserialize, Serialize};
 use statrs::distribution::{Binomial, DiscreteCDF};
-use std::{collections::HashMap, time::Instant, u16, u32};
-use tracing::{debug, info};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::hash::Hash;
+use std::io::{BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::path::Path;
+use std::sync::Arc;
+use std::{mem, u16, u32};
+use tracing::{debug, info, warn};
 
 use crate::{
     big_exp_float::BigExpFloat,
     binomial_sf::sf,
-    consts::BinomialConsts,
+    classifier::Classifier,
+    consts::{BinomialConsts, MAX_KMER_LEN},
+    header::{FileHeader, FileKind, MAGIC},
+    io::{
+        header_len, read_header_from, verify_checksum, write_header_to, ChecksumReader,
+        ChecksumWriter,
+    },
+    kmer_index::{KmerIndex, KmerIndexKind},
     kmer_iter::CanonicalKmerIter,
     rle::{
-        Block, BlockIter, NaiveRunLengthEncoding, RunLengthEncoding, MAX_RUN, MAX_UNCOMPRESSED_BITS,
+        Block, BlockIter, FlatRunLengthEncodings, NaiveRunLengthEncoding, RunLengthEncoding,
+        MAX_RUN, MAX_UNCOMPRESSED_BITS,
     },
-    utility::compute_total_kmers,
+    shard::{ShardConfig, ShardWriter},
+    storage::{ArraySource, MappedArrays},
+    taxonomy::Taxonomy,
+    utility::{compute_total_kmers, create_bitmap},
 };
 
-#[derive(Serialize, Deserialize)]
+// Number of k-mer ranges per thread when constructing a database
+const RANGES_PER_THREAD: usize = 64;
+
+/// The indices of the files included in a lowest common ancestor and the ancestor's tax id (if
+/// the read is classified), followed by the hit lookup and probability calculation times
+pub type LcaClassification = (Option<(Vec<usize>, usize)>, (f64, f64));
+
+/// A file that a read could be classified to, with its observed hits, its hits scaled to the
+/// fixed number of trials, and the probability of observing them by chance
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Candidate {
+    pub file_index: usize,
+    pub hits: usize,
+    pub p_value: BigExpFloat,
+    pub scaled_hits: usize,
+}
+
 pub struct Database {
+    compression_level: Option<usize>,
     consts: BinomialConsts,
     files: Box<[String]>,
+    kmer_index: KmerIndex,
+    kmer_len: usize,
+    p_values: Box<[f64]>,
+    rles: FlatRunLengthEncodings,
+    syncmer_info: Option<(usize, usize)>,
+    tax_ids: Box<[usize]>,
+    taxonomy: Option<Taxonomy>,
+}
+
+/// Everything in a database file except for the k-mer index and RLEs.
+/// On disk, the database payload is laid out as:
+///     metadata | k-mer index arrays | RLE offsets | RLE blocks
+/// where each array is aligned so that it can be used directly from a memory-mapped file.
+#[derive(Serialize, Deserialize)]
+struct DatabaseMetadata {
+    compression_level: Option<usize>,
+    files: Box<[String]>,
+    index_kind: KmerIndexKind,
+    kmer_len: usize,
+    num_blocks: usize,
+    num_kmers: usize,
+    num_rles: usize,
+    p_values: Box<[f64]>,
+    syncmer_info: Option<(usize, usize)>,
+    tax_ids: Box<[usize]>,
+    taxonomy: Option<Taxonomy>,
+}
+
+/// The layout of database files written before they had a header, which are converted by
+/// `Database::load_legacy_from_file`. Every k-mer has its own RLE.
+#[derive(Deserialize)]
+struct LegacyDatabase {
+    _consts: BinomialConsts,
+    files: Box<[String]>,
     kmer_len: usize,
     kmer_to_rle_index: HashMap<u32, u32>,
     p_values: Box<[f64]>,
-    rles: Box<[RunLengthEncoding]>,
+    rles: Box<[Box<[u16]>]>,
     syncmer_info: Option<(usize, usize)>,
     tax_ids: Box<[usize]>,
 }
 
 impl Database {
-    pub fn update_taxid(&mut self, file: String, taxid: usize) -> () {
-        let index = self
+    // Sets the tax ids of the given files and, if one is given, replaces the embedded taxonomy,
+    // either updating everything or nothing.
+    // Errors if a file is not in the database (or is given twice) or the taxonomy does not contain
+    // every new tax id. The taxonomy is pruned to the new tax ids.
+    // Returns the (file index, old tax id) of every file whose tax id changed.
+    pub fn update_tax_ids(
+        &mut self,
+        file2taxid: &[(String, usize)],
+        taxonomy: Option<Taxonomy>,
+    ) -> Result<Vec<(usize, usize)>, String> {
+        let file_indices = self
             .files
             .iter()
-            .position(|db_file| db_file.as_str() == file.as_str())
-            .expect("could not find the input file in the database");
+            .enumerate()
+            .map(|(index, file)| (file.as_str(), index))
+            .collect::<HashMap<&str, usize>>();
+
+        let mut tax_ids = self.tax_ids.to_vec();
+        let mut updated = HashSet::new();
+        let mut changes = vec![];
+        for (file, tax_id) in file2taxid {
+            let index = *file_indices
+                .get(file.as_str())
+                .ok_or_else(|| format!("file {} is not in the database", file))?;
+            if !updated.insert(index) {
+                return Err(format!("file {} is given more than once", file));
+            }
+            if tax_ids[index] != *tax_id {
+                changes.push((index, tax_ids[index]));
+                tax_ids[index] = *tax_id;
+            }
+        }
+
+        // Pruning fails if any of the tax ids is not in the taxonomy
+        self.taxonomy = taxonomy
+            .as_ref()
+            .or(self.taxonomy.as_ref())
+            .map(|taxonomy| taxonomy.pruned(&tax_ids))
+            .transpose()?;
+        self.tax_ids = tax_ids.into_boxed_slice();
+        Ok(changes)
+    }
+
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    pub fn tax_ids(&self) -> &[usize] {
+        &self.tax_ids
+    }
+
+    pub fn taxonomy(&self) -> Option<&Taxonomy> {
+        self.taxonomy.as_ref()
+    }
+
+    // Embeds the taxonomy in the database, which must contain the tax id of every file.
+    // The taxonomy should already be pruned (see `Taxonomy::from_ncbi`) to keep the database small.
+    pub fn set_taxonomy(&mut self, taxonomy: Taxonomy) -> Result<(), String> {
+        if let Some(tax_id) = self
+            .tax_ids
+            .iter()
+            .find(|tax_id| !taxonomy.contains(**tax_id))
+        {
+            return Err(format!("tax id {} is not in the taxonomy", tax_id));
+        }
+        self.taxonomy = Some(taxonomy);
+        Ok(())
+    }
 
-        assert_ne!(self.tax_ids[index], taxid);
+    pub fn p_values(&self) -> &[f64] {
+        &self.p_values
+    }
 
-        self.tax_ids[index] = taxid;
+    pub fn kmer_len(&self) -> usize {
+        self.kmer_len
     }
 
     pub fn num_files(&self) -> usize {
         self.files.len()
     }
 
+    pub fn header(&self) -> FileHeader {
+        FileHeader {
+            compression_level: self.compression_level,
+            kind: FileKind::Database,
+            kmer_len: self.kmer_len,
+            num_files: self.num_files(),
+            syncmer_info: self.syncmer_info,
+        }
+    }
+
+    pub fn num_rles(&self) -> usize {
+        self.rles.len()
+    }
+
+    pub fn kmer_index(&self) -> &KmerIndex {
+        &self.kmer_index
+    }
+
+    pub fn rles(&self) -> &FlatRunLengthEncodings {
+        &self.rles
+    }
+
+    pub fn dump_to_file(&self, file: File) -> bincode::Result<()> {
+        let mut buf_writer = BufWriter::new(file);
+        let header_len = write_header_to(&mut buf_writer, &self.header())?;
+
+        // Write the payload while computing its checksum
+        let mut checksum_writer = ChecksumWriter::new(&mut buf_writer, header_len);
+        let metadata = DatabaseMetadata {
+            compression_level: self.compression_level,
+            files: self.files.clone(),
+            index_kind: self.kmer_index.kind(),
+            kmer_len: self.kmer_len,
+            num_blocks: self.rles.num_of_blocks(),
+            num_kmers: self.kmer_index.num_kmers(),
+            num_rles: self.rles.len(),
+            p_values: self.p_values.clone(),
+            syncmer_info: self.syncmer_info,
+            tax_ids: self.tax_ids.clone(),
+            taxonomy: self.taxonomy.clone(),
+        };
+        bincode::serialize_into(&mut checksum_writer, &metadata)?;
+        self.kmer_index.write_to(&mut checksum_writer)?;
+        self.rles.write_to(&mut checksum_writer)?;
+
+        // Finally, write the checksum itself
+        let checksum = checksum_writer.finalize();
+        buf_writer.write_all(&checksum.to_le_bytes())?;
+
+        buf_writer.flush()?;
+        Ok(())
+    }
+
+    // Reads the whole database into memory, verifying its checksum
+    pub fn load_from_file(path: &Path) -> Result<Self, String> {
+        let file =
+            File::open(path).map_err(|e| format!("could not open file at {:?}: {}", path, e))?;
+        let file_len = file
+            .metadata()
+            .map_err(|e| format!("could not open file at {:?}: {}", path, e))?
+            .len();
+        let mut buf_reader = BufReader::new(file);
+        let header = read_header_from(&mut buf_reader, path, Some(FileKind::Database))?;
+        let header_len = header_len(&header).map_err(|e| e.to_string())?;
+
+        // Read the payload while computing its checksum
+        let mut checksum_reader =
+            ChecksumReader::new(&mut buf_reader, header_len).with_file_len(file_len);
+        let metadata: DatabaseMetadata = bincode::deserialize_from(&mut checksum_reader)
+            .map_err(|e| format!("failed to read database at {:?}: {}", path, e))?;
+        let database = Database::read_from(&mut checksum_reader, &header, metadata)
+            .map_err(|e| format!("failed to read database at {:?}: {}", path, e))?;
+        let computed_checksum = checksum_reader.finalize();
+        verify_checksum(&mut buf_reader, computed_checksum, path)?;
+
+        Ok(database)
+    }
+
+    // Memory-maps the database so that classification can start without reading the whole file.
+    // Multiple processes mapping the same file share one copy in the page cache.
+    // The checksum is not verified because that would require reading the whole file.
+    pub fn load_from_file_mmap(path: &Path) -> Result<Self, String> {
+        let file =
+            File::open(path).map_err(|e| format!("could not open file at {:?}: {}", path, e))?;
+
+        // SAFETY: the database file must not be modified while it is mapped
+        let mmap = Arc::new(
+            unsafe { Mmap::map(&file) }
+                .map_err(|e| format!("could not memory-map {:?}: {}", path, e))?,
+        );
+
+        let mut cursor = Cursor::new(&mmap[..]);
+        let header = read_header_from(&mut cursor, path, Some(FileKind::Database))?;
+        let metadata: DatabaseMetadata = bincode::deserialize_from(&mut cursor)
+            .map_err(|e| format!("failed to read database at {:?}: {}", path, e))?;
+
+        // Borrow the arrays directly from the mapped file
+        let mut mapped_arrays = MappedArrays {
+            mmap: &mmap,
+            position: cursor.position(),
+        };
+        Database::read_from(&mut mapped_arrays, &header, metadata)
+            .map_err(|e| format!("{:?} is truncated or corrupted: {}", path, e))
+    }
+
+    // Reads a database file written before databases had a header (and deduplicated identical
+    // RLEs), storing each distinct RLE once. Such files do not record whether they were lossy
+    // compressed, so the compression level must be given for them.
+    pub fn load_legacy_from_file(
+        path: &Path,
+        compression_level: Option<usize>,
+    ) -> Result<Self, String> {
+        let file =
+            File::open(path).map_err(|e| format!("could not open file at {:?}: {}", path, e))?;
+        let file_len = file
+            .metadata()
+            .map_err(|e| format!("could not open file at {:?}: {}", path, e))?
+            .len();
+        let mut buf_reader = BufReader::new(file);
+        let mut magic = [0_u8; MAGIC.len()];
+        if buf_reader.read_exact(&mut magic).is_ok() && magic == MAGIC {
+            return Err(format!(
+                "{:?} is not a legacy database, and is already deduplicated",
+                path
+            ));
+        }
+        buf_reader
+            .seek(SeekFrom::Start(0))
+            .map_err(|e| format!("could not read file at {:?}: {}", path, e))?;
+
+        // Legacy files were written with the default bincode options, and nothing in the file
+        // can be longer than the file itself
+        let legacy: LegacyDatabase = bincode::options()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(file_len)
+            .deserialize_from(buf_reader)
+            .map_err(|e| format!("failed to read legacy database at {:?}: {}", path, e))?;
+        let num_files = legacy.files.len();
+        if legacy.tax_ids.len() != num_files || legacy.p_values.len() != num_files {
+            return Err(format!(
+                "legacy database at {:?} does not have a tax id and p-value for every file",
+                path
+            ));
+        }
+        if legacy.kmer_len == 0 || legacy.kmer_len > 16 {
+            return Err(format!(
+                "legacy database at {:?} has an invalid k-mer length of {}",
+                path, legacy.kmer_len
+            ));
+        }
+        if legacy.kmer_to_rle_index.iter().any(|(kmer, rle_index)| {
+            (*kmer as u64) >> (legacy.kmer_len << 1) != 0
+                || *rle_index as usize >= legacy.rles.len()
+        }) {
+            return Err(format!(
+                "legacy database at {:?} has an invalid k-mer or rle index",
+                path
+            ));
+        }
+
+        let mut pairs = legacy
+            .kmer_to_rle_index
+            .into_iter()
+            .collect::<Vec<(u32, u32)>>();
+        pairs.sort_unstable();
+        let (kmers, rle_indices) = pairs
+            .into_iter()
+            .map(|(kmer, rle_index)| (kmer as u64, rle_index))
+            .unzip::<u64, u32, Vec<u64>, Vec<u32>>();
+        let index_kind = KmerIndexKind::choose(legacy.kmer_len, kmers.len());
+        let kmer_index =
+            KmerIndex::from_sorted_kmers(kmers, rle_indices, legacy.kmer_len, index_kind);
+        let rles = FlatRunLengthEncodings::from_rles(
+            legacy
+                .rles
+                .into_vec()
+                .into_iter()
+                .map(RunLengthEncoding::from),
+        );
+
+        let mut database = Database {
+            compression_level,
+            consts: BinomialConsts::new(),
+            files: legacy.files,
+            kmer_index,
+            kmer_len: legacy.kmer_len,
+            p_values: legacy.p_values,
+            rles,
+            syncmer_info: legacy.syncmer_info,
+            tax_ids: legacy.tax_ids,
+            taxonomy: None,
+        };
+        database.deduplicate_rows();
+
+        Ok(database)
+    }
+
+    fn read_from<S: ArraySource>(
+        source: &mut S,
+        header: &FileHeader,
+        metadata: DatabaseMetadata,
+    ) -> Result<Self, String> {
+        if metadata.files.len() != header.num_files
+            || metadata.tax_ids.len() != header.num_files
+            || metadata.p_values.len() != header.num_files
+        {
+            return Err("database header does not match its contents".to_string());
+        }
+        if metadata.kmer_len == 0 || metadata.kmer_len > MAX_KMER_LEN {
+            return Err(format!(
+                "k-mer length {} is not supported (at most {})",
+                metadata.kmer_len, MAX_KMER_LEN
+            ));
+        }
+
+        let kmer_index = KmerIndex::read_from(
+            source,
+            metadata.index_kind,
+            metadata.kmer_len,
+            metadata.num_kmers,
+        )?;
+        let rles =
+            FlatRunLengthEncodings::read_from(source, metadata.num_rles, metadata.num_blocks)?;
+
+        Ok(Database {
+            compression_level: metadata.compression_level,
+            consts: BinomialConsts::new(),
+            files: metadata.files,
+            kmer_index,
+            kmer_len: metadata.kmer_len,
+            p_values: metadata.p_values,
+            rles,
+            syncmer_info: metadata.syncmer_info,
+            tax_ids: metadata.tax_ids,
+            taxonomy: metadata.taxonomy,
+        })
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        self.rles.is_mapped()
+    }
+
+    // Builds the database from the k-mers of each file. Errors if the given index kind does not fit
+    // the k-mers, and otherwise picks one.
     pub fn from(
-        file_bitmaps: Vec<RoaringBitmap>,
+        file_bitmaps: Vec<RoaringTreemap>,
         files: Vec<String>,
         tax_ids: Vec<usize>,
         kmer_len: usize,
         syncmer_info: Option<(usize, usize)>,
-    ) -> Self {
+        index_kind: Option<KmerIndexKind>,
+    ) -> Result<Self, String> {
         let total_kmers = compute_total_kmers(kmer_len, syncmer_info);
         debug!("{} total possible k-mers", total_kmers);
 
@@ -69,71 +451,623 @@ impl Database {
             .map(|size| size as f64 / total_kmers as f64)
             .collect::<Box<[f64]>>();
 
-        // Initialize the naive RLEs to be the maximum possible size to avoid new allocations
-        let mut naive_rles = vec![NaiveRunLengthEncoding::new(); total_kmers];
-        let mut kmer_to_rle_index = HashMap::with_capacity(total_kmers);
-        let mut next_rle_index = 0_u32;
-
-        // Construct all naive kmer RLEs from the bitmaps
-        info!("constructing naive runs...");
-        for (index, bitmap) in file_bitmaps.into_iter().enumerate().progress() {
-            for kmer in bitmap {
-                let naive_rle_index = kmer_to_rle_index.entry(kmer).or_insert_with(|| {
-                    let new_rle_index = next_rle_index;
-                    next_rle_index += 1;
-                    new_rle_index
-                });
-                naive_rles[*naive_rle_index as usize].push(index);
-            }
-        }
+        // Split the k-mer space into disjoint ranges that are processed in parallel
+        // There are many more ranges than threads so that the work stays balanced
+        let kmer_space = 1_usize << (kmer_len << 1);
+        let num_ranges = (rayon::current_num_threads() * RANGES_PER_THREAD)
+            .next_power_of_two()
+            .min(kmer_space);
+        let range_len = kmer_space / num_ranges;
+        debug!("constructing runs in {} k-mer ranges", num_ranges);
 
-        // Filter out naive rles that do not have any blocks
-        let naive_rles = naive_rles
-            .into_iter()
-            .filter_map(|naive_rle| {
-                if naive_rle.num_of_blocks() == 0 {
-                    None
-                } else {
-                    Some(naive_rle)
+        // Construct the naive kmer RLEs of each range from the bitmaps
+        // Every range walks the bitmaps in file order, so each naive RLE is pushed to in order
+        info!("constructing naive runs and allowing uncompressed bit sets...");
+        let ranges = (0..num_ranges)
+            .into_par_iter()
+            .progress()
+            .map(|range_index| {
+                let first_kmer = (range_index * range_len) as u64;
+                let last_kmer = (range_index * range_len + range_len - 1) as u64;
+                let mut naive_rles = HashMap::new();
+                for (index, bitmap) in file_bitmaps.iter().enumerate() {
+                    let mut kmers = bitmap.iter();
+                    kmers.advance_to(first_kmer);
+                    for kmer in kmers.take_while(|kmer| *kmer <= last_kmer) {
+                        naive_rles
+                            .entry(kmer)
+                            .or_insert_with(NaiveRunLengthEncoding::new)
+                            .push(index);
+                    }
                 }
+
+                // Sort the k-mers of the range and compress their rles by allowing the use of
+                // uncompressed bit sets
+                let mut naive_rles = naive_rles
+                    .into_iter()
+                    .collect::<Vec<(u64, NaiveRunLengthEncoding)>>();
+                naive_rles.sort_unstable_by_key(|(kmer, _naive_rle)| *kmer);
+                let naive_run_count = naive_rles
+                    .iter()
+                    .map(|(_kmer, naive_rle)| naive_rle.num_of_blocks())
+                    .sum::<usize>();
+                let (kmers, rles) = naive_rles
+                    .into_iter()
+                    .map(|(kmer, naive_rle)| (kmer, naive_rle.to_rle()))
+                    .unzip::<u64, RunLengthEncoding, Vec<u64>, Vec<RunLengthEncoding>>();
+                (kmers, rles, naive_run_count)
             })
-            .collect::<Vec<NaiveRunLengthEncoding>>();
+            .collect::<Vec<(Vec<u64>, Vec<RunLengthEncoding>, usize)>>();
+        drop(file_bitmaps);
 
         // Log information about the number of naive runs
-        let naive_run_count = naive_rles
-            .par_iter()
-            .map(|naive_rle| naive_rle.num_of_blocks())
+        let naive_run_count = ranges
+            .iter()
+            .map(|(_kmers, _rles, naive_run_count)| naive_run_count)
             .sum::<usize>();
         debug!("number of naive rle runs: {}", naive_run_count);
 
-        // Compress the database by allowing the use of uncompressed bit sets
-        info!("naive runs constructed! allowing uncompressed bit sets...");
-        let rles = naive_rles
-            .into_par_iter()
-            .progress()
-            .map(|naive_rle| naive_rle.to_rle())
-            .collect::<Box<[RunLengthEncoding]>>();
+        // Concatenate the ranges, which are already in k-mer order
+        let mut kmers = Vec::with_capacity(ranges.iter().map(|(kmers, _, _)| kmers.len()).sum());
+        let mut rles = Vec::with_capacity(kmers.capacity());
+        for (range_kmers, range_rles, _naive_run_count) in ranges {
+            kmers.extend(range_kmers);
+            rles.extend(range_rles);
+        }
+        let rles = FlatRunLengthEncodings::from_rles(rles);
+
+        // Create the k-mer index
+        let index_kind = match index_kind {
+            Some(index_kind) => {
+                index_kind.check(kmer_len, kmers.len())?;
+                index_kind
+            }
+            None => KmerIndexKind::choose(kmer_len, kmers.len()),
+        };
+        let rle_indices = (0..kmers.len() as u32).collect::<Vec<u32>>();
+        let kmer_index = KmerIndex::from_sorted_kmers(kmers, rle_indices, kmer_len, index_kind);
+        debug!(
+            "{:?} k-mer index of {} k-mers uses {} bytes",
+            index_kind,
+            kmer_index.num_kmers(),
+            kmer_index.size_in_bytes()
+        );
 
         // Log information about the number of compressed runs
-        let compressed_block_num = rles
-            .par_iter()
-            .map(|rle| rle.num_of_blocks())
-            .sum::<usize>();
         debug!(
             "number of rle runs after allowing uncompressed bit sets: {}",
-            compressed_block_num
+            rles.num_of_blocks()
         );
 
-        Database {
+        let mut database = Database {
+            compression_level: None,
             consts: BinomialConsts::new(),
             files: files.into_boxed_slice(),
+            kmer_index,
             kmer_len,
-            kmer_to_rle_index,
             p_values,
             rles,
             syncmer_info,
             tax_ids: tax_ids.into_boxed_slice(),
+            taxonomy: None,
+        };
+
+        // Many k-mers are in exactly the same set of files, so store their rles only once
+        database.deduplicate_rows();
+
+        Ok(database)
+    }
+
+    pub fn deduplicate_rows(&mut self) {
+        let (rles, mapping) = self.rles.deduplicate();
+        debug!(
+            "collapsed {} identical rle rows: {} rows before, {} rows after",
+            self.rles.len() - rles.len(),
+            self.rles.len(),
+            rles.len()
+        );
+        debug!(
+            "number of rle runs after deduplicating rows: {} (previously {})",
+            rles.num_of_blocks(),
+            self.rles.num_of_blocks()
+        );
+
+        self.kmer_index.remap_rle_indices(&mapping);
+        self.rles = rles;
+    }
+
+    // Adds new files to the end of the database, as if they had been included when it was built
+    pub fn add_files(
+        &mut self,
+        file_bitmaps: Vec<RoaringTreemap>,
+        files: Vec<String>,
+        tax_ids: Vec<usize>,
+    ) -> Result<(), String> {
+        if self.compression_level.is_some() {
+            return Err("files cannot be added to a lossy compressed database".to_string());
+        }
+        if file_bitmaps.len() != files.len() || files.len() != tax_ids.len() {
+            return Err("a bitmap and tax id must be provided for every new file".to_string());
+        }
+        let mut all_files = self.files.iter().collect::<HashSet<&String>>();
+        if let Some(file) = files.iter().find(|file| !all_files.insert(file)) {
+            return Err(format!("{} is already in the database", file));
         }
+        if let Some(taxonomy) = &self.taxonomy {
+            if let Some(tax_id) = tax_ids.iter().find(|tax_id| !taxonomy.contains(**tax_id)) {
+                return Err(format!(
+                    "tax id {} is not in the database's taxonomy, which must be replaced first",
+                    tax_id
+                ));
+            }
+        }
+
+        // Collect the (k-mer, file index) pairs of the new files, grouped by k-mer
+        let first_new_index = self.num_files();
+        let mut new_kmers = file_bitmaps
+            .into_par_iter()
+            .enumerate()
+            .flat_map_iter(|(index, bitmap)| {
+                bitmap
+                    .into_iter()
+                    .map(move |kmer| (kmer, (first_new_index + index) as u32))
+            })
+            .collect::<Vec<(u64, u32)>>();
+        new_kmers.par_sort_unstable();
+        debug!("{} (k-mer, file) pairs to add", new_kmers.len());
+
+        // A k-mer's new rle depends only on its previous rle and the new files that contain it
+        let mut new_kmers = new_kmers
+            .chunk_by(|(kmer_a, _), (kmer_b, _)| kmer_a == kmer_b)
+            .map(|pairs| {
+                (
+                    pairs[0].0,
+                    pairs.iter().map(|(_, index)| *index).collect::<Vec<u32>>(),
+                )
+            })
+            .peekable();
+        let mut kmer_keys = Vec::with_capacity(self.kmer_index.num_kmers());
+        for (kmer, rle_index) in self.kmer_index.iter() {
+            while let Some((new_kmer, new_files)) =
+                new_kmers.next_if(|(new_kmer, _)| *new_kmer < kmer)
+            {
+                kmer_keys.push((new_kmer, (None, new_files)));
+            }
+            let new_files = new_kmers
+                .next_if(|(new_kmer, _)| *new_kmer == kmer)
+                .map(|(_, new_files)| new_files)
+                .unwrap_or_default();
+            kmer_keys.push((kmer, (Some(rle_index), new_files)));
+        }
+        kmer_keys.extend(new_kmers.map(|(new_kmer, new_files)| (new_kmer, (None, new_files))));
+
+        // The new k-mers can make the index kind no longer fit (e.g. too many for a bit vector)
+        info!("extending rles with the new files...");
+        let (index_kind, kmer_len) = (self.kmer_index.kind(), self.kmer_len);
+        self.rebuild_rles(
+            kmer_keys,
+            |rles, (rle_index, new_files)| {
+                let mut indices = match rle_index {
+                    Some(rle_index) => rles.collect_indices(*rle_index),
+                    None => vec![],
+                };
+                indices.extend(new_files);
+                indices
+            },
+            |num_kmers| index_kind.check_or_choose(kmer_len, num_kmers),
+        );
+
+        self.files = [mem::take(&mut self.files).into_vec(), files]
+            .concat()
+            .into();
+        self.tax_ids = [mem::take(&mut self.tax_ids).into_vec(), tax_ids]
+            .concat()
+            .into();
+
+        info!("recomputing p-values for all targets");
+        self.recompute_p_values();
+
+        Ok(())
+    }
+
+    // Removes files from the database, shifting the indices of the files after them down.
+    // K-mers that are no longer in any file are removed as well.
+    pub fn remove_files(&mut self, file_indices: &[usize]) -> Result<(), String> {
+        if let Some(index) = file_indices
+            .iter()
+            .find(|index| **index >= self.num_files())
+        {
+            return Err(format!(
+                "file index {} is out of range for a database with {} files",
+                index,
+                self.num_files()
+            ));
+        }
+        let remove = file_indices.iter().copied().collect::<HashSet<usize>>();
+        if remove.len() == self.num_files() {
+            return Err("every file in the database would be removed".to_string());
+        }
+
+        // The new index of every file that is kept
+        let mut next_index = 0_u32;
+        let new_indices = (0..self.num_files())
+            .map(|index| {
+                (!remove.contains(&index)).then(|| {
+                    next_index += 1;
+                    next_index - 1
+                })
+            })
+            .collect::<Vec<Option<u32>>>();
+
+        // Every k-mer with the same rle still shares the same (possibly empty) rle
+        info!("removing {} files from rles...", remove.len());
+        let kmer_keys = self.kmer_index.iter().collect::<Vec<(u64, usize)>>();
+        // Fewer k-mers may fit a faster index (or a smaller one), so the kind is chosen again
+        let kmer_len = self.kmer_len;
+        self.rebuild_rles(
+            kmer_keys,
+            |rles, rle_index| {
+                rles.collect_indices(*rle_index)
+                    .into_iter()
+                    .filter_map(|index| new_indices[index as usize])
+                    .collect()
+            },
+            |num_kmers| KmerIndexKind::choose(kmer_len, num_kmers),
+        );
+
+        self.files = mem::take(&mut self.files)
+            .into_vec()
+            .into_iter()
+            .zip(&new_indices)
+            .filter_map(|(file, new_index)| new_index.map(|_| file))
+            .collect();
+        self.tax_ids = self
+            .tax_ids
+            .iter()
+            .zip(&new_indices)
+            .filter_map(|(tax_id, new_index)| new_index.map(|_| *tax_id))
+            .collect();
+        self.taxonomy = self
+            .taxonomy
+            .as_ref()
+            .map(|taxonomy| taxonomy.pruned(&self.tax_ids))
+            .transpose()?;
+
+        info!("recomputing p-values for all targets");
+        self.recompute_p_values();
+
+        Ok(())
+    }
+
+    // Adds the files of another database built with the same parameters after the files of this
+    // database, as if they had all been included when it was built
+    pub fn merge(&mut self, other: &Database) -> Result<(), String> {
+        if self.kmer_len != other.kmer_len || self.syncmer_info != other.syncmer_info {
+            return Err(format!(
+                "databases were built with different parameters: ({}) and ({})",
+                self.header(),
+                other.header()
+            ));
+        }
+        if self.compression_level.is_some() || other.compression_level.is_some() {
+            return Err("lossy compressed databases cannot be merged".to_string());
+        }
+        let files = self.files.iter().collect::<HashSet<&String>>();
+        if let Some(file) = other.files.iter().find(|file| files.contains(file)) {
+            return Err(format!("{} is in both databases", file));
+        }
+
+        // The merged taxonomy must cover the tax ids of both databases
+        let tax_ids = [&self.tax_ids[..], &other.tax_ids[..]].concat();
+        let taxonomy = match (&self.taxonomy, &other.taxonomy) {
+            (Some(taxonomy), Some(other_taxonomy)) => Some(taxonomy.merged(other_taxonomy)),
+            (Some(taxonomy), None) | (None, Some(taxonomy)) => Some(taxonomy.clone()),
+            (None, None) => None,
+        }
+        .map(|taxonomy| taxonomy.pruned(&tax_ids))
+        .transpose()
+        .map_err(|e| format!("databases cannot be merged: {}", e))?;
+
+        // A k-mer's new rle depends only on its rles in each of the databases
+        let mut other_kmers = other.kmer_index.iter().peekable();
+        let mut kmer_keys = Vec::with_capacity(self.kmer_index.num_kmers());
+        for (kmer, rle_index) in self.kmer_index.iter() {
+            while let Some((other_kmer, other_rle_index)) =
+                other_kmers.next_if(|(other_kmer, _)| *other_kmer < kmer)
+            {
+                kmer_keys.push((other_kmer, (None, Some(other_rle_index))));
+            }
+            let other_rle_index = other_kmers
+                .next_if(|(other_kmer, _)| *other_kmer == kmer)
+                .map(|(_, other_rle_index)| other_rle_index);
+            kmer_keys.push((kmer, (Some(rle_index), other_rle_index)));
+        }
+        kmer_keys.extend(
+            other_kmers
+                .map(|(other_kmer, other_rle_index)| (other_kmer, (None, Some(other_rle_index)))),
+        );
+
+        // The indices of the other database's files come after this database's files
+        let offset = self.num_files() as u32;
+        // The k-mers of both databases can make the index kind no longer fit (e.g. too many for a
+        // bit vector)
+        info!("merging rles...");
+        let (index_kind, kmer_len) = (self.kmer_index.kind(), self.kmer_len);
+        self.rebuild_rles(
+            kmer_keys,
+            |rles, (rle_index, other_rle_index)| {
+                let mut indices = match rle_index {
+                    Some(rle_index) => rles.collect_indices(*rle_index),
+                    None => vec![],
+                };
+                if let Some(other_rle_index) = other_rle_index {
+                    indices.extend(
+                        other
+                            .rles
+                            .collect_indices(*other_rle_index)
+                            .into_iter()
+                            .map(|index| index + offset),
+                    );
+                }
+                indices
+            },
+            |num_kmers| index_kind.check_or_choose(kmer_len, num_kmers),
+        );
+
+        self.files = [&self.files[..], &other.files[..]].concat().into();
+        self.tax_ids = tax_ids.into();
+        self.taxonomy = taxonomy;
+
+        info!("recomputing p-values for all targets");
+        self.recompute_p_values();
+
+        Ok(())
+    }
+
+    // Replaces the k-mer index and rles using (k-mer, key) pairs sorted by k-mer.
+    // K-mers with the same key share the rle of the file indices returned by `file_indices_of`,
+    // which is given the current rles.
+    // K-mers that end up in no files are removed from the database.
+    fn rebuild_rles<K, F, I>(&mut self, kmer_keys: Vec<(u64, K)>, file_indices_of: F, index_kind: I)
+    where
+        K: Hash + Eq + Sync,
+        F: Fn(&FlatRunLengthEncodings, &K) -> Vec<u32> + Sync,
+        I: FnOnce(usize) -> KmerIndexKind,
+    {
+        // Give every distinct key an index
+        let mut key_to_index = HashMap::new();
+        let kmer_keys = kmer_keys
+            .into_iter()
+            .map(|(kmer, key)| {
+                let next_index = key_to_index.len() as u32;
+                (kmer, *key_to_index.entry(key).or_insert(next_index))
+            })
+            .collect::<Vec<(u64, u32)>>();
+        let mut keys = key_to_index.into_iter().collect::<Vec<(K, u32)>>();
+        keys.sort_unstable_by_key(|(_key, index)| *index);
+
+        // Create the rle of every key, leaving out the empty ones
+        let rles = keys
+            .par_iter()
+            .map(|(key, _index)| {
+                let indices = file_indices_of(&self.rles, key);
+                (!indices.is_empty()).then(|| {
+                    RunLengthEncoding::from_indices(indices.into_iter().map(|i| i as usize))
+                })
+            })
+            .collect::<Vec<Option<RunLengthEncoding>>>();
+        let mut next_rle_index = 0_u32;
+        let key_to_rle_index = rles
+            .iter()
+            .map(|rle| {
+                rle.as_ref().map(|_| {
+                    next_rle_index += 1;
+                    next_rle_index - 1
+                })
+            })
+            .collect::<Vec<Option<u32>>>();
+
+        let (kmers, rle_indices) = kmer_keys
+            .into_iter()
+            .filter_map(|(kmer, key_index)| {
+                key_to_rle_index[key_index as usize].map(|rle_index| (kmer, rle_index))
+            })
+            .unzip::<u64, u32, Vec<u64>, Vec<u32>>();
+        debug!("{} k-mers in the rebuilt database", kmers.len());
+
+        // The index kind may have to change with the number of k-mers
+        let index_kind = index_kind(kmers.len());
+        debug!("{:?} k-mer index in the rebuilt database", index_kind);
+        self.kmer_index =
+            KmerIndex::from_sorted_kmers(kmers, rle_indices, self.kmer_len, index_kind);
+        self.rles = FlatRunLengthEncodings::from_rles(rles.into_iter().flatten());
+
+        // Different keys can still result in the same rle
+        self.deduplicate_rows();
+    }
+
+    // Builds the same database as `Database::from`, but without holding the bitmaps of every file
+    // in memory at the same time. The (k-mer, file index) pairs of the files are spilled to shards
+    // of the k-mer space, which are then turned into rles one at a time. The memory limit bounds
+    // the bitmaps created at a time (except that the bitmap of a single file is always created)
+    // and the pairs of each shard, but not the database itself or the buffers of the shards.
+    pub fn from_files_sharded(
+        reference_dir: &Path,
+        files: Vec<String>,
+        tax_ids: Vec<usize>,
+        kmer_len: usize,
+        syncmer_info: Option<(usize, usize)>,
+        index_kind: Option<KmerIndexKind>,
+        config: &ShardConfig,
+    ) -> Result<Self, String> {
+        let total_kmers = compute_total_kmers(kmer_len, syncmer_info);
+        debug!("{} total possible k-mers", total_kmers);
+
+        // Each base of a file results in at most one k-mer, so the total size of the files is a
+        // first estimate of the number of pairs. It is too small for compressed files (and shards
+        // are not balanced), so shards that do not fit are split once their pairs are counted.
+        let file_bytes = files
+            .iter()
+            .map(|file| {
+                fs::metadata(reference_dir.join(file))
+                    .map(|metadata| metadata.len() as usize)
+                    .map_err(|e| format!("could not read {:?}: {}", reference_dir.join(file), e))
+            })
+            .collect::<Result<Vec<usize>, String>>()?;
+        let total_file_bytes = file_bytes.iter().sum::<usize>();
+        let pair_bytes = size_of::<[u64; 2]>();
+        let num_shards = (total_file_bytes * pair_bytes).div_ceil(config.max_memory.max(1));
+
+        let temp_dir = tempfile::tempdir_in(&config.temp_dir).map_err(|e| {
+            format!(
+                "could not create a temporary directory in {:?}: {}",
+                config.temp_dir, e
+            )
+        })?;
+        let mut shard_writer =
+            ShardWriter::create(temp_dir.path(), "shard", kmer_len << 1, num_shards)?;
+        debug!(
+            "spilling k-mers to {} shards in {:?}",
+            shard_writer.num_shards(),
+            temp_dir.path()
+        );
+
+        // Create the bitmaps of up to as many files at a time as there are threads and spill their
+        // k-mers, keeping track of the size of each bitmap to compute the p-values. Files are only
+        // added to a chunk while their bitmaps are expected to fit within the memory limit, going
+        // by the largest bitmap per byte of file so far (so the first chunk is a single file).
+        info!("spilling k-mers of each file to shards...");
+        let max_chunk_len = rayon::current_num_threads();
+        let mut bitmap_bytes_per_file_byte: Option<f64> = None;
+        let mut bitmap_sizes = Vec::with_capacity(files.len());
+        let progress_bar = ProgressBar::new(files.len() as u64);
+        let mut chunk_start = 0;
+        while chunk_start < files.len() {
+            let mut chunk_end = chunk_start + 1;
+            if let Some(ratio) = bitmap_bytes_per_file_byte {
+                let mut chunk_bytes = file_bytes[chunk_start] as f64 * ratio;
+                while chunk_end < files.len() && chunk_end - chunk_start < max_chunk_len {
+                    chunk_bytes += file_bytes[chunk_end] as f64 * ratio;
+                    if chunk_bytes > config.max_memory as f64 {
+                        break;
+                    }
+                    chunk_end += 1;
+                }
+            }
+
+            let bitmaps = files[chunk_start..chunk_end]
+                .par_iter()
+                .map(|file| create_bitmap(reference_dir.join(file), kmer_len, syncmer_info))
+                .collect::<Vec<RoaringTreemap>>();
+            for (file_index, bitmap) in (chunk_start..chunk_end).zip(bitmaps) {
+                let ratio = bitmap.serialized_size() as f64 / file_bytes[file_index].max(1) as f64;
+                bitmap_bytes_per_file_byte = Some(
+                    bitmap_bytes_per_file_byte.map_or(ratio, |max_ratio| max_ratio.max(ratio)),
+                );
+                bitmap_sizes.push(bitmap.len());
+                for kmer in bitmap {
+                    shard_writer.write(kmer, file_index as u32)?;
+                }
+            }
+            progress_bar.inc((chunk_end - chunk_start) as u64);
+            chunk_start = chunk_end;
+        }
+        progress_bar.finish();
+        let mut shards = shard_writer.finish()?;
+        let total_pairs = shards.iter().map(|shard| shard.num_pairs).sum::<usize>();
+        debug!("total bits set: {}", bitmap_sizes.iter().sum::<u64>());
+        let p_values = bitmap_sizes
+            .into_iter()
+            .map(|size| size as f64 / total_kmers as f64)
+            .collect::<Box<[f64]>>();
+
+        // Shards are in k-mer order, so rles are created (and deduplicated) in the same order as
+        // `Database::from`. Shards are taken from the end, so split shards replace their parent
+        // in the same order.
+        info!("constructing rles shard by shard...");
+        let max_pairs = (config.max_memory / pair_bytes).max(1);
+        let progress_bar = ProgressBar::new(total_pairs as u64);
+        let mut kmers = vec![];
+        let mut rle_indices = vec![];
+        let mut distinct_rles = HashMap::new();
+        shards.reverse();
+        while let Some(shard) = shards.pop() {
+            if shard.num_pairs > max_pairs {
+                if shard.can_split() {
+                    let num_shards = shard.num_pairs.div_ceil(max_pairs);
+                    debug!("splitting a shard with {} k-mer pairs", shard.num_pairs);
+                    shards.extend(shard.split(num_shards)?.into_iter().rev());
+                    continue;
+                }
+                warn!(
+                    "a shard with {} k-mer pairs of a single k-mer does not fit within the memory limit",
+                    shard.num_pairs
+                );
+            }
+            progress_bar.inc(shard.num_pairs as u64);
+            let mut pairs = shard.read_pairs()?;
+            pairs.par_sort_unstable();
+            let shard_rles = pairs
+                .par_chunk_by(|pair_a, pair_b| pair_a[0] == pair_b[0])
+                .map(|kmer_pairs| {
+                    let indices = kmer_pairs.iter().map(|pair| pair[1] as usize);
+                    (
+                        kmer_pairs[0][0],
+                        RunLengthEncoding::from_indices(indices).into_raw_blocks(),
+                    )
+                })
+                .collect::<Vec<(u64, Box<[u16]>)>>();
+            for (kmer, blocks) in shard_rles {
+                let next_rle_index = distinct_rles.len() as u32;
+                rle_indices.push(*distinct_rles.entry(blocks).or_insert(next_rle_index));
+                kmers.push(kmer);
+            }
+        }
+        progress_bar.finish();
+        let mut distinct_rles = distinct_rles
+            .into_iter()
+            .collect::<Vec<(Box<[u16]>, u32)>>();
+        distinct_rles.sort_unstable_by_key(|(_blocks, rle_index)| *rle_index);
+        let rles = FlatRunLengthEncodings::from_rles(
+            distinct_rles
+                .into_iter()
+                .map(|(blocks, _rle_index)| RunLengthEncoding::from(blocks)),
+        );
+        debug!(
+            "{} k-mers share {} distinct rles with {} runs",
+            kmers.len(),
+            rles.len(),
+            rles.num_of_blocks()
+        );
+
+        // Create the k-mer index
+        let index_kind = match index_kind {
+            Some(index_kind) => {
+                index_kind.check(kmer_len, kmers.len())?;
+                index_kind
+            }
+            None => KmerIndexKind::choose(kmer_len, kmers.len()),
+        };
+        let kmer_index = KmerIndex::from_sorted_kmers(kmers, rle_indices, kmer_len, index_kind);
+        debug!(
+            "{:?} k-mer index of {} k-mers uses {} bytes",
+            index_kind,
+            kmer_index.num_kmers(),
+            kmer_index.size_in_bytes()
+        );
+
+        Ok(Database {
+            compression_level: None,
+            consts: BinomialConsts::new(),
+            files: files.into_boxed_slice(),
+            kmer_index,
+            kmer_len,
+            p_values,
+            rles,
+            syncmer_info,
+            tax_ids: tax_ids.into_boxed_slice(),
+            taxonomy: None,
+        })
     }
 
     pub fn compute_loookup_table(&self, n_fixed: u64) -> Vec<BigExpFloat> {
@@ -189,35 +1123,37 @@ impl Database {
         }
         info!("performing lossy compresseion...");
 
-        let total_set_bits = self
-            .rles
-            .par_iter()
-            .map(|rle| {
-                rle.block_iters()
+        // Rows may be shared by multiple k-mers, so weight each row by its number of uses
+        let rle_uses = self.kmer_index.count_rle_uses(self.rles.len());
+        let total_set_bits = (0..self.rles.len())
+            .into_par_iter()
+            .map(|rle_index| {
+                self.rles
+                    .block_iters(rle_index)
                     .map(|block_iter| match block_iter {
                         BlockIter::Range((start_i, end_i)) => end_i - start_i,
                         BlockIter::BitIter((bit_iter, _start_i)) => bit_iter.count(),
                     })
                     .sum::<usize>()
+                    * rle_uses[rle_index] as usize
             })
             .sum::<usize>();
         debug!("total set bits before compression {}", total_set_bits);
 
-        let total_blocks = self
-            .rles
-            .par_iter()
-            .map(|rle| rle.num_of_blocks())
-            .sum::<usize>();
-        debug!("total blocks before compression {}", total_blocks);
+        debug!(
+            "total blocks before compression {}",
+            self.rles.num_of_blocks()
+        );
+
+        let compressed_rles = (0..self.rles.len()).into_par_iter().map(|rle_index| {
+            let current_blocks = self.rles.get_raw_blocks(rle_index);
 
-        self.rles.par_iter_mut().for_each(|current_rle| {
             // variable to hold the new lossy compressed blocks as u16s
-            let mut compressed_blocks = Vec::with_capacity(current_rle.num_of_blocks());
+            let mut compressed_blocks = Vec::with_capacity(current_blocks.len());
 
             // peekable iterator over the current runs
-            let mut block_iter = current_rle
-                .get_raw_blocks()
-                .into_iter()
+            let mut block_iter = current_blocks
+                .iter()
                 .map(|block| Block::from_u16(*block))
                 .peekable();
 
@@ -331,63 +1267,55 @@ impl Database {
             } // end while
 
             // At this point, we have created the variable `compressed_blocks` with the lossy compressed blocks in it
-            // We can simply assign the new `compressed_blocks` to the original rle
+            // We can simply create a new rle from the `compressed_blocks`
             let raw_compressed_blocks = compressed_blocks
                 .into_iter()
                 .map(|block| block.to_u16())
                 .collect::<Box<[u16]>>();
-            *current_rle = RunLengthEncoding::from(raw_compressed_blocks);
-        }); // end par_iter_mut/for_each
+            RunLengthEncoding::from(raw_compressed_blocks)
+        }); // end into_par_iter/map
+
+        // Replace the original rles with the lossy compressed ones
+        self.rles =
+            FlatRunLengthEncodings::from_rles(compressed_rles.collect::<Vec<RunLengthEncoding>>());
 
         // self.rles has now been mutably updated with the requested lossy compression
-        let total_set_bits = self
-            .rles
-            .par_iter()
-            .map(|rle| {
-                rle.block_iters()
+        let total_set_bits = (0..self.rles.len())
+            .into_par_iter()
+            .map(|rle_index| {
+                self.rles
+                    .block_iters(rle_index)
                     .map(|block_iter| match block_iter {
                         BlockIter::Range((start_i, end_i)) => end_i - start_i,
                         BlockIter::BitIter((bit_iter, _start_i)) => bit_iter.count(),
                     })
                     .sum::<usize>()
+                    * rle_uses[rle_index] as usize
             })
             .sum::<usize>();
         debug!("total set bits after compression {}", total_set_bits);
 
-        let total_blocks = self
-            .rles
-            .par_iter()
-            .map(|rle| rle.num_of_blocks())
-            .sum::<usize>();
-        debug!("total blocks after compression {}", total_blocks);
+        debug!(
+            "total blocks after compression {}",
+            self.rles.num_of_blocks()
+        );
+
+        // Lossy compression can make previously distinct rows identical
+        self.deduplicate_rows();
 
         // Recompute the p_values after
         info!("recomputing p-values for all targets");
         self.recompute_p_values();
+
+        self.compression_level = Some(compression_level);
     }
 
     fn recompute_p_values(&mut self) -> () {
         let total_kmers = compute_total_kmers(self.kmer_len, self.syncmer_info);
         info!("{} total possible k-mers", total_kmers);
 
-        let mut file2kmer_num = vec![0_usize; self.num_files()];
-
-        self.rles.iter().for_each(|rle| {
-            rle.block_iters().for_each(|block_iter| match block_iter {
-                BlockIter::BitIter((bit_iter, start_i)) => {
-                    bit_iter.map(|i| i + start_i).for_each(|i| {
-                        file2kmer_num[i] += 1;
-                    });
-                }
-                BlockIter::Range((start_i, end_i)) => {
-                    file2kmer_num[start_i..end_i].iter_mut().for_each(|count| {
-                        *count += 1;
-                    });
-                }
-            });
-        });
-
-        let p_values = file2kmer_num
+        let p_values = self
+            .count_kmers_per_file()
             .into_par_iter()
             .map(|kmer_num| kmer_num as f64 / total_kmers as f64)
             .collect::<Box<[f64]>>();
@@ -395,93 +1323,279 @@ impl Database {
         self.p_values = p_values;
     }
 
-    pub fn classify(
-        &self,
-        read: &[u8],
-        cutoff_threshold: BigExpFloat,
-        n_fixed: usize,
-        lookup_table: &Vec<BigExpFloat>,
-    ) -> (Option<(&str, usize)>, (f64, f64)) {
-        // Create a vector to store the hits
-        let mut num_hits = vec![0_usize; self.num_files()];
+    // Counts the number of k-mers in each file
+    pub fn count_kmers_per_file(&self) -> Vec<usize> {
+        let mut file2kmer_num = vec![0_usize; self.num_files()];
 
-        // Create a variable to track the total number of kmers queried
-        let mut n_total = 0_usize;
+        // Each row counts once for every k-mer that uses it
+        let rle_uses = self.kmer_index.count_rle_uses(self.rles.len());
+        (0..self.rles.len()).for_each(|rle_index| {
+            let uses = rle_uses[rle_index] as usize;
+            self.rles
+                .block_iters(rle_index)
+                .for_each(|block_iter| match block_iter {
+                    BlockIter::BitIter((bit_iter, start_i)) => {
+                        bit_iter.map(|i| i + start_i).for_each(|i| {
+                            file2kmer_num[i] += uses;
+                        });
+                    }
+                    BlockIter::Range((start_i, end_i)) => {
+                        file2kmer_num[start_i..end_i].iter_mut().for_each(|count| {
+                            *count += uses;
+                        });
+                    }
+                });
+        });
 
-        let hit_lookup_start = Instant::now();
-        // For each kmer in the read
-        for kmer in
-            CanonicalKmerIter::from(read, self.kmer_len, self.syncmer_info).map(|k| k as u32)
-        {
-            // Lookup the RLE and decompress
-            if let Some(rle_index) = self.kmer_to_rle_index.get(&kmer) {
-                self.rles[*rle_index as usize].block_iters().for_each(
-                    |block_iter| match block_iter {
+        file2kmer_num
+    }
+
+    // The row of each k-mer of the mates of a read, or None if the database does not contain the
+    // k-mer. The k-mers of every mate are pooled together.
+    pub(crate) fn kmer_rows<'a>(
+        &'a self,
+        mates: &'a [&'a [u8]],
+    ) -> impl Iterator<Item = Option<usize>> + 'a {
+        mates.iter().flat_map(move |mate| {
+            CanonicalKmerIter::from(mate, self.kmer_len, self.syncmer_info)
+                .map(move |kmer| self.kmer_index.get(kmer as u64))
+        })
+    }
+
+    // Adds a hit to every file in each row into a zeroed buffer, adding the index of each file
+    // with a first hit to `touched`, so that only those files need to be reset afterwards.
+    // Returns the total number of k-mers queried, including those without a row.
+    pub(crate) fn add_hits(
+        &self,
+        rows: impl IntoIterator<Item = Option<usize>>,
+        num_hits: &mut [usize],
+        touched: &mut Vec<usize>,
+    ) -> usize {
+        let mut n_total = 0_usize;
+        let mut add_hit = |i: usize| {
+            if num_hits[i] == 0 {
+                touched.push(i);
+            }
+            num_hits[i] += 1;
+        };
+        for row in rows {
+            if let Some(rle_index) = row {
+                self.rles
+                    .block_iters(rle_index)
+                    .for_each(|block_iter| match block_iter {
                         BlockIter::BitIter((bit_iter, start_i)) => {
-                            bit_iter.map(|i| i + start_i).for_each(|i| {
-                                num_hits[i] += 1;
-                            });
+                            bit_iter.map(|i| i + start_i).for_each(&mut add_hit);
                         }
                         BlockIter::Range((start_i, end_i)) => {
-                            num_hits[start_i..end_i].iter_mut().for_each(|count| {
-                                *count += 1;
-                            });
+                            (start_i..end_i).for_each(&mut add_hit);
                         }
-                    },
-                );
+                    });
             }
-            // Increment the total number of queries
             n_total += 1;
         }
-        let hit_lookup_time = hit_lookup_start.elapsed().as_secs_f64();
+        n_total
+    }
+
+    // Looks up the probability of the hits of a file, if it is likely to be significant
+    pub(crate) fn file_probability(
+        &self,
+        index: usize,
+        x_observed: usize,
+        n_total: usize,
+        n_fixed: usize,
+        lookup_table: &[BigExpFloat],
+    ) -> Option<BigExpFloat> {
+        // This check tries to save runtime in practice
+        // Only find the probability if the p-value is going to be < 0.5
+        if x_observed as f64 > (n_total as f64 * self.p_values[index]) {
+            let x = scaled_hits(x_observed, n_total, n_fixed);
+
+            //Lookup the probability
+            let lookup_position = (index * (n_fixed + 1)) + x;
+            Some(lookup_table[lookup_position])
+        } else {
+            // The p-value will be greater than 0.5 (insignificant)
+            // Don't compute or lookup
+            None
+        }
+    }
+
+    // The (at most) `num_candidates` files with the lowest probabilities, from lowest to highest,
+    // and the total number of k-mers queried. Only files whose probability is likely to be below
+    // 0.5 are candidates.
+    pub fn top_candidates(
+        &self,
+        mates: &[&[u8]],
+        n_fixed: usize,
+        lookup_table: &[BigExpFloat],
+        num_candidates: usize,
+    ) -> (Vec<Candidate>, usize) {
+        // The cutoff only decides the classification, which is not needed
+        let classifier =
+            Classifier::with_lookup_table(self, BigExpFloat::zero(), n_fixed, lookup_table);
+        let mut state = classifier.new_state();
+        let n_total = classifier.classify(&mut state, mates).n_total;
+        (classifier.top_candidates(&state, num_candidates), n_total)
+    }
+
+    pub fn classify(
+        &self,
+        read: &[u8],
+        cutoff_threshold: BigExpFloat,
+        n_fixed: usize,
+        lookup_table: &Vec<BigExpFloat>,
+    ) -> (Option<(&str, usize)>, (f64, f64)) {
+        self.classify_mates(&[read], cutoff_threshold, n_fixed, lookup_table)
+    }
 
-        // Classify the hits
+    // Classifies the mates of a read (e.g. R1 and R2 of a paired-end read) together, pooling
+    // their k-mer hits into a single classification. Classifying many reads with a Classifier
+    // reuses the buffers of each read instead.
+    pub fn classify_mates(
+        &self,
+        mates: &[&[u8]],
+        cutoff_threshold: BigExpFloat,
+        n_fixed: usize,
+        lookup_table: &[BigExpFloat],
+    ) -> (Option<(&str, usize)>, (f64, f64)) {
+        let classifier =
+            Classifier::with_lookup_table(self, cutoff_threshold, n_fixed, lookup_table);
+        let classification = classifier.classify(&mut classifier.new_state(), mates);
+        (
+            classification.file(self).zip(classification.tax_id),
+            (
+                classification.hit_lookup_time,
+                classification.prob_calc_time,
+            ),
+        )
+    }
+
+    // The file with the lowest probability, if it is below the cutoff
+    pub(crate) fn best_file(
+        &self,
+        probabilities: impl Iterator<Item = (usize, BigExpFloat)>,
+        cutoff_threshold: BigExpFloat,
+    ) -> Option<usize> {
         // Would do this using min_by_key but the Ord trait is difficult to implement for float types
-        let prob_calc_start = Instant::now();
-        let lowest_option = num_hits
+        probabilities
+            .min_by(|a, b| a.1.partial_cmp(&b.1).expect("NaN appeared in lookup table"))
+            .filter(|(_index, lowest_prob)| *lowest_prob < cutoff_threshold)
+            .map(|(index, _lowest_prob)| index)
+    }
+
+    // Classifies the read as the lowest common ancestor of every file whose probability is below
+    // the cutoff. If `max_log_ratio` is provided, only files whose probability is within that
+    // many orders of magnitude (log10) of the lowest probability are included.
+    // Returns the indices of the included files and their lowest common ancestor.
+    pub fn classify_lca(
+        &self,
+        read: &[u8],
+        cutoff_threshold: BigExpFloat,
+        n_fixed: usize,
+        lookup_table: &[BigExpFloat],
+        max_log_ratio: Option<f64>,
+        taxonomy: &Taxonomy,
+    ) -> LcaClassification {
+        self.classify_lca_mates(
+            &[read],
+            cutoff_threshold,
+            n_fixed,
+            lookup_table,
+            max_log_ratio,
+            taxonomy,
+        )
+    }
+
+    // Like classify_lca, but pools the k-mer hits of the mates of a read
+    pub fn classify_lca_mates(
+        &self,
+        mates: &[&[u8]],
+        cutoff_threshold: BigExpFloat,
+        n_fixed: usize,
+        lookup_table: &[BigExpFloat],
+        max_log_ratio: Option<f64>,
+        taxonomy: &Taxonomy,
+    ) -> LcaClassification {
+        let mut classifier =
+            Classifier::with_lookup_table(self, cutoff_threshold, n_fixed, lookup_table);
+        classifier.set_lca(taxonomy, max_log_ratio);
+        let classification = classifier.classify(&mut classifier.new_state(), mates);
+        (
+            classification
+                .tax_id
+                .map(|tax_id| (classification.file_indices, tax_id)),
+            (
+                classification.hit_lookup_time,
+                classification.prob_calc_time,
+            ),
+        )
+    }
+
+    // The files whose probability is below the cutoff (and within `max_log_ratio` of the lowest)
+    // and their lowest common ancestor
+    pub(crate) fn lca_files(
+        &self,
+        probabilities: impl Iterator<Item = (usize, BigExpFloat)>,
+        cutoff_threshold: BigExpFloat,
+        max_log_ratio: Option<f64>,
+        taxonomy: &Taxonomy,
+    ) -> Option<(Vec<usize>, usize)> {
+        let significant = probabilities
+            .filter(|(_index, prob)| *prob < cutoff_threshold)
+            .collect::<Vec<(usize, BigExpFloat)>>();
+        let lowest_log_prob = significant
             .iter()
-            .zip(self.p_values.iter())
-            .enumerate()
-            .filter_map(|(index, (x_observed, p))| {
-                // This check tries to save runtime in practice
-                // Only find the probability if the p-value is going to be < 0.5
-                if *x_observed as f64 > (n_total as f64 * p) {
-                    let x = if n_total > n_fixed {
-                        // If the total number of queries is > n_fixed, adjust x based on the expected value
-                        (*x_observed as f64 * n_fixed as f64 / n_total as f64).round() as usize
-                    } else {
-                        // Otherwise, just use x_observed
-                        *x_observed
-                    };
-
-                    //Lookup the probability
-                    let lookup_position = (index * (n_fixed + 1)) + x;
-                    Some((index, lookup_table[lookup_position]))
-                } else {
-                    // The p-value will be greater than 0.5 (insignificant)
-                    // Don't compute or lookup
-                    None
-                }
+            .map(|(_index, prob)| prob.log10())
+            .fold(f64::INFINITY, f64::min);
+        let file_indices = significant
+            .into_iter()
+            .filter(|(_index, prob)| match max_log_ratio {
+                Some(max_log_ratio) => prob.log10() - lowest_log_prob <= max_log_ratio,
+                None => true,
             })
-            .min_by(|a, b| a.1.partial_cmp(&b.1).expect("NaN appeared in lookup table"));
-        let prob_calc_time = prob_calc_start.elapsed().as_secs_f64();
+            .map(|(index, _prob)| index)
+            .collect::<Vec<usize>>();
+        let tax_ids = file_indices
+            .iter()
+            .map(|index| self.tax_ids[*index])
+            .collect::<Vec<usize>>();
+        taxonomy
+            .lca(&tax_ids)
+            .map(|lca_tax_id| (file_indices, lca_tax_id))
+    }
+}
 
-        // Handle the return values
-        match lowest_option {
-            Some((lowest_prob_index, lowest_prob)) => {
-                if lowest_prob < cutoff_threshold {
-                    (
-                        Some((
-                            &*self.files[lowest_prob_index],
-                            self.tax_ids[lowest_prob_index],
-                        )),
-                        (hit_lookup_time, prob_calc_time),
-                    )
-                } else {
-                    (None, (hit_lookup_time, prob_calc_time))
-                }
-            }
-            None => (None, (hit_lookup_time, prob_calc_time)),
-        }
+// If the total number of queries is > n_fixed, adjust the hits based on the expected value.
+// Otherwise, just use the observed hits.
+pub(crate) fn scaled_hits(x_observed: usize, n_total: usize, n_fixed: usize) -> usize {
+    if n_total > n_fixed {
+        (x_observed as f64 * n_fixed as f64 / n_total as f64).round() as usize
+    } else {
+        x_observed
     }
 }
+
+// The (at most) `num_candidates` files with the lowest probabilities, from lowest to highest
+pub(crate) fn lowest_candidates(
+    probabilities: impl Iterator<Item = (usize, BigExpFloat)>,
+    num_hits: &[usize],
+    n_total: usize,
+    n_fixed: usize,
+    num_candidates: usize,
+) -> Vec<Candidate> {
+    let mut candidates = probabilities
+        .map(|(index, p_value)| Candidate {
+            file_index: index,
+            hits: num_hits[index],
+            p_value,
+            scaled_hits: scaled_hits(num_hits[index], n_total, n_fixed),
+        })
+        .collect::<Vec<Candidate>>();
+    candidates.sort_by(|a, b| {
+        a.p_value
+            .partial_cmp(&b.p_value)
+            .expect("NaN appeared in lookup table")
+    });
+    candidates.truncate(num_candidates);
+    candidates
+}
@@ -0,0 +1,53 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter, Result};
+
+/// Every binary file written by skim starts with these bytes
+pub const MAGIC: [u8; 4] = *b"SKiM";
+
+/// Must be incremented whenever the on-disk layout of any file kind changes
+pub const FORMAT_VERSION: u32 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileKind {
+    Database,
+    PairwiseDistances,
+}
+
+impl Display for FileKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            FileKind::Database => write!(f, "database (.db/.cdb)"),
+            FileKind::PairwiseDistances => write!(f, "pairwise distances (.pd)"),
+        }
+    }
+}
+
+/// Describes the parameters a file was built with.
+/// On disk, a file is laid out as:
+///     MAGIC | FORMAT_VERSION (u32, little endian) | header | payload | CRC32 of payload (u32, little endian)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileHeader {
+    pub compression_level: Option<usize>,
+    pub kind: FileKind,
+    pub kmer_len: usize,
+    pub num_files: usize,
+    pub syncmer_info: Option<(usize, usize)>,
+}
+
+impl Display for FileHeader {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{} file, k-mer length: {}", self.kind, self.kmer_len)?;
+        match self.syncmer_info {
+            Some((smer_len, syncmer_offset)) => write!(
+                f,
+                ", s-mer length: {}, syncmer offset: {}",
+                smer_len, syncmer_offset
+            )?,
+            None => write!(f, ", syncmers disabled")?,
+        }
+        if let Some(compression_level) = self.compression_level {
+            write!(f, ", lossy compression level: {}", compression_level)?;
+        }
+        write!(f, ", number of files: {}", self.num_files)
+    }
+}
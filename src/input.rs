@@ -0,0 +1,275 @@
+use bio::io::{fasta, fastq};
+use bzip2::bufread::MultiBzDecoder;
+use flate2::bufread::MultiGzDecoder;
+use lzma_rust2::XzReader;
+use ruzstd::decoding::{FrameDecoder, StreamingDecoder};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Cursor, Read};
+use std::path::Path;
+
+use crate::io::is_stdio;
+
+/// A buffered reader over the decompressed contents of an input file
+pub type InputReader = BufReader<Box<dyn Read + Send>>;
+
+/// The compression of an input file, detected from its first bytes.
+/// bgzip files are gzip files with several members, so they are read as gzip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Bzip2,
+    Gzip,
+    None,
+    Xz,
+    Zstd,
+}
+
+// The number of bytes needed to detect every compression
+const MAGIC_LEN: usize = 6;
+
+impl Compression {
+    pub fn detect(magic: &[u8]) -> Self {
+        match magic {
+            [0x1f, 0x8b, ..] => Compression::Gzip,
+            [b'B', b'Z', b'h', ..] => Compression::Bzip2,
+            [0xfd, b'7', b'z', b'X', b'Z', 0x00, ..] => Compression::Xz,
+            [0x28, 0xb5, 0x2f, 0xfd, ..] => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+}
+
+/// The format of a sequence file, detected from its first non-whitespace byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SequenceFormat {
+    Fasta,
+    Fastq,
+}
+
+impl SequenceFormat {
+    // An empty file is read as a FASTA file without records
+    pub fn detect(start: &[u8]) -> Result<Self, String> {
+        match start.iter().find(|byte| !byte.is_ascii_whitespace()) {
+            Some(b'>') | None => Ok(SequenceFormat::Fasta),
+            Some(b'@') => Ok(SequenceFormat::Fastq),
+            Some(byte) => Err(format!(
+                "expected a FASTA ('>') or FASTQ ('@') record, but found '{}'",
+                byte.escape_ascii()
+            )),
+        }
+    }
+}
+
+// Reads every frame of a zstd stream, since a single decoder stops after the first frame
+struct ZstdReader<R: BufRead> {
+    decoder: Option<StreamingDecoder<R, FrameDecoder>>,
+}
+
+impl<R: BufRead> ZstdReader<R> {
+    fn new(reader: R) -> io::Result<Self> {
+        let decoder = StreamingDecoder::new(reader).map_err(io::Error::other)?;
+        Ok(ZstdReader {
+            decoder: Some(decoder),
+        })
+    }
+}
+
+impl<R: BufRead> Read for ZstdReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let decoder = match self.decoder.as_mut() {
+                Some(decoder) => decoder,
+                None => return Ok(0),
+            };
+            let num_read = decoder.read(buf)?;
+            if num_read > 0 || buf.is_empty() {
+                return Ok(num_read);
+            }
+
+            // The frame is done, so start the next one if there is one
+            let (mut reader, frame_decoder) = self.decoder.take().unwrap().into_parts();
+            if !reader.fill_buf()?.is_empty() {
+                self.decoder = Some(
+                    StreamingDecoder::new_with_decoder(reader, frame_decoder)
+                        .map_err(io::Error::other)?,
+                );
+            }
+        }
+    }
+}
+
+// Reads the first `len` bytes (or every byte, if there are fewer), since a single read of a pipe
+// may return fewer bytes than are needed to detect the compression
+fn read_start(reader: &mut impl Read, len: usize) -> io::Result<Vec<u8>> {
+    let mut start = Vec::with_capacity(len);
+    reader.take(len as u64).read_to_end(&mut start)?;
+    Ok(start)
+}
+
+/// Opens a file, or stdin if the path is "-", transparently decompressing it if it is compressed
+/// with gzip, bgzip, bzip2, xz or zstd. Named pipes are read like files, without seeking.
+pub fn open_decompressed(path: &Path) -> Result<InputReader, String> {
+    let mut source: Box<dyn Read + Send> = if is_stdio(path) {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(path).map_err(|e| format!("could not open {:?}: {}", path, e))?)
+    };
+    let magic = read_start(&mut source, MAGIC_LEN)
+        .map_err(|e| format!("could not read {:?}: {}", path, e))?;
+    let reader = BufReader::new(Cursor::new(magic.clone()).chain(source));
+    let decompressed: Box<dyn Read + Send> = match Compression::detect(&magic) {
+        Compression::Bzip2 => Box::new(MultiBzDecoder::new(reader)),
+        Compression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+        Compression::None => Box::new(reader),
+        Compression::Xz => Box::new(XzReader::new(reader, true)),
+        Compression::Zstd => Box::new(
+            ZstdReader::new(reader)
+                .map_err(|e| format!("could not read zstd file {:?}: {}", path, e))?,
+        ),
+    };
+    Ok(BufReader::new(decompressed))
+}
+
+/// A record of either a FASTA or a FASTQ file
+#[derive(Debug, Clone)]
+pub enum SequenceRecord {
+    Fasta(fasta::Record),
+    Fastq(fastq::Record),
+}
+
+impl SequenceRecord {
+    pub fn id(&self) -> &str {
+        match self {
+            SequenceRecord::Fasta(record) => record.id(),
+            SequenceRecord::Fastq(record) => record.id(),
+        }
+    }
+
+    pub fn desc(&self) -> Option<&str> {
+        match self {
+            SequenceRecord::Fasta(record) => record.desc(),
+            SequenceRecord::Fastq(record) => record.desc(),
+        }
+    }
+
+    pub fn seq(&self) -> &[u8] {
+        match self {
+            SequenceRecord::Fasta(record) => record.seq(),
+            SequenceRecord::Fastq(record) => record.seq(),
+        }
+    }
+
+    // The quality scores, which only FASTQ records have
+    pub fn qual(&self) -> Option<&[u8]> {
+        match self {
+            SequenceRecord::Fasta(_) => None,
+            SequenceRecord::Fastq(record) => Some(record.qual()),
+        }
+    }
+}
+
+/// The records of a (possibly compressed) FASTA or FASTQ file
+pub enum SequenceRecords {
+    Fasta(fasta::Records<InputReader>),
+    Fastq(fastq::Records<InputReader>),
+}
+
+impl SequenceRecords {
+    // Opens the file, detecting its compression and whether it is FASTA or FASTQ
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let mut reader = open_decompressed(path)?;
+        let start = reader
+            .fill_buf()
+            .map_err(|e| format!("could not read {:?}: {}", path, e))?;
+        let format = SequenceFormat::detect(start).map_err(|e| format!("{:?}: {}", path, e))?;
+        Ok(match format {
+            SequenceFormat::Fasta => {
+                SequenceRecords::Fasta(fasta::Reader::from_bufread(reader).records())
+            }
+            SequenceFormat::Fastq => {
+                SequenceRecords::Fastq(fastq::Reader::from_bufread(reader).records())
+            }
+        })
+    }
+
+    pub fn format(&self) -> SequenceFormat {
+        match self {
+            SequenceRecords::Fasta(_) => SequenceFormat::Fasta,
+            SequenceRecords::Fastq(_) => SequenceFormat::Fastq,
+        }
+    }
+}
+
+impl Iterator for SequenceRecords {
+    type Item = Result<SequenceRecord, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            SequenceRecords::Fasta(records) => records
+                .next()
+                .map(|record| record.map(SequenceRecord::Fasta).map_err(|e| e.to_string())),
+            SequenceRecords::Fastq(records) => records
+                .next()
+                .map(|record| record.map(SequenceRecord::Fastq).map_err(|e| e.to_string())),
+        }
+    }
+}
+
+/// The id shared by the mates of a paired-end read, without a trailing "/1" or "/2"
+pub fn mate_id(id: &str) -> &str {
+    id.strip_suffix("/1")
+        .or_else(|| id.strip_suffix("/2"))
+        .unwrap_or(id)
+}
+
+/// The pairs of mates of paired-end reads, either from two files (R1 and R2) or from a single
+/// interleaved file in which each R1 record is followed by its R2 record
+pub enum PairedRecords {
+    Files(SequenceRecords, SequenceRecords),
+    Interleaved(SequenceRecords),
+}
+
+impl PairedRecords {
+    pub fn from_files(path_1: &Path, path_2: &Path) -> Result<Self, String> {
+        Ok(PairedRecords::Files(
+            SequenceRecords::from_file(path_1)?,
+            SequenceRecords::from_file(path_2)?,
+        ))
+    }
+
+    pub fn from_interleaved_file(path: &Path) -> Result<Self, String> {
+        Ok(PairedRecords::Interleaved(SequenceRecords::from_file(
+            path,
+        )?))
+    }
+}
+
+impl Iterator for PairedRecords {
+    type Item = Result<(SequenceRecord, SequenceRecord), String>;
+
+    // Errors if the ids of the mates do not match or a read has no mate, since every pair after
+    // it would be out of sync
+    fn next(&mut self) -> Option<Self::Item> {
+        let (mate_1, mate_2) = match self {
+            PairedRecords::Files(records_1, records_2) => (records_1.next(), records_2.next()),
+            PairedRecords::Interleaved(records) => (records.next(), records.next()),
+        };
+        match (mate_1, mate_2) {
+            (None, None) => None,
+            (Some(Err(e)), _) | (_, Some(Err(e))) => Some(Err(e)),
+            (Some(Ok(mate)), None) | (None, Some(Ok(mate))) => {
+                Some(Err(format!("read {} has no mate", mate.id())))
+            }
+            (Some(Ok(mate_1)), Some(Ok(mate_2))) => {
+                if mate_id(mate_1.id()) == mate_id(mate_2.id()) {
+                    Some(Ok((mate_1, mate_2)))
+                } else {
+                    Some(Err(format!(
+                        "the ids of mates {} and {} do not match",
+                        mate_1.id(),
+                        mate_2.id()
+                    )))
+                }
+            }
+        }
+    }
+}
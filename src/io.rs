@@ -1,16 +1,37 @@
 use bio::io::fasta;
+use crc32fast::Hasher;
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use std::any::type_name;
-use std::fs::File;
-use std::io::BufWriter;
-use std::io::{BufRead, BufReader};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::io::{BufWriter, Write};
 use std::path::{Path, PathBuf};
-use tracing::{info, warn};
+use std::process;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::header::{FileHeader, FileKind, FORMAT_VERSION, MAGIC};
+
+// Whether a path given on the command line is "-", which means stdin or stdout
+pub fn is_stdio(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+// Whether the path is an existing named pipe or device (e.g. /dev/stdout), which is written to as is
+fn is_special_file(path: &Path) -> bool {
+    fs::metadata(path)
+        .map(|metadata| !metadata.is_file() && !metadata.is_dir())
+        .unwrap_or(false)
+}
 
 pub fn create_output_file(path: &Path, extension: &str) -> File {
     let file_path = if path.is_dir() {
         path.join(extension)
+    } else if is_special_file(path) {
+        path.to_path_buf()
     } else {
         path.with_extension(extension)
     };
@@ -20,6 +41,44 @@ pub fn create_output_file(path: &Path, extension: &str) -> File {
     File::create(file_path).expect("could not create output file")
 }
 
+// Creates the output file, or writes to stdout if the path is "-"
+pub fn create_output_writer(path: &Path, extension: &str) -> Box<dyn Write + Send> {
+    if is_stdio(path) {
+        info!("writing output to stdout");
+        Box::new(io::stdout())
+    } else {
+        Box::new(create_output_file(path, extension))
+    }
+}
+
+// Whether output to the path should be flushed regularly, since another process may be reading it
+// as it is written
+pub fn is_stream(path: &Path) -> bool {
+    is_stdio(path) || is_special_file(path)
+}
+
+// Exits once the output cannot be written. The reader of stdout or a named pipe stopping early
+// (e.g. `| head`) is not an error, so the exit is clean.
+pub fn exit_on_write_error(error: io::Error) -> ! {
+    if error.kind() == io::ErrorKind::BrokenPipe {
+        info!("the output was closed, so no more reads are written");
+        process::exit(0);
+    }
+    error!("could not write to output file: {}", error);
+    process::exit(1);
+}
+
+// Flushes the writer every interval, until the sender of `stop` is dropped
+pub fn flush_periodically<W: Write>(writer: &Mutex<W>, interval: Duration, stop: Receiver<()>) {
+    while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(interval) {
+        writer
+            .lock()
+            .unwrap()
+            .flush()
+            .unwrap_or_else(|e| exit_on_write_error(e));
+    }
+}
+
 pub fn split_string_to_taxid(line: String) -> Result<(String, usize), String> {
     let mut column_iter = line.split("\t");
     let file = column_iter.next().unwrap().to_string();
@@ -77,22 +136,221 @@ pub fn load_string2taxid(string2taxid: &Path) -> Vec<(String, usize)> {
 // Takes a file (already opened) as an input
 // All binaries open files at the start of execution, if needed.
 // All such binaries should error early in execution if an improper path is provided.
-pub fn dump_data_to_file<T: Serialize>(data: &T, file: File) -> bincode::Result<()> {
-    let buf_writer = BufWriter::new(file);
-    bincode::serialize_into(buf_writer, data)
+pub fn dump_data_to_file<T: Serialize>(
+    header: &FileHeader,
+    data: &T,
+    file: File,
+) -> bincode::Result<()> {
+    let mut buf_writer = BufWriter::new(file);
+    let header_len = write_header_to(&mut buf_writer, header)?;
+
+    // Write the payload while computing its checksum, then write the checksum itself
+    let mut checksum_writer = ChecksumWriter::new(&mut buf_writer, header_len);
+    bincode::serialize_into(&mut checksum_writer, data)?;
+    let checksum = checksum_writer.finalize();
+    buf_writer.write_all(&checksum.to_le_bytes())?;
+
+    buf_writer.flush()?;
+    Ok(())
+}
+
+// Writes the magic bytes, format version, and header so the file can be identified on load
+// Returns the number of bytes written
+pub(crate) fn write_header_to<W: Write>(
+    writer: &mut W,
+    header: &FileHeader,
+) -> bincode::Result<u64> {
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    bincode::serialize_into(&mut *writer, header)?;
+    header_len(header)
+}
+
+// The number of bytes taken up by the magic bytes, format version, and header
+pub(crate) fn header_len(header: &FileHeader) -> bincode::Result<u64> {
+    Ok((MAGIC.len() + 4) as u64 + bincode::serialized_size(header)?)
+}
+
+// Reads the magic bytes, format version, and header from the start of a file
+// Errors if the file is not a skim file, has a different format version, or is of the wrong kind
+pub(crate) fn read_header_from<R: Read>(
+    reader: &mut R,
+    path: &Path,
+    kind: Option<FileKind>,
+) -> Result<FileHeader, String> {
+    let mut magic = [0_u8; 4];
+    let mut version = [0_u8; 4];
+    if reader.read_exact(&mut magic).is_err() || magic != MAGIC {
+        return Err(format!(
+            "{:?} is not a skim file (it may have been written by a version of skim before file headers were introduced)",
+            path
+        ));
+    }
+    reader
+        .read_exact(&mut version)
+        .map_err(|e| format!("could not read the format version of {:?}: {}", path, e))?;
+
+    let version = u32::from_le_bytes(version);
+    if version != FORMAT_VERSION {
+        return Err(format!(
+            "{:?} was written with format version {} but this version of skim reads format version {}. please rebuild the file",
+            path, version, FORMAT_VERSION
+        ));
+    }
+
+    let header: FileHeader = bincode::deserialize_from(reader)
+        .map_err(|e| format!("could not read the header of {:?}: {}", path, e))?;
+
+    // Make sure the file is of the expected kind before anything tries to deserialize it
+    match kind {
+        Some(kind) if kind != header.kind => Err(format!(
+            "{:?} is a {} file but a {} file was expected",
+            path, header.kind, kind
+        )),
+        _ => Ok(header),
+    }
+}
+
+// Compares the checksum stored at the end of a file against the computed checksum
+pub(crate) fn verify_checksum<R: Read>(
+    reader: &mut R,
+    computed_checksum: u32,
+    path: &Path,
+) -> Result<(), String> {
+    let mut stored_checksum = [0_u8; 4];
+    reader
+        .read_exact(&mut stored_checksum)
+        .map_err(|e| format!("could not read the checksum of {:?}: {}", path, e))?;
+    if u32::from_le_bytes(stored_checksum) != computed_checksum {
+        Err(format!(
+            "checksum mismatch for {:?}, the file is likely corrupted",
+            path
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+// Takes a path (not opened) as an input
+// Only reads the header, which is useful to check the parameters a file was built with
+pub fn load_header_from_file(path: &Path) -> Result<FileHeader, String> {
+    let mut buf_reader = BufReader::new(
+        File::open(path).map_err(|e| format!("could not open file at {:?}: {}", path, e))?,
+    );
+    read_header_from(&mut buf_reader, path, None)
 }
 
 // Takes a path (not opened) as an input
 // All binaries that need to load data will do so at the start of execution.
 // All such binaries will error here if an improper path is provided.
-pub fn load_data_from_file<T: for<'a> Deserialize<'a>>(path: &Path) -> T {
-    let buf_reader =
-        BufReader::new(File::open(path).expect(&*format!("could not open file at {:?}", path)));
-    bincode::deserialize_from(buf_reader).expect(&*format!(
-        "failed to deserialize data at {:?} into {}",
-        path,
-        type_name::<T>()
-    ))
+pub fn load_data_from_file<T: for<'a> Deserialize<'a>>(
+    path: &Path,
+    kind: FileKind,
+) -> Result<(FileHeader, T), String> {
+    let mut buf_reader = BufReader::new(
+        File::open(path).map_err(|e| format!("could not open file at {:?}: {}", path, e))?,
+    );
+    let header = read_header_from(&mut buf_reader, path, Some(kind))?;
+
+    // Read the payload while computing its checksum
+    let mut checksum_reader = ChecksumReader::new(&mut buf_reader, 0);
+    let data = bincode::deserialize_from(&mut checksum_reader).map_err(|e| {
+        format!(
+            "failed to deserialize data at {:?} into {}: {}",
+            path,
+            type_name::<T>(),
+            e
+        )
+    })?;
+    let computed_checksum = checksum_reader.finalize();
+    verify_checksum(&mut buf_reader, computed_checksum, path)?;
+
+    Ok((header, data))
+}
+
+// Computes the checksum of everything written through it and tracks the position in the file
+pub(crate) struct ChecksumWriter<W: Write> {
+    hasher: Hasher,
+    inner: W,
+    position: u64,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    pub(crate) fn new(inner: W, position: u64) -> Self {
+        ChecksumWriter {
+            hasher: Hasher::new(),
+            inner,
+            position,
+        }
+    }
+
+    pub(crate) fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub(crate) fn finalize(self) -> u32 {
+        self.hasher.finalize()
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Computes the checksum of everything read through it and tracks the position in the file
+pub(crate) struct ChecksumReader<R: Read> {
+    file_len: Option<u64>,
+    hasher: Hasher,
+    inner: R,
+    position: u64,
+}
+
+impl<R: Read> ChecksumReader<R> {
+    pub(crate) fn new(inner: R, position: u64) -> Self {
+        ChecksumReader {
+            file_len: None,
+            hasher: Hasher::new(),
+            inner,
+            position,
+        }
+    }
+
+    // Records the length of the file, so arrays that would extend past its end are rejected
+    // before they are allocated
+    pub(crate) fn with_file_len(mut self, file_len: u64) -> Self {
+        self.file_len = Some(file_len);
+        self
+    }
+
+    pub(crate) fn file_len(&self) -> Option<u64> {
+        self.file_len
+    }
+
+    pub(crate) fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub(crate) fn finalize(self) -> u32 {
+        self.hasher.finalize()
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.position += read as u64;
+        Ok(read)
+    }
 }
 
 pub fn save_fasta_record_to_file(record: fasta::Record, file_path: &PathBuf) -> () {
@@ -0,0 +1,407 @@
+use bit_iter::BitIter;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::mem::size_of;
+
+use crate::io::ChecksumWriter;
+use crate::storage::{write_array, ArraySource, Storage};
+
+// Direct-addressed tables are chosen automatically if they take at most this many bytes
+const MAX_AUTO_DIRECT_BYTES: usize = 1 << 26;
+
+// Number of 64-bit words covered by each rank sample of a ranked bit vector
+const WORDS_PER_RANK: usize = 8;
+
+// Marks k-mers that are not in a direct-addressed table
+const ABSENT: u32 = u32::MAX;
+
+// K-mers up to this length fit in 32 bits, so sorted tables of them are stored as u32
+const MAX_NARROW_KMER_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum KmerIndexKind {
+    /// A bit vector over all possible k-mers with rank samples
+    BitVector,
+    /// A table with an entry for every possible k-mer (only for small k-mer lengths)
+    Direct,
+    /// A sorted table of the k-mers, searched using binary search
+    Sorted,
+}
+
+impl KmerIndexKind {
+    // Picks the fastest index that does not use an unreasonable amount of memory
+    pub fn choose(kmer_len: usize, num_kmers: usize) -> Self {
+        let (direct_bytes, bit_vector_bytes, sorted_bytes) = table_bytes(kmer_len, num_kmers);
+        if direct_bytes <= MAX_AUTO_DIRECT_BYTES {
+            KmerIndexKind::Direct
+        } else if bit_vector_bytes < sorted_bytes && num_kmers <= u32::MAX as usize {
+            KmerIndexKind::BitVector
+        } else {
+            KmerIndexKind::Sorted
+        }
+    }
+
+    // Keeps this kind if it still fits (e.g. after k-mers were added), and otherwise picks the
+    // index that would be chosen automatically
+    pub fn check_or_choose(self, kmer_len: usize, num_kmers: usize) -> Self {
+        match self.check(kmer_len, num_kmers) {
+            Ok(()) => self,
+            Err(_) => KmerIndexKind::choose(kmer_len, num_kmers),
+        }
+    }
+
+    // Errors if an index of this kind (e.g. chosen by the user) does not fit: its table over all
+    // possible k-mers is larger than both the sorted table and what would be chosen
+    // automatically, or the ranks of a bit vector would overflow
+    pub fn check(self, kmer_len: usize, num_kmers: usize) -> Result<(), String> {
+        let (direct_bytes, bit_vector_bytes, sorted_bytes) = table_bytes(kmer_len, num_kmers);
+        let max_bytes = sorted_bytes.max(MAX_AUTO_DIRECT_BYTES);
+        let table_bytes = match self {
+            KmerIndexKind::BitVector if num_kmers > u32::MAX as usize => {
+                return Err(format!(
+                    "a bit vector index cannot rank {} k-mers (at most {})",
+                    num_kmers,
+                    u32::MAX
+                ));
+            }
+            KmerIndexKind::BitVector => bit_vector_bytes,
+            KmerIndexKind::Direct => direct_bytes,
+            KmerIndexKind::Sorted => return Ok(()),
+        };
+        if table_bytes > max_bytes {
+            return Err(format!(
+                "a {:?} index for k = {} would take {} bytes, but a sorted index of its {} k-mers only takes {} bytes",
+                self, kmer_len, table_bytes, num_kmers, sorted_bytes
+            ));
+        }
+        Ok(())
+    }
+}
+
+// The bytes taken by a direct-addressed table, a ranked bit vector, and a sorted table of
+// `num_kmers` k-mers. Each also stores an rle index for every k-mer, which is left out here.
+fn table_bytes(kmer_len: usize, num_kmers: usize) -> (usize, usize, usize) {
+    let kmer_space = kmer_space(kmer_len);
+    let direct_bytes = kmer_space.saturating_mul(size_of::<u32>());
+    let bit_vector_bytes = kmer_space.div_ceil(8) + kmer_space.div_ceil(64 * WORDS_PER_RANK) * 4;
+    let sorted_bytes = num_kmers * SortedKmers::kmer_size(kmer_len);
+    (direct_bytes, bit_vector_bytes, sorted_bytes)
+}
+
+/// Maps each k-mer in the database to the index of its run length encoding.
+/// Multiple k-mers may share the same run length encoding.
+pub enum KmerIndex {
+    BitVector {
+        bits: Storage<u64>,
+        ranks: Storage<u32>,
+        rle_indices: Storage<u32>,
+    },
+    Direct {
+        num_kmers: usize,
+        rle_indices: Storage<u32>,
+    },
+    Sorted {
+        kmers: SortedKmers,
+        rle_indices: Storage<u32>,
+    },
+}
+
+/// The k-mers of a sorted table, stored in the narrowest width that fits the k-mer length
+pub enum SortedKmers {
+    Narrow(Storage<u32>),
+    Wide(Storage<u64>),
+}
+
+impl SortedKmers {
+    fn kmer_size(kmer_len: usize) -> usize {
+        if kmer_len <= MAX_NARROW_KMER_LEN {
+            size_of::<u32>()
+        } else {
+            size_of::<u64>()
+        }
+    }
+
+    fn from(kmers: Vec<u64>, kmer_len: usize) -> Self {
+        if kmer_len <= MAX_NARROW_KMER_LEN {
+            SortedKmers::Narrow(Storage::from(
+                kmers
+                    .into_iter()
+                    .map(|kmer| kmer as u32)
+                    .collect::<Vec<u32>>(),
+            ))
+        } else {
+            SortedKmers::Wide(Storage::from(kmers))
+        }
+    }
+
+    fn read_from<S: ArraySource>(
+        source: &mut S,
+        kmer_len: usize,
+        num_kmers: usize,
+    ) -> Result<Self, String> {
+        if kmer_len <= MAX_NARROW_KMER_LEN {
+            Ok(SortedKmers::Narrow(source.next_array(num_kmers)?))
+        } else {
+            Ok(SortedKmers::Wide(source.next_array(num_kmers)?))
+        }
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut ChecksumWriter<W>) -> std::io::Result<()> {
+        match self {
+            SortedKmers::Narrow(kmers) => write_array(writer, kmers),
+            SortedKmers::Wide(kmers) => write_array(writer, kmers),
+        }
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = u64> + '_> {
+        match self {
+            SortedKmers::Narrow(kmers) => Box::new(kmers.iter().map(|kmer| *kmer as u64)),
+            SortedKmers::Wide(kmers) => Box::new(kmers.iter().copied()),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            SortedKmers::Narrow(kmers) => kmers.len(),
+            SortedKmers::Wide(kmers) => kmers.len(),
+        }
+    }
+
+    fn size_in_bytes(&self) -> usize {
+        match self {
+            SortedKmers::Narrow(kmers) => kmers.size_in_bytes(),
+            SortedKmers::Wide(kmers) => kmers.size_in_bytes(),
+        }
+    }
+
+    // Returns the position of the k-mer in the table, if it is there
+    fn position(&self, kmer: u64) -> Option<usize> {
+        match self {
+            SortedKmers::Narrow(kmers) => kmers.binary_search(&u32::try_from(kmer).ok()?).ok(),
+            SortedKmers::Wide(kmers) => kmers.binary_search(&kmer).ok(),
+        }
+    }
+}
+
+impl KmerIndex {
+    // The k-mers must be sorted and the k-mer at position i maps to the run length encoding at
+    // rle_indices[i]. The kind must fit the k-mers (see KmerIndexKind::check).
+    pub fn from_sorted_kmers(
+        kmers: Vec<u64>,
+        rle_indices: Vec<u32>,
+        kmer_len: usize,
+        kind: KmerIndexKind,
+    ) -> Self {
+        assert_eq!(kmers.len(), rle_indices.len());
+
+        match kind {
+            KmerIndexKind::BitVector => {
+                let mut bits = vec![0_u64; kmer_space(kmer_len).div_ceil(64)];
+                kmers.iter().for_each(|kmer| {
+                    bits[(kmer / 64) as usize] |= 1 << (kmer % 64);
+                });
+
+                // Sample the number of set bits before every WORDS_PER_RANK words
+                // The last sample is the total number of set bits
+                let mut ranks = Vec::with_capacity(bits.len() / WORDS_PER_RANK + 2);
+                let mut rank = 0_u32;
+                for words in bits.chunks(WORDS_PER_RANK) {
+                    ranks.push(rank);
+                    rank += words.iter().map(|word| word.count_ones()).sum::<u32>();
+                }
+                ranks.push(rank);
+
+                KmerIndex::BitVector {
+                    bits: Storage::from(bits),
+                    ranks: Storage::from(ranks),
+                    rle_indices: Storage::from(rle_indices),
+                }
+            }
+            KmerIndexKind::Direct => {
+                let mut table = vec![ABSENT; kmer_space(kmer_len)];
+                kmers.iter().zip(rle_indices).for_each(|(kmer, rle_index)| {
+                    table[*kmer as usize] = rle_index;
+                });
+                KmerIndex::Direct {
+                    num_kmers: kmers.len(),
+                    rle_indices: Storage::from(table),
+                }
+            }
+            KmerIndexKind::Sorted => KmerIndex::Sorted {
+                kmers: SortedKmers::from(kmers, kmer_len),
+                rle_indices: Storage::from(rle_indices),
+            },
+        }
+    }
+
+    // Points every k-mer at the run length encoding `mapping[rle_index]` instead of `rle_index`
+    pub fn remap_rle_indices(&mut self, mapping: &[u32]) {
+        let (KmerIndex::BitVector { rle_indices, .. }
+        | KmerIndex::Direct { rle_indices, .. }
+        | KmerIndex::Sorted { rle_indices, .. }) = self;
+        let remapped = rle_indices
+            .iter()
+            .map(|rle_index| match *rle_index {
+                ABSENT => ABSENT,
+                rle_index => mapping[rle_index as usize],
+            })
+            .collect::<Vec<u32>>();
+        *rle_indices = Storage::from(remapped);
+    }
+
+    pub(crate) fn read_from<S: ArraySource>(
+        source: &mut S,
+        kind: KmerIndexKind,
+        kmer_len: usize,
+        num_kmers: usize,
+    ) -> Result<Self, String> {
+        match kind {
+            KmerIndexKind::BitVector => {
+                let num_words = kmer_space(kmer_len).div_ceil(64);
+                Ok(KmerIndex::BitVector {
+                    bits: source.next_array(num_words)?,
+                    ranks: source.next_array(num_words.div_ceil(WORDS_PER_RANK) + 1)?,
+                    rle_indices: source.next_array(num_kmers)?,
+                })
+            }
+            KmerIndexKind::Direct => Ok(KmerIndex::Direct {
+                num_kmers,
+                rle_indices: source.next_array(kmer_space(kmer_len))?,
+            }),
+            KmerIndexKind::Sorted => Ok(KmerIndex::Sorted {
+                kmers: SortedKmers::read_from(source, kmer_len, num_kmers)?,
+                rle_indices: source.next_array(num_kmers)?,
+            }),
+        }
+    }
+
+    pub(crate) fn write_to<W: Write>(&self, writer: &mut ChecksumWriter<W>) -> std::io::Result<()> {
+        match self {
+            KmerIndex::BitVector {
+                bits,
+                ranks,
+                rle_indices,
+            } => {
+                write_array(writer, bits)?;
+                write_array(writer, ranks)?;
+                write_array(writer, rle_indices)
+            }
+            KmerIndex::Direct { rle_indices, .. } => write_array(writer, rle_indices),
+            KmerIndex::Sorted { kmers, rle_indices } => {
+                kmers.write_to(writer)?;
+                write_array(writer, rle_indices)
+            }
+        }
+    }
+
+    // Counts how many k-mers point to each run length encoding
+    pub fn count_rle_uses(&self, num_rles: usize) -> Vec<u32> {
+        let (KmerIndex::BitVector { rle_indices, .. }
+        | KmerIndex::Direct { rle_indices, .. }
+        | KmerIndex::Sorted { rle_indices, .. }) = self;
+        let mut uses = vec![0_u32; num_rles];
+        rle_indices
+            .iter()
+            .filter(|rle_index| **rle_index != ABSENT)
+            .for_each(|rle_index| uses[*rle_index as usize] += 1);
+        uses
+    }
+
+    // Iterates over the (k-mer, rle index) pairs in increasing k-mer order
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (u64, usize)> + '_> {
+        match self {
+            KmerIndex::BitVector {
+                bits, rle_indices, ..
+            } => Box::new(
+                bits.iter()
+                    .enumerate()
+                    .flat_map(|(word_index, word)| {
+                        BitIter::from(*word).map(move |bit| (word_index * 64 + bit) as u64)
+                    })
+                    .zip(rle_indices.iter().map(|rle_index| *rle_index as usize)),
+            ),
+            KmerIndex::Direct { rle_indices, .. } => Box::new(
+                rle_indices
+                    .iter()
+                    .enumerate()
+                    .filter(|(_kmer, rle_index)| **rle_index != ABSENT)
+                    .map(|(kmer, rle_index)| (kmer as u64, *rle_index as usize)),
+            ),
+            KmerIndex::Sorted { kmers, rle_indices } => Box::new(
+                kmers
+                    .iter()
+                    .zip(rle_indices.iter().map(|rle_index| *rle_index as usize)),
+            ),
+        }
+    }
+
+    pub fn kind(&self) -> KmerIndexKind {
+        match self {
+            KmerIndex::BitVector { .. } => KmerIndexKind::BitVector,
+            KmerIndex::Direct { .. } => KmerIndexKind::Direct,
+            KmerIndex::Sorted { .. } => KmerIndexKind::Sorted,
+        }
+    }
+
+    pub fn num_kmers(&self) -> usize {
+        match self {
+            KmerIndex::BitVector { ranks, .. } => *ranks.last().unwrap() as usize,
+            KmerIndex::Direct { num_kmers, .. } => *num_kmers,
+            KmerIndex::Sorted { kmers, .. } => kmers.len(),
+        }
+    }
+
+    pub fn size_in_bytes(&self) -> usize {
+        match self {
+            KmerIndex::BitVector {
+                bits,
+                ranks,
+                rle_indices,
+            } => bits.size_in_bytes() + ranks.size_in_bytes() + rle_indices.size_in_bytes(),
+            KmerIndex::Direct { rle_indices, .. } => rle_indices.size_in_bytes(),
+            KmerIndex::Sorted { kmers, rle_indices } => {
+                kmers.size_in_bytes() + rle_indices.size_in_bytes()
+            }
+        }
+    }
+
+    pub fn get(&self, kmer: u64) -> Option<usize> {
+        match self {
+            KmerIndex::BitVector {
+                bits,
+                ranks,
+                rle_indices,
+            } => {
+                let word_index = (kmer / 64) as usize;
+                let word = *bits.get(word_index)?;
+                let bit = kmer % 64;
+                if word & (1 << bit) == 0 {
+                    return None;
+                }
+
+                // The rank of the k-mer is the number of set bits before it
+                let sample_index = word_index / WORDS_PER_RANK;
+                let rank = ranks[sample_index]
+                    + bits[sample_index * WORDS_PER_RANK..word_index]
+                        .iter()
+                        .map(|word| word.count_ones())
+                        .sum::<u32>()
+                    + (word & ((1 << bit) - 1)).count_ones();
+                Some(rle_indices[rank as usize] as usize)
+            }
+            KmerIndex::Direct { rle_indices, .. } => match rle_indices.get(kmer as usize) {
+                Some(rle_index) if *rle_index != ABSENT => Some(*rle_index as usize),
+                _ => None,
+            },
+            KmerIndex::Sorted { kmers, rle_indices } => kmers
+                .position(kmer)
+                .map(|position| rle_indices[position] as usize),
+        }
+    }
+}
+
+// The number of possible (not necessarily canonical) k-mers of length `kmer_len`
+fn kmer_space(kmer_len: usize) -> usize {
+    1 << (kmer_len << 1)
+}
@@ -2,6 +2,8 @@ use itertools::Itertools;
 use std::cmp::min;
 use std::slice::Iter;
 
+use crate::consts::MAX_KMER_LEN;
+
 fn base2int(base: u8) -> Option<usize> {
     match base {
         b'A' => Some(0),
@@ -32,6 +34,7 @@ pub struct CanonicalKmerIter<'a> {
 
 impl<'a> CanonicalKmerIter<'a> {
     pub fn from(sequence: &'a [u8], kmer_len: usize, syncmer_info: Option<(usize, usize)>) -> Self {
+        assert!(kmer_len > 0 && kmer_len <= MAX_KMER_LEN);
         match syncmer_info {
             Some((smer_len, syncmer_offset)) => {
                 assert!(smer_len <= kmer_len);
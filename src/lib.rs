@@ -1,12 +1,23 @@
+pub mod abundance;
 pub mod big_exp_float;
 pub mod binomial_sf;
+pub mod classifier;
 pub mod consts;
 pub mod database;
 pub mod decode;
 pub mod group;
+pub mod header;
+pub mod input;
 pub mod io;
+pub mod kmer_index;
 pub mod kmer_iter;
 pub mod order;
+pub mod pipeline;
+pub mod report;
 pub mod rle;
+pub mod serve;
+pub mod shard;
+pub mod storage;
+pub mod taxonomy;
 pub mod tracing;
 pub mod utility;
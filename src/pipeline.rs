@@ -0,0 +1,259 @@
+use std::collections::BTreeMap;
+use std::panic;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How often a worker waiting for a batch checks whether another stage panicked
+const ABORT_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Consecutive items of an input, numbered in the order they were read so that their results can
+/// be written in that order
+#[derive(Debug, Clone, PartialEq)]
+pub struct Batch<T> {
+    pub index: usize,
+    pub items: Vec<T>,
+}
+
+/// Groups the items of an iterator into numbered batches of (at most) `batch_size` items
+pub struct Batches<I> {
+    batch_size: usize,
+    iter: I,
+    next_index: usize,
+}
+
+impl<I: Iterator> Batches<I> {
+    pub fn new(iter: I, batch_size: usize) -> Self {
+        assert!(batch_size > 0, "the batch size must be at least 1");
+        Batches {
+            batch_size,
+            iter,
+            next_index: 0,
+        }
+    }
+}
+
+impl<I: Iterator> Iterator for Batches<I> {
+    type Item = Batch<I::Item>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let items = self
+            .iter
+            .by_ref()
+            .take(self.batch_size)
+            .collect::<Vec<I::Item>>();
+        if items.is_empty() {
+            return None;
+        }
+        let index = self.next_index;
+        self.next_index += 1;
+        Some(Batch { index, items })
+    }
+}
+
+/// Holds the results of batches that finished before an earlier batch, until every batch before
+/// them has finished
+#[derive(Debug)]
+pub struct ReorderBuffer<T> {
+    next_index: usize,
+    pending: BTreeMap<usize, T>,
+}
+
+impl<T> Default for ReorderBuffer<T> {
+    fn default() -> Self {
+        ReorderBuffer {
+            next_index: 0,
+            pending: BTreeMap::new(),
+        }
+    }
+}
+
+impl<T> ReorderBuffer<T> {
+    // Adds the result of a batch, returning the results that can now be written in order
+    pub fn push(&mut self, index: usize, result: T) -> Vec<T> {
+        self.pending.insert(index, result);
+        let mut in_order = vec![];
+        while let Some(result) = self.pending.remove(&self.next_index) {
+            in_order.push(result);
+            self.next_index += 1;
+        }
+        in_order
+    }
+}
+
+/// The time each stage of a pipeline spent working, excluding the time it waited for the other
+/// stages. The worker time is summed over every worker.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct PipelineStats {
+    pub num_batches: usize,
+    pub read_time: Duration,
+    pub work_time: Duration,
+    pub write_time: Duration,
+}
+
+/// Runs the batches through a reader thread, `num_workers` worker threads that process each batch
+/// with `work` and their own state (e.g. reusable buffers) from `new_state`, and a writer thread
+/// that passes the results to `write` in the order of the batches (or as soon as they are
+/// processed, if not `ordered`).
+/// At most `capacity` batches wait between two stages, so the reader blocks instead of reading the
+/// whole input if the workers or the writer are slower. The reader also blocks once
+/// `num_workers + 2 * capacity` batches have been read but not written, so a slow batch cannot make
+/// the results of later batches pile up while they wait to be written in order. If the reader, a
+/// worker, or the writer panics, the other stages stop after their current batch (without reading
+/// the rest of the input) and the panic is propagated. A reader blocked on its input (e.g. stdin)
+/// only stops once the input returns.
+pub fn run_pipeline<T, S, R, I, N, W, F>(
+    batches: I,
+    num_workers: usize,
+    capacity: usize,
+    ordered: bool,
+    new_state: N,
+    work: W,
+    mut write: F,
+) -> PipelineStats
+where
+    I: Iterator<Item = Batch<T>> + Send,
+    T: Send,
+    R: Send,
+    N: Fn() -> S + Sync,
+    W: Fn(&mut S, Vec<T>) -> R + Sync,
+    F: FnMut(R) + Send,
+{
+    assert!(num_workers > 0, "the pipeline needs at least 1 worker");
+    let (batch_sender, batch_receiver) = mpsc::sync_channel::<Batch<T>>(capacity);
+    let (result_sender, result_receiver) = mpsc::sync_channel::<(usize, R)>(capacity);
+
+    // The reader takes a slot before reading each batch, which the writer frees once the batch is
+    // written (or a worker or the writer frees if it panics, so the reader does not wait for it).
+    // Batches take slots in order, so the next batch to write always has one.
+    let max_in_flight = num_workers + 2 * capacity;
+    let (slot_sender, slot_receiver) = mpsc::sync_channel::<()>(max_in_flight);
+    for _ in 0..max_in_flight {
+        slot_sender.send(()).unwrap();
+    }
+
+    // The workers share the receiver, which is dropped (stopping the reader) once every worker
+    // has stopped
+    let batch_receiver = Arc::new(Mutex::new(batch_receiver));
+    let new_state = &new_state;
+    let work = &work;
+
+    // Set when a worker or the writer panics, since the other stages would otherwise go on
+    // through the whole input (or wait for each other forever)
+    let aborted = &AtomicBool::new(false);
+
+    thread::scope(|scope| {
+        let reader = scope.spawn(move || {
+            let mut read_time = Duration::ZERO;
+            let mut num_batches = 0;
+            let mut batches = batches;
+            while !aborted.load(Ordering::Relaxed) {
+                if slot_receiver.recv().is_err() {
+                    break;
+                }
+                let read_start = Instant::now();
+                let batch = match batches.next() {
+                    Some(batch) => batch,
+                    None => break,
+                };
+                read_time += read_start.elapsed();
+                num_batches += 1;
+                if batch_sender.send(batch).is_err() {
+                    break;
+                }
+            }
+            (read_time, num_batches)
+        });
+
+        let workers = (0..num_workers)
+            .map(|_| {
+                let batch_receiver = Arc::clone(&batch_receiver);
+                let result_sender = result_sender.clone();
+                let slot_sender = slot_sender.clone();
+                scope.spawn(move || {
+                    let _abort_on_panic = AbortOnPanic(aborted, slot_sender);
+                    let mut state = new_state();
+                    let mut work_time = Duration::ZERO;
+                    while !aborted.load(Ordering::Relaxed) {
+                        // Release the receiver before processing, so other workers can receive
+                        let batch = match batch_receiver
+                            .lock()
+                            .unwrap()
+                            .recv_timeout(ABORT_CHECK_INTERVAL)
+                        {
+                            Ok(batch) => batch,
+                            Err(RecvTimeoutError::Timeout) => continue,
+                            Err(RecvTimeoutError::Disconnected) => break,
+                        };
+                        let work_start = Instant::now();
+                        let result = work(&mut state, batch.items);
+                        work_time += work_start.elapsed();
+                        if result_sender.send((batch.index, result)).is_err() {
+                            break;
+                        }
+                    }
+                    work_time
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(batch_receiver);
+        drop(result_sender);
+
+        let writer_slot_sender = slot_sender.clone();
+        let writer = scope.spawn(move || {
+            let _abort_on_panic = AbortOnPanic(aborted, writer_slot_sender);
+            let mut write_time = Duration::ZERO;
+            let mut reorder_buffer = ReorderBuffer::default();
+            let mut write_and_free_slot = |result| {
+                write(result);
+                // The reader may have stopped, in which case the slot is no longer needed
+                let _ = slot_sender.try_send(());
+            };
+            for (index, result) in result_receiver {
+                let write_start = Instant::now();
+                if ordered {
+                    reorder_buffer
+                        .push(index, result)
+                        .into_iter()
+                        .for_each(&mut write_and_free_slot);
+                } else {
+                    write_and_free_slot(result);
+                }
+                write_time += write_start.elapsed();
+            }
+            write_time
+        });
+
+        let work_time = workers.into_iter().map(join).sum();
+        let (read_time, num_batches) = join(reader);
+        let write_time = join(writer);
+        PipelineStats {
+            num_batches,
+            read_time,
+            work_time,
+            write_time,
+        }
+    })
+}
+
+// Sets the flag if the thread panics while the guard is alive, and frees the slot of the batch it
+// panicked on so that the reader does not wait for it to be written (and sees the flag)
+struct AbortOnPanic<'a>(&'a AtomicBool, mpsc::SyncSender<()>);
+
+impl Drop for AbortOnPanic<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.0.store(true, Ordering::Relaxed);
+            let _ = self.1.try_send(());
+        }
+    }
+}
+
+// Waits for a thread of the pipeline, propagating its panic
+fn join<T>(handle: thread::ScopedJoinHandle<'_, T>) -> T {
+    handle
+        .join()
+        .unwrap_or_else(|panic| panic::resume_unwind(panic))
+}
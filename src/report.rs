@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use tracing::warn;
+
+use crate::taxonomy::Taxonomy;
+
+/// The number of reads classified to each tax id and file
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ReadCounts {
+    pub files: HashMap<String, usize>,
+    // Reads classified to the lowest common ancestor of several files ("-" in the file column)
+    pub lca_tax_ids: HashMap<usize, usize>,
+    pub tax_ids: HashMap<usize, usize>,
+    pub unclassified: usize,
+}
+
+impl ReadCounts {
+    pub fn add_classified(&mut self, tax_id: usize, file: &str) {
+        *self.tax_ids.entry(tax_id).or_insert(0) += 1;
+        *self.files.entry(file.to_string()).or_insert(0) += 1;
+        if file == "-" {
+            *self.lca_tax_ids.entry(tax_id).or_insert(0) += 1;
+        }
+    }
+
+    pub fn add_unclassified(&mut self) {
+        self.unclassified += 1;
+    }
+
+    pub fn num_classified(&self) -> usize {
+        self.tax_ids.values().sum()
+    }
+
+    pub fn num_reads(&self) -> usize {
+        self.num_classified() + self.unclassified
+    }
+
+    // Counts the reads of a readid2file (.r2f) written by skim-classify
+    pub fn from_r2f(path: &Path) -> Result<Self, String> {
+        let file =
+            File::open(path).map_err(|e| format!("could not open file at {:?}: {}", path, e))?;
+        let mut counts = ReadCounts::default();
+        for (line_index, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|e| format!("could not read {:?}: {}", path, e))?;
+            let columns = line.split('\t').collect::<Vec<&str>>();
+            match columns[..] {
+                ["C", _read_id, tax_id, file, ..] => {
+                    let tax_id = tax_id.parse::<usize>().map_err(|e| {
+                        format!(
+                            "line {} of {:?} has a bad tax id: {}",
+                            line_index + 1,
+                            path,
+                            e
+                        )
+                    })?;
+                    counts.add_classified(tax_id, file);
+                }
+                ["U", ..] => counts.add_unclassified(),
+                _ => {
+                    return Err(format!(
+                        "line {} of {:?} is not a classification",
+                        line_index + 1,
+                        path
+                    ))
+                }
+            }
+        }
+        Ok(counts)
+    }
+}
+
+// Formats `part` as a percentage of `total`
+fn percent(part: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / total as f64
+    }
+}
+
+// The one letter code Kraken2 uses for a rank, if it is one of the main ranks
+fn main_rank_code(rank: &str) -> Option<char> {
+    match rank {
+        "superkingdom" | "domain" => Some('D'),
+        "kingdom" => Some('K'),
+        "phylum" => Some('P'),
+        "class" => Some('C'),
+        "order" => Some('O'),
+        "family" => Some('F'),
+        "genus" => Some('G'),
+        "species" => Some('S'),
+        _ => None,
+    }
+}
+
+/// Writes a Kraken2-style report, where each line is:
+///     percentage | clade count | direct count | rank code | tax id | indented scientific name
+/// Reads are rolled up to every ancestor of the tax id they were classified to. Tax ids with no
+/// reads in their clade are left out, and children are sorted by decreasing clade count.
+pub fn write_taxonomy_report<W: Write>(
+    writer: &mut W,
+    counts: &ReadCounts,
+    taxonomy: &Taxonomy,
+) -> io::Result<()> {
+    let num_reads = counts.num_reads();
+    writeln!(
+        writer,
+        "{:6.2}\t{}\t{}\tU\t0\tunclassified",
+        percent(counts.unclassified, num_reads),
+        counts.unclassified,
+        counts.unclassified
+    )?;
+
+    // Roll the direct counts up to every ancestor
+    let mut clade_counts = HashMap::new();
+    let mut num_missing = 0;
+    for (tax_id, count) in &counts.tax_ids {
+        let lineage = taxonomy.lineage(*tax_id);
+        if lineage.is_empty() {
+            num_missing += count;
+        }
+        for ancestor in lineage {
+            *clade_counts.entry(ancestor).or_insert(0_usize) += count;
+        }
+    }
+    if num_missing > 0 {
+        warn!(
+            "{} classified reads have tax ids that are not in the taxonomy and are left out of the report",
+            num_missing
+        );
+    }
+
+    // The children of every tax id with reads in its clade
+    let mut children = HashMap::new();
+    let mut roots = vec![];
+    for tax_id in clade_counts.keys() {
+        match taxonomy.parent(*tax_id) {
+            Some(parent) => children
+                .entry(parent)
+                .or_insert_with(Vec::new)
+                .push(*tax_id),
+            None => roots.push(*tax_id),
+        }
+    }
+    let sort_by_clade_count = |tax_ids: &mut Vec<usize>| {
+        tax_ids.sort_unstable_by_key(|tax_id| (usize::MAX - clade_counts[tax_id], *tax_id))
+    };
+    sort_by_clade_count(&mut roots);
+    children.values_mut().for_each(sort_by_clade_count);
+
+    // Depth first, keeping track of the depth, the last main rank code, and how many levels below
+    // that rank the tax id is
+    let mut stack = roots
+        .into_iter()
+        .rev()
+        .map(|root| (root, 0, 'R', 0))
+        .collect::<Vec<(usize, usize, char, usize)>>();
+    while let Some((tax_id, depth, parent_code, parent_levels)) = stack.pop() {
+        let (code, levels) = match main_rank_code(taxonomy.rank(tax_id).unwrap_or_default()) {
+            Some(code) => (code, 0),
+            None if depth == 0 => ('R', 0),
+            None => (parent_code, parent_levels + 1),
+        };
+        let rank_code = match levels {
+            0 => code.to_string(),
+            levels => format!("{}{}", code, levels),
+        };
+        writeln!(
+            writer,
+            "{:6.2}\t{}\t{}\t{}\t{}\t{}{}",
+            percent(clade_counts[&tax_id], num_reads),
+            clade_counts[&tax_id],
+            counts.tax_ids.get(&tax_id).copied().unwrap_or(0),
+            rank_code,
+            tax_id,
+            "  ".repeat(depth),
+            taxonomy.name(tax_id).unwrap_or_default()
+        )?;
+        if let Some(children) = children.get(&tax_id) {
+            stack.extend(
+                children
+                    .iter()
+                    .rev()
+                    .map(|child| (*child, depth + 1, code, levels)),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Writes a report of the reads classified to each reference file, for databases without a
+/// taxonomy, where each line is:
+///     percentage | count | tax id | file
+/// Files are sorted by decreasing count.
+pub fn write_file_report<W: Write>(
+    writer: &mut W,
+    counts: &ReadCounts,
+    files: &[String],
+    tax_ids: &[usize],
+) -> io::Result<()> {
+    let num_reads = counts.num_reads();
+    writeln!(
+        writer,
+        "{:6.2}\t{}\t0\tunclassified",
+        percent(counts.unclassified, num_reads),
+        counts.unclassified
+    )?;
+
+    let file_tax_ids = files
+        .iter()
+        .map(String::as_str)
+        .zip(tax_ids.iter().copied())
+        .collect::<HashMap<&str, usize>>();
+    let mut file_counts = counts.files.iter().collect::<Vec<(&String, &usize)>>();
+    file_counts.sort_unstable_by_key(|(file, count)| (usize::MAX - **count, *file));
+    for (file, count) in file_counts {
+        writeln!(
+            writer,
+            "{:6.2}\t{}\t{}\t{}",
+            percent(*count, num_reads),
+            count,
+            file_tax_ids.get(file.as_str()).copied().unwrap_or(0),
+            file
+        )?;
+    }
+    Ok(())
+}
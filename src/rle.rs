@@ -1,8 +1,13 @@
 use bit_iter::BitIter;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
 use std::slice::Iter;
 use tracing::warn;
 
+use crate::io::ChecksumWriter;
+use crate::storage::{write_array, ArraySource, Storage};
+
 pub const MAX_RUN: u16 = (1 << 14) - 1;
 pub const MAX_UNCOMPRESSED_BITS: usize = 15;
 
@@ -265,32 +270,138 @@ impl RunLengthEncoding {
         }
     }
 
+    pub fn from_indices<I: IntoIterator<Item = usize>>(indices: I) -> Self {
+        let mut naive_rle = NaiveRunLengthEncoding::new();
+        indices.into_iter().for_each(|index| naive_rle.push(index));
+        naive_rle.to_rle()
+    }
+
     pub fn collect_indices(&self) -> Vec<u32> {
-        // Create the blocks iterator
-        let mut blocks_iter = self
-            .blocks
-            .iter()
-            .map(|block_u16| Block::from_u16(*block_u16));
-
-        // Initialize curr_i and the return value
-        let mut curr_i = 0_u32;
-        let mut indices = vec![];
-
-        while let Some(block) = blocks_iter.next() {
-            match block {
-                Block::Zeros(zeroes_count) => curr_i += zeroes_count as u32,
-                Block::Ones(ones_count) => {
-                    let ones_count = ones_count as u32;
-                    indices.extend(curr_i..curr_i + ones_count);
-                    curr_i += ones_count;
-                }
-                Block::Uncompressed(bits) => {
-                    indices.extend(BitIter::from(bits).map(|i| i as u32 + curr_i));
-                    curr_i += MAX_UNCOMPRESSED_BITS as u32;
-                }
+        collect_indices_of(&self.blocks)
+    }
+}
+
+// Decodes the indices of the set bits of the raw blocks of an RLE
+fn collect_indices_of(blocks: &[u16]) -> Vec<u32> {
+    // Create the blocks iterator
+    let mut blocks_iter = blocks.iter().map(|block_u16| Block::from_u16(*block_u16));
+
+    // Initialize curr_i and the return value
+    let mut curr_i = 0_u32;
+    let mut indices = vec![];
+
+    while let Some(block) = blocks_iter.next() {
+        match block {
+            Block::Zeros(zeroes_count) => curr_i += zeroes_count as u32,
+            Block::Ones(ones_count) => {
+                let ones_count = ones_count as u32;
+                indices.extend(curr_i..curr_i + ones_count);
+                curr_i += ones_count;
+            }
+            Block::Uncompressed(bits) => {
+                indices.extend(BitIter::from(bits).map(|i| i as u32 + curr_i));
+                curr_i += MAX_UNCOMPRESSED_BITS as u32;
             }
         }
-        indices
+    }
+    indices
+}
+
+/// All run length encodings of a database, stored back to back in one contiguous buffer.
+/// The blocks of the RLE at `index` are `blocks[offsets[index]..offsets[index + 1]]`.
+pub struct FlatRunLengthEncodings {
+    blocks: Storage<u16>,
+    offsets: Storage<u64>,
+}
+
+impl FlatRunLengthEncodings {
+    pub fn from_rles<I: IntoIterator<Item = RunLengthEncoding>>(rles: I) -> Self {
+        let mut blocks = vec![];
+        let mut offsets = vec![0_u64];
+        for rle in rles {
+            blocks.extend_from_slice(&rle.blocks);
+            offsets.push(blocks.len() as u64);
+        }
+        FlatRunLengthEncodings {
+            blocks: Storage::from(blocks),
+            offsets: Storage::from(offsets),
+        }
+    }
+
+    // Stores each distinct run length encoding once
+    // Returns the deduplicated encodings along with the new index of every original encoding
+    pub fn deduplicate(&self) -> (Self, Vec<u32>) {
+        let mut blocks = vec![];
+        let mut offsets = vec![0_u64];
+        let mut distinct_rles = HashMap::new();
+        let mapping = (0..self.len())
+            .map(|index| {
+                let raw_blocks = self.get_raw_blocks(index);
+                *distinct_rles.entry(raw_blocks).or_insert_with(|| {
+                    blocks.extend_from_slice(raw_blocks);
+                    offsets.push(blocks.len() as u64);
+                    (offsets.len() - 2) as u32
+                })
+            })
+            .collect::<Vec<u32>>();
+        let deduplicated = FlatRunLengthEncodings {
+            blocks: Storage::from(blocks),
+            offsets: Storage::from(offsets),
+        };
+        (deduplicated, mapping)
+    }
+
+    pub(crate) fn read_from<S: ArraySource>(
+        source: &mut S,
+        num_rles: usize,
+        num_blocks: usize,
+    ) -> Result<Self, String> {
+        let offsets = source.next_array::<u64>(num_rles + 1)?;
+        let blocks = source.next_array::<u16>(num_blocks)?;
+
+        // Only check the ends of the offsets because checking all of them would require reading
+        // every offset from a memory-mapped file
+        if offsets.first() != Some(&0) || offsets.last() != Some(&(num_blocks as u64)) {
+            return Err("run length encoding offsets do not match the block buffer".to_string());
+        }
+        Ok(FlatRunLengthEncodings { blocks, offsets })
+    }
+
+    pub(crate) fn write_to<W: Write>(&self, writer: &mut ChecksumWriter<W>) -> std::io::Result<()> {
+        write_array(writer, &self.offsets)?;
+        write_array(writer, &self.blocks)
+    }
+
+    pub fn len(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        self.blocks.is_mapped()
+    }
+
+    pub fn num_of_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn get_raw_blocks(&self, index: usize) -> &[u16] {
+        &self.blocks[self.offsets[index] as usize..self.offsets[index + 1] as usize]
+    }
+
+    pub fn block_iters(&self, index: usize) -> RunLengthEncodingBlockIter<'_> {
+        RunLengthEncodingBlockIter::from_blocks(self.get_raw_blocks(index))
+    }
+
+    pub fn collect_indices(&self, index: usize) -> Vec<u32> {
+        collect_indices_of(self.get_raw_blocks(index))
+    }
+
+    pub fn size_in_bytes(&self) -> usize {
+        self.blocks.size_in_bytes() + self.offsets.size_in_bytes()
     }
 }
 
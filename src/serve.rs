@@ -0,0 +1,406 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use tracing::warn;
+
+use crate::classifier::{Classification, Classifier, ClassifierState, Decision};
+use crate::database::Database;
+use crate::taxonomy::Taxonomy;
+
+// The longest read id or chunk accepted, so a corrupt length cannot exhaust the memory
+pub const MAX_FIELD_LEN: usize = 1 << 24;
+
+/// What the sequencer should do with a read, as in the Read Until API
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Send the next chunk of the read
+    Proceed,
+    /// Keep sequencing the read, but stop sending its chunks
+    StopReceiving,
+    /// Eject the read from the pore
+    Unblock,
+}
+
+impl Action {
+    // The byte that encodes the action in a response
+    pub fn code(self) -> u8 {
+        match self {
+            Action::Proceed => 0,
+            Action::StopReceiving => 1,
+            Action::Unblock => 2,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Result<Self, String> {
+        match code {
+            0 => Ok(Action::Proceed),
+            1 => Ok(Action::StopReceiving),
+            2 => Ok(Action::Unblock),
+            _ => Err(format!("{} is not an action", code)),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Action::Proceed => "proceed",
+            Action::StopReceiving => "stop_receiving",
+            Action::Unblock => "unblock",
+        }
+    }
+}
+
+/// Whether the reads of the targets are kept and the others ejected, or the other way around
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetMode {
+    Enrich,
+    Deplete,
+}
+
+/// The files of a database whose reads are enriched or depleted
+#[derive(Debug, Clone, PartialEq)]
+pub struct Targets {
+    is_target: Vec<bool>,
+    mode: TargetMode,
+}
+
+impl Targets {
+    // The files of the database that are listed, or whose tax id is a listed tax id or (if the
+    // taxonomy is known) a descendant of one
+    pub fn new(
+        database: &Database,
+        tax_ids: &[usize],
+        files: &[String],
+        taxonomy: Option<&Taxonomy>,
+        mode: TargetMode,
+    ) -> Self {
+        let tax_ids = tax_ids.iter().collect::<HashSet<&usize>>();
+        let files = files.iter().collect::<HashSet<&String>>();
+        let is_target = database
+            .files()
+            .iter()
+            .zip(database.tax_ids())
+            .map(|(file, tax_id)| {
+                let lineage = match taxonomy.map(|taxonomy| taxonomy.lineage(*tax_id)) {
+                    Some(lineage) if !lineage.is_empty() => lineage,
+                    _ => vec![*tax_id],
+                };
+                files.contains(file) || lineage.iter().any(|tax_id| tax_ids.contains(tax_id))
+            })
+            .collect();
+        Targets { is_target, mode }
+    }
+
+    // Reads the targets from a file with a tax id or the name of a file of the database on each
+    // line. Empty lines and lines starting with '#' are skipped.
+    pub fn from_file(
+        path: &Path,
+        database: &Database,
+        taxonomy: Option<&Taxonomy>,
+        mode: TargetMode,
+    ) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("could not read the targets in {:?}: {}", path, e))?;
+        let mut tax_ids = vec![];
+        let mut files = vec![];
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.parse::<usize>() {
+                Ok(tax_id) => tax_ids.push(tax_id),
+                Err(_) => files.push(line.to_string()),
+            }
+        }
+        Ok(Targets::new(database, &tax_ids, &files, taxonomy, mode))
+    }
+
+    // The number of files of the database that are targets
+    pub fn num_targets(&self) -> usize {
+        self.is_target
+            .iter()
+            .filter(|is_target| **is_target)
+            .count()
+    }
+
+    // Whether the read is classified, and only to files that are targets
+    pub fn is_target(&self, classification: &Classification) -> bool {
+        classification.is_classified()
+            && classification
+                .file_indices
+                .iter()
+                .all(|index| self.is_target[*index])
+    }
+
+    // Keeps sequencing the reads that are wanted and ejects the others. When enriching, reads that
+    // cannot be classified are not wanted, and when depleting, they are.
+    pub fn action(&self, decision: Decision, classification: &Classification) -> Action {
+        let wanted = match decision {
+            Decision::NeedsMoreData => return Action::Proceed,
+            Decision::Classified => {
+                self.is_target(classification) == (self.mode == TargetMode::Enrich)
+            }
+            Decision::Rejected => self.mode == TargetMode::Deplete,
+        };
+        if wanted {
+            Action::StopReceiving
+        } else {
+            Action::Unblock
+        }
+    }
+}
+
+/// The next chunk of a read, sent by the client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    /// The bases of the chunk. An empty chunk ends the read.
+    pub chunk: Vec<u8>,
+    pub read_id: String,
+}
+
+/// The action for a read after a chunk, and its classification by the bases so far
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub action: Action,
+    /// The file the read is classified to, or None if it is unclassified
+    pub file: Option<String>,
+    pub read_id: String,
+    /// The tax id the read is classified to, or None if it is unclassified
+    pub tax_id: Option<usize>,
+}
+
+/// The number of responses with each action
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ActionCounts {
+    pub proceed: usize,
+    pub stop_receiving: usize,
+    pub unblock: usize,
+}
+
+/// The reads of a client that are still being classified, each with its own classifier state.
+/// A read is forgotten once it gets an action other than proceed (or ends), so any later chunk
+/// with its id starts a new read. Once there are too many reads, the one that has gone the longest
+/// without a chunk is evicted, as the client stopped sending its chunks without ending it.
+pub struct Session<'a> {
+    classifier: &'a Classifier<'a>,
+    counts: ActionCounts,
+    max_read_len: Option<usize>,
+    max_reads: usize,
+    num_requests: u64,
+    // Each read with the number of requests before its last chunk, which orders `idle_reads`
+    idle_reads: BTreeMap<u64, String>,
+    reads: HashMap<String, (u64, ClassifierState)>,
+    spare_states: Vec<ClassifierState>,
+    targets: &'a Targets,
+}
+
+impl<'a> Session<'a> {
+    // Reads are rejected once they cannot be classified within `max_read_len` bases, and at most
+    // `max_reads` reads are kept at a time
+    pub fn new(
+        classifier: &'a Classifier<'a>,
+        targets: &'a Targets,
+        max_read_len: Option<usize>,
+        max_reads: usize,
+    ) -> Self {
+        Session {
+            classifier,
+            counts: ActionCounts::default(),
+            max_read_len,
+            max_reads: max_reads.max(1),
+            num_requests: 0,
+            idle_reads: BTreeMap::new(),
+            reads: HashMap::new(),
+            spare_states: vec![],
+            targets,
+        }
+    }
+
+    // Adds the chunk to its read and decides what to do with the read
+    pub fn handle(&mut self, request: &Request) -> Response {
+        if !self.reads.contains_key(&request.read_id) {
+            if self.reads.len() >= self.max_reads {
+                let (_, read_id) = self.idle_reads.pop_first().unwrap();
+                warn!(
+                    "evicting read {}, the longest without a chunk of {} reads without a decision",
+                    read_id,
+                    self.reads.len()
+                );
+                let (_, state) = self.reads.remove(&read_id).unwrap();
+                self.spare_states.push(state);
+            }
+
+            let mut state = self
+                .spare_states
+                .pop()
+                .unwrap_or_else(|| self.classifier.new_state());
+            self.classifier.start_read(&mut state, self.max_read_len);
+            self.reads
+                .insert(request.read_id.clone(), (self.num_requests, state));
+        }
+        let (last_request, state) = self.reads.get_mut(&request.read_id).unwrap();
+        self.idle_reads.remove(last_request);
+        *last_request = self.num_requests;
+        self.idle_reads
+            .insert(self.num_requests, request.read_id.clone());
+        self.num_requests += 1;
+        let (decision, classification) = self.classifier.add_chunk(state, &request.chunk);
+
+        // A read that ended without a decision can no longer be classified
+        let decision = match decision {
+            Decision::NeedsMoreData if request.chunk.is_empty() => Decision::Rejected,
+            decision => decision,
+        };
+        let action = self.targets.action(decision, &classification);
+        match action {
+            Action::Proceed => self.counts.proceed += 1,
+            Action::StopReceiving => self.counts.stop_receiving += 1,
+            Action::Unblock => self.counts.unblock += 1,
+        }
+        if action != Action::Proceed {
+            let (last_request, state) = self.reads.remove(&request.read_id).unwrap();
+            self.idle_reads.remove(&last_request);
+            self.spare_states.push(state);
+        }
+
+        Response {
+            action,
+            file: classification
+                .file(self.classifier.database())
+                .map(str::to_string),
+            read_id: request.read_id.clone(),
+            tax_id: classification.tax_id,
+        }
+    }
+
+    pub fn action_counts(&self) -> ActionCounts {
+        self.counts
+    }
+
+    // The number of reads that are still being classified
+    pub fn num_reads(&self) -> usize {
+        self.reads.len()
+    }
+
+    // Whether the read is still being classified
+    pub fn is_reading(&self, read_id: &str) -> bool {
+        self.reads.contains_key(read_id)
+    }
+}
+
+// Answers the requests of a client until it disconnects. The responses are flushed whenever no
+// more requests have been received, so the client gets each decision as soon as possible.
+pub fn serve_connection<R: Read, W: Write>(
+    session: &mut Session,
+    reader: R,
+    writer: W,
+) -> Result<(), String> {
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    while let Some(request) = read_request(&mut reader)? {
+        let response = session.handle(&request);
+        write_response(&mut writer, &response)?;
+        if reader.buffer().is_empty() {
+            writer.flush().map_err(write_error)?;
+        }
+    }
+    writer.flush().map_err(write_error)
+}
+
+// Every message is a sequence of fields. Numbers are big-endian, and the read id, chunk and file
+// are prefixed with their length as a u32.
+//
+// Request:  read id, chunk
+// Response: read id, action (u8: 0 = proceed, 1 = stop_receiving, 2 = unblock),
+//           tax id (u64, 0 if unclassified), file (empty if unclassified or several files)
+
+// Reads a request, or None if the client disconnected before it
+pub fn read_request<R: BufRead>(reader: &mut R) -> Result<Option<Request>, String> {
+    let read_id = match read_field(reader, true)? {
+        Some(read_id) => into_string(read_id)?,
+        None => return Ok(None),
+    };
+    let chunk = read_field(reader, false)?.unwrap();
+    Ok(Some(Request { chunk, read_id }))
+}
+
+pub fn write_request<W: Write>(writer: &mut W, read_id: &str, chunk: &[u8]) -> Result<(), String> {
+    write_field(writer, read_id.as_bytes())?;
+    write_field(writer, chunk)
+}
+
+// Reads a response, or None if the server disconnected before it
+pub fn read_response<R: BufRead>(reader: &mut R) -> Result<Option<Response>, String> {
+    let read_id = match read_field(reader, true)? {
+        Some(read_id) => into_string(read_id)?,
+        None => return Ok(None),
+    };
+    let mut action = [0; 1];
+    let mut tax_id = [0; 8];
+    reader.read_exact(&mut action).map_err(read_error)?;
+    reader.read_exact(&mut tax_id).map_err(read_error)?;
+    let file = into_string(read_field(reader, false)?.unwrap())?;
+    Ok(Some(Response {
+        action: Action::from_code(action[0])?,
+        file: Some(file).filter(|file| !file.is_empty()),
+        read_id,
+        tax_id: Some(u64::from_be_bytes(tax_id) as usize).filter(|tax_id| *tax_id != 0),
+    }))
+}
+
+pub fn write_response<W: Write>(writer: &mut W, response: &Response) -> Result<(), String> {
+    write_field(writer, response.read_id.as_bytes())?;
+    writer
+        .write_all(&[response.action.code()])
+        .map_err(write_error)?;
+    writer
+        .write_all(&(response.tax_id.unwrap_or(0) as u64).to_be_bytes())
+        .map_err(write_error)?;
+    write_field(writer, response.file.as_deref().unwrap_or("").as_bytes())
+}
+
+// Reads a length and then that many bytes. If `first` (the field starts a message), the stream
+// may end before it, which returns None.
+fn read_field<R: BufRead>(reader: &mut R, first: bool) -> Result<Option<Vec<u8>>, String> {
+    if first && reader.fill_buf().map_err(read_error)?.is_empty() {
+        return Ok(None);
+    }
+    let mut len = [0; 4];
+    reader.read_exact(&mut len).map_err(read_error)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FIELD_LEN {
+        return Err(format!(
+            "a field of {} bytes is longer than the maximum of {} bytes",
+            len, MAX_FIELD_LEN
+        ));
+    }
+    let mut bytes = vec![0; len];
+    reader.read_exact(&mut bytes).map_err(read_error)?;
+    Ok(Some(bytes))
+}
+
+fn write_field<W: Write>(writer: &mut W, bytes: &[u8]) -> Result<(), String> {
+    if bytes.len() > MAX_FIELD_LEN {
+        return Err(format!(
+            "a field of {} bytes is longer than the maximum of {} bytes",
+            bytes.len(),
+            MAX_FIELD_LEN
+        ));
+    }
+    writer
+        .write_all(&(bytes.len() as u32).to_be_bytes())
+        .map_err(write_error)?;
+    writer.write_all(bytes).map_err(write_error)
+}
+
+fn into_string(bytes: Vec<u8>) -> Result<String, String> {
+    String::from_utf8(bytes).map_err(|e| format!("the read id or file is not UTF-8: {}", e))
+}
+
+fn read_error(e: io::Error) -> String {
+    format!("could not read message: {}", e)
+}
+
+fn write_error(e: io::Error) -> String {
+    format!("could not write message: {}", e)
+}
@@ -0,0 +1,153 @@
+use bytemuck::cast_slice_mut;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+// At most this many shards are used so that the number of open files stays reasonable
+pub(crate) const MAX_SHARDS: usize = 512;
+
+// Size of the write buffer of each shard
+const SHARD_BUFFER_BYTES: usize = 1 << 16;
+
+// Number of pairs read at a time when a shard is split
+const SPLIT_BUFFER_PAIRS: usize = 1 << 16;
+
+/// Options for building a database shard by shard (see `Database::from_files_sharded`)
+pub struct ShardConfig {
+    // The most memory, in bytes, that the (k-mer, file index) pairs of a shard should use
+    pub max_memory: usize,
+    // Where the shards are written to
+    pub temp_dir: PathBuf,
+}
+
+/// Spills (k-mer, file index) pairs to one temporary file per shard.
+/// Shards partition a k-mer range by prefix, so shard `i` only contains k-mers that are smaller
+/// than every k-mer in shard `i + 1`.
+pub(crate) struct ShardWriter {
+    num_pairs: Vec<usize>,
+    paths: Vec<PathBuf>,
+    shift: usize,
+    writers: Vec<BufWriter<File>>,
+}
+
+impl ShardWriter {
+    // Shards the k-mers that only differ in their lowest `range_bits` bits (all k-mers, if it is
+    // twice the k-mer length). The number of shards is rounded up to a power of two, but is never
+    // more than MAX_SHARDS or the number of k-mers in the range.
+    pub(crate) fn create(
+        dir: &Path,
+        name: &str,
+        range_bits: usize,
+        num_shards: usize,
+    ) -> Result<Self, String> {
+        let shard_bits =
+            (num_shards.next_power_of_two().min(MAX_SHARDS).ilog2() as usize).min(range_bits);
+
+        let paths = (0..1_usize << shard_bits)
+            .map(|shard| dir.join(format!("{}_{}.bin", name, shard)))
+            .collect::<Vec<PathBuf>>();
+        let writers = paths
+            .iter()
+            .map(|path| {
+                File::create(path)
+                    .map(|file| BufWriter::with_capacity(SHARD_BUFFER_BYTES, file))
+                    .map_err(|e| format!("could not create shard file {:?}: {}", path, e))
+            })
+            .collect::<Result<Vec<BufWriter<File>>, String>>()?;
+
+        Ok(ShardWriter {
+            num_pairs: vec![0; paths.len()],
+            paths,
+            shift: range_bits - shard_bits,
+            writers,
+        })
+    }
+
+    pub(crate) fn num_shards(&self) -> usize {
+        self.paths.len()
+    }
+
+    pub(crate) fn write(&mut self, kmer: u64, file_index: u32) -> Result<(), String> {
+        // Higher bits are the same for every k-mer of the range
+        let shard = (kmer >> self.shift) as usize & (self.paths.len() - 1);
+        self.num_pairs[shard] += 1;
+        self.writers[shard]
+            .write_all(bytemuck::bytes_of(&[kmer, file_index as u64]))
+            .map_err(|e| {
+                format!(
+                    "could not write to shard file {:?}: {}",
+                    self.paths[shard], e
+                )
+            })
+    }
+
+    // Flushes every shard, returning them in k-mer order
+    pub(crate) fn finish(self) -> Result<Vec<Shard>, String> {
+        self.writers
+            .into_iter()
+            .zip(self.paths)
+            .zip(self.num_pairs)
+            .map(|((mut writer, path), num_pairs)| {
+                writer
+                    .flush()
+                    .map_err(|e| format!("could not write to shard file {:?}: {}", path, e))?;
+                Ok(Shard {
+                    num_pairs,
+                    path,
+                    range_bits: self.shift,
+                })
+            })
+            .collect()
+    }
+}
+
+/// The (k-mer, file index) pairs of a k-mer range, spilled to a temporary file
+pub(crate) struct Shard {
+    pub(crate) num_pairs: usize,
+    path: PathBuf,
+    // K-mers of the shard only differ in this many of their lowest bits
+    range_bits: usize,
+}
+
+impl Shard {
+    // Reads all pairs of the shard (in the order they were written) as [k-mer, file index]
+    pub(crate) fn read_pairs(&self) -> Result<Vec<[u64; 2]>, String> {
+        let mut pairs = vec![[0_u64; 2]; self.num_pairs];
+        File::open(&self.path)
+            .and_then(|file| BufReader::new(file).read_exact(cast_slice_mut(&mut pairs)))
+            .map_err(|e| format!("could not read shard file {:?}: {}", self.path, e))?;
+        Ok(pairs)
+    }
+
+    // Whether the k-mer range of the shard has more than one k-mer, so it can be split
+    pub(crate) fn can_split(&self) -> bool {
+        self.range_bits > 0
+    }
+
+    // Splits the shard into (up to) `num_shards` shards of its k-mer range, in k-mer order,
+    // without reading all of its pairs at once
+    pub(crate) fn split(self, num_shards: usize) -> Result<Vec<Shard>, String> {
+        let dir = self.path.parent().unwrap();
+        let name = self.path.file_stem().unwrap().to_string_lossy();
+        let mut shard_writer = ShardWriter::create(dir, &name, self.range_bits, num_shards)?;
+
+        let read_error = |e| format!("could not read shard file {:?}: {}", self.path, e);
+        let mut reader = BufReader::new(File::open(&self.path).map_err(read_error)?);
+        let mut pairs = vec![[0_u64; 2]; self.num_pairs.min(SPLIT_BUFFER_PAIRS)];
+        let mut num_remaining = self.num_pairs;
+        while num_remaining > 0 {
+            let len = num_remaining.min(pairs.len());
+            reader
+                .read_exact(cast_slice_mut(&mut pairs[..len]))
+                .map_err(read_error)?;
+            for [kmer, file_index] in &pairs[..len] {
+                shard_writer.write(*kmer, *file_index as u32)?;
+            }
+            num_remaining -= len;
+        }
+        fs::remove_file(&self.path)
+            .map_err(|e| format!("could not remove shard file {:?}: {}", self.path, e))?;
+
+        shard_writer.finish()
+    }
+}
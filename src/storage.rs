@@ -0,0 +1,123 @@
+use bytemuck::{cast_slice, cast_slice_mut, Pod};
+use memmap2::Mmap;
+use std::io::{Read, Write};
+use std::mem::size_of;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use crate::io::{ChecksumReader, ChecksumWriter};
+
+/// Arrays in a file are aligned to this many bytes so that they can be used directly from a
+/// memory-mapped file
+pub const ALIGNMENT: u64 = 8;
+
+/// A read-only array that either owns its elements or borrows them from a memory-mapped file.
+/// Arrays are stored in native (little endian on all supported platforms) byte order.
+pub enum Storage<T: Pod> {
+    Mapped {
+        mmap: Arc<Mmap>,
+        start: usize,
+        len: usize,
+    },
+    Owned(Box<[T]>),
+}
+
+impl<T: Pod> Storage<T> {
+    pub fn is_mapped(&self) -> bool {
+        matches!(self, Storage::Mapped { .. })
+    }
+
+    pub fn size_in_bytes(&self) -> usize {
+        self.len() * size_of::<T>()
+    }
+}
+
+impl<T: Pod> Deref for Storage<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match self {
+            Storage::Mapped { mmap, start, len } => {
+                cast_slice(&mmap[*start..*start + *len * size_of::<T>()])
+            }
+            Storage::Owned(array) => array,
+        }
+    }
+}
+
+impl<T: Pod> From<Vec<T>> for Storage<T> {
+    fn from(array: Vec<T>) -> Self {
+        Storage::Owned(array.into_boxed_slice())
+    }
+}
+
+// Returns the number of padding bytes needed to align `position`
+pub fn padding_len(position: u64) -> u64 {
+    (ALIGNMENT - position % ALIGNMENT) % ALIGNMENT
+}
+
+/// Produces the arrays of a file one after another, skipping the padding between them
+pub(crate) trait ArraySource {
+    fn next_array<T: Pod>(&mut self, len: usize) -> Result<Storage<T>, String>;
+}
+
+// The position just past an array of `len` elements that starts (after its padding) at
+// `position`, or an error if the array would extend past the end of the file. The lengths of
+// arrays come from the file itself, so they are checked before anything is allocated or sliced.
+fn array_end<T: Pod>(position: u64, len: usize, file_len: u64) -> Result<(u64, u64), String> {
+    let start = position + padding_len(position);
+    let end = (len as u64)
+        .checked_mul(size_of::<T>() as u64)
+        .and_then(|num_bytes| start.checked_add(num_bytes))
+        .filter(|end| *end <= file_len)
+        .ok_or_else(|| {
+            format!(
+                "array of {} elements at byte {} extends past the end of the file",
+                len, start
+            )
+        })?;
+    Ok((start, end))
+}
+
+// Reads the arrays into owned memory
+impl<R: Read> ArraySource for ChecksumReader<R> {
+    fn next_array<T: Pod>(&mut self, len: usize) -> Result<Storage<T>, String> {
+        if let Some(file_len) = self.file_len() {
+            array_end::<T>(self.position(), len, file_len)?;
+        }
+        let mut padding = [0_u8; ALIGNMENT as usize];
+        let mut array = vec![T::zeroed(); len].into_boxed_slice();
+        self.read_exact(&mut padding[..padding_len(self.position()) as usize])
+            .and_then(|_| self.read_exact(cast_slice_mut(&mut array)))
+            .map_err(|e| format!("could not read array of {} elements: {}", len, e))?;
+        Ok(Storage::Owned(array))
+    }
+}
+
+/// Borrows the arrays directly from a memory-mapped file
+pub(crate) struct MappedArrays<'a> {
+    pub(crate) mmap: &'a Arc<Mmap>,
+    pub(crate) position: u64,
+}
+
+impl ArraySource for MappedArrays<'_> {
+    fn next_array<T: Pod>(&mut self, len: usize) -> Result<Storage<T>, String> {
+        let (start, end) = array_end::<T>(self.position, len, self.mmap.len() as u64)?;
+        self.position = end;
+        Ok(Storage::Mapped {
+            mmap: Arc::clone(self.mmap),
+            start: start as usize,
+            len,
+        })
+    }
+}
+
+// Pads the output so that the array starts on an `ALIGNMENT` byte boundary, then writes it
+pub(crate) fn write_array<T: Pod, W: Write>(
+    writer: &mut ChecksumWriter<W>,
+    array: &[T],
+) -> std::io::Result<()> {
+    let padding = [0_u8; ALIGNMENT as usize];
+    writer.write_all(&padding[..padding_len(writer.position()) as usize])?;
+    writer.write_all(cast_slice(array))
+}
@@ -0,0 +1,208 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use taxonomy::{ncbi, GeneralTaxonomy, Taxonomy as _};
+
+/// A taxonomy pruned to the tax ids of a database and all of their ancestors, so that names,
+/// ranks and lineages are available without the original taxonomy files.
+/// Nodes are sorted by tax id and found using binary search.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Taxonomy {
+    names: Box<[String]>,
+    // The parent of the root is the root itself, as in nodes.dmp
+    parents: Box<[usize]>,
+    ranks: Box<[String]>,
+    tax_ids: Box<[usize]>,
+}
+
+impl Taxonomy {
+    // Loads the NCBI taxonomy (nodes.dmp and names.dmp) in `ncbi_dir`, keeping only the given tax
+    // ids and their ancestors
+    pub fn from_ncbi(ncbi_dir: &Path, tax_ids: &[usize]) -> Result<Self, String> {
+        let full_taxonomy = ncbi::load(ncbi_dir)
+            .map_err(|e| format!("could not load the NCBI taxonomy in {:?}: {}", ncbi_dir, e))?;
+        Taxonomy::from_lineages(tax_ids, |tax_id| ncbi_node(&full_taxonomy, tax_id))
+    }
+
+    // Returns the part of the taxonomy needed for the given tax ids
+    pub fn pruned(&self, tax_ids: &[usize]) -> Result<Self, String> {
+        Taxonomy::from_lineages(tax_ids, |tax_id| {
+            self.position(tax_id).map(|position| {
+                (
+                    self.parents[position],
+                    self.names[position].clone(),
+                    self.ranks[position].clone(),
+                )
+            })
+        })
+    }
+
+    // Combines the nodes of both taxonomies, preferring the nodes of `self` when both have the
+    // same tax id
+    pub fn merged(&self, other: &Taxonomy) -> Self {
+        let mut nodes = BTreeMap::new();
+        for taxonomy in [other, self] {
+            for position in 0..taxonomy.len() {
+                nodes.insert(
+                    taxonomy.tax_ids[position],
+                    (
+                        taxonomy.parents[position],
+                        taxonomy.names[position].clone(),
+                        taxonomy.ranks[position].clone(),
+                    ),
+                );
+            }
+        }
+        Taxonomy::from_nodes(nodes)
+    }
+
+    // Collects the given tax ids and all of their ancestors, where `node_of` returns the
+    // (parent tax id, name, rank) of a tax id
+    fn from_lineages<F>(tax_ids: &[usize], node_of: F) -> Result<Self, String>
+    where
+        F: Fn(usize) -> Option<(usize, String, String)>,
+    {
+        let mut nodes = BTreeMap::new();
+        for tax_id in tax_ids {
+            let mut tax_id = *tax_id;
+            while !nodes.contains_key(&tax_id) {
+                let node = node_of(tax_id)
+                    .ok_or_else(|| format!("tax id {} is not in the taxonomy", tax_id))?;
+                let parent = node.0;
+                nodes.insert(tax_id, node);
+                tax_id = parent;
+            }
+        }
+        Ok(Taxonomy::from_nodes(nodes))
+    }
+
+    fn from_nodes(nodes: BTreeMap<usize, (usize, String, String)>) -> Self {
+        let mut names = Vec::with_capacity(nodes.len());
+        let mut parents = Vec::with_capacity(nodes.len());
+        let mut ranks = Vec::with_capacity(nodes.len());
+        let mut tax_ids = Vec::with_capacity(nodes.len());
+        for (tax_id, (parent, name, rank)) in nodes {
+            names.push(name);
+            parents.push(parent);
+            ranks.push(rank);
+            tax_ids.push(tax_id);
+        }
+        Taxonomy {
+            names: names.into_boxed_slice(),
+            parents: parents.into_boxed_slice(),
+            ranks: ranks.into_boxed_slice(),
+            tax_ids: tax_ids.into_boxed_slice(),
+        }
+    }
+
+    fn position(&self, tax_id: usize) -> Option<usize> {
+        self.tax_ids.binary_search(&tax_id).ok()
+    }
+
+    pub fn contains(&self, tax_id: usize) -> bool {
+        self.position(tax_id).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tax_ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tax_ids.is_empty()
+    }
+
+    // The scientific name of the tax id
+    pub fn name(&self, tax_id: usize) -> Option<&str> {
+        self.position(tax_id)
+            .map(|position| self.names[position].as_str())
+    }
+
+    // The rank of the tax id as written in nodes.dmp (e.g. "species" or "no rank")
+    pub fn rank(&self, tax_id: usize) -> Option<&str> {
+        self.position(tax_id)
+            .map(|position| self.ranks[position].as_str())
+    }
+
+    // The parent of the tax id, which is None for the root
+    pub fn parent(&self, tax_id: usize) -> Option<usize> {
+        self.position(tax_id)
+            .map(|position| self.parents[position])
+            .filter(|parent| *parent != tax_id)
+    }
+
+    // The tax ids from the root down to (and including) the tax id, which is empty if the tax id
+    // is not in the taxonomy
+    pub fn lineage(&self, tax_id: usize) -> Vec<usize> {
+        if !self.contains(tax_id) {
+            return vec![];
+        }
+        let mut lineage = vec![tax_id];
+        while let Some(parent) = self.parent(*lineage.last().unwrap()) {
+            lineage.push(parent);
+        }
+        lineage.reverse();
+        lineage
+    }
+
+    // The lowest common ancestor of the tax ids, which is None if there are no tax ids or any of
+    // them is not in the taxonomy
+    pub fn lca(&self, tax_ids: &[usize]) -> Option<usize> {
+        let (first, rest) = tax_ids.split_first()?;
+        let mut lineage = self.lineage(*first);
+        for tax_id in rest {
+            let other_lineage = self.lineage(*tax_id);
+            let common_len = lineage
+                .iter()
+                .zip(&other_lineage)
+                .take_while(|(a, b)| a == b)
+                .count();
+            lineage.truncate(common_len);
+        }
+        lineage.last().copied()
+    }
+}
+
+// Loads merged.dmp of the NCBI taxonomy in `ncbi_dir`, mapping each old tax id to the tax id it was
+// merged into
+pub fn load_ncbi_merged(ncbi_dir: &Path) -> Result<HashMap<usize, usize>, String> {
+    let path = ncbi_dir.join("merged.dmp");
+    let contents =
+        fs::read_to_string(&path).map_err(|e| format!("could not read {:?}: {}", path, e))?;
+    contents
+        .lines()
+        .enumerate()
+        .map(|(line_index, line)| {
+            let columns = line
+                .split('|')
+                .map(|column| column.trim().parse::<usize>())
+                .collect::<Vec<_>>();
+            match columns[..] {
+                [Ok(old_tax_id), Ok(new_tax_id), ..] => Ok((old_tax_id, new_tax_id)),
+                _ => Err(format!(
+                    "line {} of {:?} is not an old and a new tax id",
+                    line_index + 1,
+                    path
+                )),
+            }
+        })
+        .collect()
+}
+
+// Looks up the (parent tax id, name, rank) of a tax id in a taxonomy loaded by the taxonomy crate
+fn ncbi_node(taxonomy: &GeneralTaxonomy, tax_id: usize) -> Option<(usize, String, String)> {
+    let index = taxonomy.to_internal_index(&tax_id.to_string()).ok()?;
+    let parent = match taxonomy.parent(index).ok()? {
+        Some((parent_index, _distance)) => taxonomy
+            .from_internal_index(parent_index)
+            .ok()?
+            .parse::<usize>()
+            .ok()?,
+        None => tax_id,
+    };
+    Some((
+        parent,
+        taxonomy.name(index).ok()?.to_string(),
+        taxonomy.rank(index).ok()?.to_ncbi_rank().to_string(),
+    ))
+}
@@ -1,10 +1,20 @@
 use std::io;
 use tracing::Level;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 use tracing_subscriber::{filter, fmt, prelude::*, EnvFilter};
 
-pub fn start_skim_tracing_subscriber() -> () {
-    // Create a layer that logs to stdout
-    let stdout_log = fmt::layer();
+pub fn start_skim_tracing_subscriber() {
+    start_tracing_subscriber(BoxMakeWriter::new(io::stdout))
+}
+
+// Logs INFO and DEBUG messages to stderr as well, for binaries writing their output to stdout
+pub fn start_skim_stderr_tracing_subscriber() {
+    start_tracing_subscriber(BoxMakeWriter::new(io::stderr))
+}
+
+fn start_tracing_subscriber(info_writer: BoxMakeWriter) {
+    // Create a layer that logs to stdout (or stderr)
+    let stdout_log = fmt::layer().with_writer(info_writer);
 
     // Get the stdout logging filter level from the RUST_LOG environment variable
     //   - INFO messages are always logged to stdout
@@ -1,21 +1,32 @@
-use bio::io::{fasta, fastq};
+use bio::io::fasta;
 use indicatif::ProgressIterator;
 use itertools::Itertools;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use rayon::prelude::*;
-use roaring::RoaringBitmap;
+use roaring::RoaringTreemap;
 use std::cmp::min;
-use std::fs::File;
 use std::fs::{self, DirEntry};
-use std::io::{BufReader, ErrorKind};
+use std::io::ErrorKind;
 use std::path::Path;
 use std::path::PathBuf;
 use tracing::{debug, info, warn};
 
-use crate::consts::{DEFAULT_K, DEFAULT_S, DEFAULT_T, DEFAULT_TOTAL_KMERS, TOTAL_CANONICAL_15MERS};
+use crate::consts::{
+    DEFAULT_K, DEFAULT_S, DEFAULT_T, DEFAULT_TOTAL_KMERS, MAX_KMER_LEN, TOTAL_CANONICAL_15MERS,
+};
+use crate::input::{open_decompressed, InputReader, SequenceRecords};
 use crate::kmer_iter::CanonicalKmerIter;
 
+// Extensions of the compressed files that are read transparently
+const COMPRESSED_EXTENSIONS: [&str; 6] = [".gz", ".bgz", ".bz2", ".xz", ".zst", ".zstd"];
+
 fn is_fasta_file(entry: &DirEntry) -> bool {
     let entry_file_name = entry.file_name().to_str().unwrap().to_string();
+    let entry_file_name = COMPRESSED_EXTENSIONS
+        .iter()
+        .find_map(|extension| entry_file_name.strip_suffix(extension))
+        .unwrap_or(&entry_file_name);
     entry_file_name.ends_with(".fna")
         || entry_file_name.ends_with(".fasta")
         || entry_file_name.ends_with(".fa")
@@ -36,7 +47,7 @@ pub fn get_fasta_files(ref_loc: &Path) -> Vec<PathBuf> {
                     Some(entry.path())
                 } else {
                     warn!(
-                        "reference directory entry {:?} not recognized as a fasta file (did not end with '.fna', '.fasta', or '.fa'), skipping...",
+                        "reference directory entry {:?} not recognized as a fasta file (did not end with '.fna', '.fasta', or '.fa', optionally followed by a compression extension), skipping...",
                         entry
                     );
                     None
@@ -50,9 +61,10 @@ pub fn get_fasta_files(ref_loc: &Path) -> Vec<PathBuf> {
         .collect::<Vec<PathBuf>>()
 }
 
-pub fn get_fasta_iter_of_file(file_path: &Path) -> fasta::Records<BufReader<File>> {
-    match fasta::Reader::from_file(file_path) {
-        Ok(reader) => reader.records(),
+// Iterates over the records of a FASTA file, which may be compressed
+pub fn get_fasta_iter_of_file(file_path: &Path) -> fasta::Records<InputReader> {
+    match open_decompressed(file_path) {
+        Ok(reader) => fasta::Reader::from_bufread(reader).records(),
         Err(error) => panic!(
             "unable to get fasta iter of {:?} because of the following error: {}",
             file_path, error
@@ -60,11 +72,12 @@ pub fn get_fasta_iter_of_file(file_path: &Path) -> fasta::Records<BufReader<File
     }
 }
 
-pub fn get_fastq_iter_of_file(file_path: &Path) -> fastq::Records<BufReader<File>> {
-    match fastq::Reader::from_file(file_path) {
-        Ok(reader) => reader.records(),
+// Iterates over the records of a FASTA or FASTQ file, which may be compressed
+pub fn get_sequence_iter_of_file(file_path: &Path) -> SequenceRecords {
+    match SequenceRecords::from_file(file_path) {
+        Ok(records) => records,
         Err(error) => panic!(
-            "unable to get fastq iter of {:?} because of the following error: {}",
+            "unable to get sequence iter of {:?} because of the following error: {}",
             file_path, error
         ),
     }
@@ -75,17 +88,65 @@ pub fn create_bitmap(
     file: PathBuf,
     kmer_len: usize,
     syncmers: Option<(usize, usize)>,
-) -> RoaringBitmap {
-    let mut bitmap = RoaringBitmap::new();
-    let mut record_iter = get_fasta_iter_of_file(&file);
+) -> RoaringTreemap {
+    let mut bitmap = RoaringTreemap::new();
+    let mut record_iter = get_sequence_iter_of_file(&file);
     while let Some(Ok(record)) = record_iter.next() {
         for kmer in CanonicalKmerIter::from(record.seq(), kmer_len, syncmers) {
-            bitmap.insert(kmer as u32);
+            bitmap.insert(kmer as u64);
         }
     }
     bitmap
 }
 
+// Parses a k-mer length, which must fit in a 64-bit k-mer key
+pub fn parse_kmer_len(kmer_len: &str) -> Result<usize, String> {
+    let kmer_len = kmer_len
+        .parse::<usize>()
+        .map_err(|_| format!("could not parse k-mer length '{}'", kmer_len))?;
+    if kmer_len == 0 || kmer_len > MAX_KMER_LEN {
+        Err(format!(
+            "k-mer length must be between 1 and {}, but was {}",
+            MAX_KMER_LEN, kmer_len
+        ))
+    } else {
+        Ok(kmer_len)
+    }
+}
+
+// Parses a number of bytes with an optional binary unit suffix (e.g. 512M or 16G)
+pub fn parse_memory_size(size: &str) -> Result<usize, String> {
+    let size = size.trim();
+    let digits_end = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(digits_end);
+    let number = number
+        .parse::<usize>()
+        .map_err(|_| format!("could not parse memory size '{}'", size))?;
+    let shift = match unit
+        .trim()
+        .to_ascii_uppercase()
+        .trim_end_matches(['B', 'I'])
+    {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(format!("unknown unit in memory size '{}'", size)),
+    };
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("memory size '{}' is too large", size))
+}
+
+// The total number of possible syncmers is computed exactly up to this k-mer length
+const MAX_ENUMERATED_KMER_LEN: usize = 16;
+
+// Number of k-mers sampled to estimate the total number of possible syncmers
+const TOTAL_KMERS_SAMPLE_SIZE: usize = 1 << 24;
+
 pub fn compute_total_kmers(kmer_len: usize, syncmers: Option<(usize, usize)>) -> usize {
     // Handle some special values that we don't need to compute
     if kmer_len == DEFAULT_K && Some((DEFAULT_S, DEFAULT_T)) == syncmers {
@@ -96,38 +157,38 @@ pub fn compute_total_kmers(kmer_len: usize, syncmers: Option<(usize, usize)>) ->
 
     let total_kmers = 4_usize.pow(kmer_len as u32);
     let kmer_mask = (1 << (kmer_len << 1)) - 1;
-    info!("computing total possible k-mers...");
     match syncmers {
         Some((smer_len, syncmer_offset)) => {
             let smer_mask = (1 << (smer_len << 1)) - 1;
             let kmer_smer_diff = kmer_len - smer_len;
-            (0..total_kmers)
-                .progress()
-                .filter_map(|kmer| {
-                    let canonical_kmer = min(kmer, reverse_compliment(kmer, kmer_len, kmer_mask));
-                    if kmer == canonical_kmer {
-                        if is_syncmer(kmer, kmer_smer_diff, smer_mask, syncmer_offset) {
-                            Some(kmer)
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
-                    }
-                })
-                .count()
-        }
-        None => (0..total_kmers)
-            .progress()
-            .filter_map(|kmer| {
+            let is_canonical_syncmer = |kmer: usize| {
                 let canonical_kmer = min(kmer, reverse_compliment(kmer, kmer_len, kmer_mask));
-                if kmer == canonical_kmer {
-                    Some(kmer)
-                } else {
-                    None
-                }
-            })
-            .count(),
+                kmer == canonical_kmer
+                    && is_syncmer(kmer, kmer_smer_diff, smer_mask, syncmer_offset)
+            };
+
+            if kmer_len <= MAX_ENUMERATED_KMER_LEN {
+                info!("computing total possible k-mers...");
+                (0..total_kmers)
+                    .progress()
+                    .filter(|kmer| is_canonical_syncmer(*kmer))
+                    .count()
+            } else {
+                // There are too many k-mers to check each one, so estimate the total from a
+                // (reproducible) random sample
+                info!("estimating total possible k-mers...");
+                let mut rng = StdRng::seed_from_u64(0);
+                let num_syncmers = (0..TOTAL_KMERS_SAMPLE_SIZE)
+                    .filter(|_| is_canonical_syncmer(rng.random_range(0..total_kmers)))
+                    .count();
+                (num_syncmers as f64 / TOTAL_KMERS_SAMPLE_SIZE as f64 * total_kmers as f64).round()
+                    as usize
+            }
+        }
+        // Every k-mer is canonical or the reverse complement of a canonical k-mer
+        // Only k-mers of even length can be their own reverse complement
+        None if kmer_len.is_multiple_of(2) => (total_kmers + 4_usize.pow(kmer_len as u32 / 2)) / 2,
+        None => total_kmers / 2,
     }
 }
 
@@ -0,0 +1,92 @@
+use common::{database, write_ncbi_taxonomy, KMER_LEN};
+use skim::abundance::{estimate_abundances, expected_kmers_per_read, sum_by_tax_id, Simulation};
+use skim::big_exp_float::BigExpFloat;
+use skim::report::ReadCounts;
+use skim::taxonomy::Taxonomy;
+use std::collections::HashMap;
+use tempfile::tempdir;
+
+mod common;
+
+fn read_counts() -> ReadCounts {
+    let mut counts = ReadCounts::default();
+    (0..3).for_each(|_| counts.add_classified(562, "a.fna"));
+    counts.add_classified(1236, "b.fna");
+    (0..4).for_each(|_| counts.add_classified(1236, "-"));
+    (0..2).for_each(|_| counts.add_classified(10239, "c.fna"));
+    counts.add_classified(2, "-");
+    counts.add_unclassified();
+    counts
+}
+
+#[test]
+fn redistributes_lca_reads() {
+    let database = database();
+    let lca_fractions = vec![
+        HashMap::from([(1236, 0.5)]),
+        HashMap::from([(1236, 1.0)]),
+        HashMap::new(),
+    ];
+    let (abundances, num_left_out) = estimate_abundances(&database, &read_counts(), &lca_fractions);
+
+    // No file has simulated reads classified to Bacteria
+    assert_eq!(1, num_left_out);
+    assert_eq!(
+        vec![3, 1, 2],
+        abundances
+            .iter()
+            .map(|abundance| abundance.assigned_reads)
+            .collect::<Vec<usize>>()
+    );
+    // The 4 Gammaproteobacteria reads are split 1.5 : 1.0
+    assert!((abundances[0].added_reads - 2.4).abs() < 1e-9);
+    assert!((abundances[1].added_reads - 1.6).abs() < 1e-9);
+    assert_eq!(0.0, abundances[2].added_reads);
+
+    // The second file has about half the k-mers of the others, so each of its reads counts double
+    let total = abundances
+        .iter()
+        .map(|abundance| abundance.relative_abundance)
+        .sum::<f64>();
+    assert!((total - 1.0).abs() < 1e-9);
+    assert!(abundances[1].relative_abundance > abundances[0].relative_abundance / 2.0);
+
+    let by_tax_id = sum_by_tax_id(&abundances, &[562, 562, 10239]);
+    assert_eq!(
+        vec![&562, &10239],
+        by_tax_id.keys().collect::<Vec<&usize>>()
+    );
+    assert_eq!(4, by_tax_id[&562].assigned_reads);
+}
+
+#[test]
+fn simulates_lca_reads() {
+    let dir = tempdir().unwrap();
+    write_ncbi_taxonomy(dir.path());
+    let database = database();
+    let taxonomy = Taxonomy::from_ncbi(dir.path(), database.tax_ids()).unwrap();
+
+    assert_eq!(90, expected_kmers_per_read(100, KMER_LEN, None));
+    assert_eq!(0, expected_kmers_per_read(5, KMER_LEN, None));
+
+    let simulation = Simulation {
+        cutoff_threshold: BigExpFloat::from_f64(1e-12),
+        kmers_per_read: 60,
+        max_log_ratio: None,
+        n_fixed: 100,
+        num_reads: 50,
+        seed: 0,
+    };
+    let lookup_table = database.compute_loookup_table(100);
+    let lca_fractions = simulation.lca_fractions(&database, &lookup_table, &taxonomy);
+
+    // Every read of the second file is also in the first file, while the third file only shares
+    // a few k-mers with the others
+    assert_eq!(Some(&1.0), lca_fractions[1].get(&1236));
+    assert!(lca_fractions[0][&1236] > 0.5);
+    assert!(!lca_fractions[2].contains_key(&1236));
+    assert_eq!(
+        lca_fractions,
+        simulation.lca_fractions(&database, &lookup_table, &taxonomy)
+    );
+}
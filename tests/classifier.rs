@@ -0,0 +1,166 @@
+use common::{database, write_ncbi_taxonomy, KMER_LEN, REFERENCES};
+use skim::big_exp_float::BigExpFloat;
+use skim::classifier::{Classification, Classifier, Decision};
+use skim::taxonomy::Taxonomy;
+use tempfile::tempdir;
+
+mod common;
+
+// Reads that hit different files, so the state is reset between them
+fn reads() -> Vec<&'static [u8]> {
+    vec![
+        REFERENCES[0].as_bytes(),
+        REFERENCES[1].as_bytes(),
+        b"ACGT",
+        &REFERENCES[0].as_bytes()[..40],
+        REFERENCES[0].as_bytes(),
+    ]
+}
+
+#[test]
+fn classifies_like_database() {
+    let database = database();
+    let cutoff_threshold = BigExpFloat::from_f64(1e-12);
+    let classifier = Classifier::new(&database, cutoff_threshold, 100);
+    let lookup_table = database.compute_loookup_table(100);
+    assert_eq!(&lookup_table[..], classifier.lookup_table());
+
+    let mut state = classifier.new_state();
+    for read in reads() {
+        let classification = classifier.classify(&mut state, &[read]);
+        let expected = database
+            .classify(read, cutoff_threshold, 100, &lookup_table)
+            .0;
+        assert_eq!(
+            expected,
+            classification
+                .tax_id
+                .map(|tax_id| (classification.file(&database).unwrap(), tax_id))
+        );
+        assert_eq!(expected.is_some(), classification.is_classified());
+        assert_eq!(
+            read.len().saturating_sub(KMER_LEN - 1),
+            classification.n_total
+        );
+        assert_eq!(
+            database.top_candidates(&[read], 100, &lookup_table, 3).0,
+            classifier.top_candidates(&state, 3)
+        );
+    }
+}
+
+#[test]
+fn classifies_lca() {
+    let dir = tempdir().unwrap();
+    write_ncbi_taxonomy(dir.path());
+    let database = database();
+    let taxonomy = Taxonomy::from_ncbi(dir.path(), database.tax_ids()).unwrap();
+    let cutoff_threshold = BigExpFloat::from_f64(1e-12);
+    let lookup_table = database.compute_loookup_table(100);
+
+    for max_log_ratio in [None, Some(1.0)] {
+        let mut classifier = Classifier::new(&database, cutoff_threshold, 100);
+        classifier.set_lca(&taxonomy, max_log_ratio);
+        let mut state = classifier.new_state();
+        for read in reads() {
+            let classification = classifier.classify(&mut state, &[read]);
+            let expected = database
+                .classify_lca(
+                    read,
+                    cutoff_threshold,
+                    100,
+                    &lookup_table,
+                    max_log_ratio,
+                    &taxonomy,
+                )
+                .0;
+            assert_eq!(
+                expected,
+                classification
+                    .tax_id
+                    .map(|tax_id| (classification.file_indices.clone(), tax_id))
+            );
+        }
+    }
+
+    // The file is only known if a single file is in the lowest common ancestor
+    let mut classifier = Classifier::new(&database, cutoff_threshold, 100);
+    classifier.set_lca(&taxonomy, None);
+    let classification = classifier.classify(&mut classifier.new_state(), &[reads()[0]]);
+    assert_eq!(vec![0, 1], classification.file_indices);
+    assert_eq!(None, classification.file(&database));
+}
+
+#[test]
+fn classifies_chunks() {
+    let database = database();
+    let classifier = Classifier::new(&database, BigExpFloat::from_f64(1e-20), 100);
+    let mut state = classifier.new_state();
+    let read = REFERENCES[1].as_bytes();
+
+    // The read is classified after the first chunk whose prefix of the read is classified, with
+    // the same k-mers as the prefix, including those spanning two chunks
+    let classify_prefix =
+        |len: usize| classifier.classify(&mut classifier.new_state(), &[&read[..len]]);
+    classifier.start_read(&mut state, Some(read.len()));
+    let mut classified_len = None;
+    for chunk in read.chunks(7) {
+        let (decision, classification) = classifier.add_chunk(&mut state, chunk);
+        match decision {
+            Decision::Classified => {
+                assert_eq!(vec![2], classification.file_indices);
+                assert_eq!(
+                    classify_prefix(state.read_len()).n_total,
+                    classification.n_total
+                );
+                classified_len = Some(state.read_len());
+                break;
+            }
+            Decision::NeedsMoreData => {
+                assert!(!classification.is_classified());
+                assert!(!classify_prefix(state.read_len()).is_classified())
+            }
+            Decision::Rejected => panic!("the read was rejected"),
+        }
+    }
+    let classified_len = classified_len.unwrap();
+
+    // Before any chunk, the read is unclassified like an empty read
+    let empty = classifier.classify(&mut classifier.new_state(), &[b""]);
+    let unclassified = Classification::unclassified();
+    assert_eq!(
+        (empty.file_indices, empty.n_total, empty.tax_id),
+        (
+            unclassified.file_indices,
+            unclassified.n_total,
+            unclassified.tax_id
+        )
+    );
+    assert!(classified_len < read.len());
+
+    // Without enough bases left for any file to reach the cutoff, the read is rejected, but
+    // without a maximum length, it could always be classified later
+    classifier.start_read(&mut state, Some(classified_len - 14));
+    assert_eq!(
+        Decision::Rejected,
+        classifier.add_chunk(&mut state, &read[..7]).0
+    );
+    classifier.start_read(&mut state, None);
+    assert_eq!(
+        Decision::NeedsMoreData,
+        classifier.add_chunk(&mut state, &read[..7]).0
+    );
+
+    // The best case is every k-mer hitting the file, which has no probability below the cutoff
+    // when scaled to all n_fixed hits, but the read can still be classified with fewer hits
+    let classifier = Classifier::new(&database, BigExpFloat::from_f64(1e-12), 10);
+    let mut state = classifier.new_state();
+    assert!(classifier
+        .classify(&mut state, &[&read[..KMER_LEN + 8]])
+        .is_classified());
+    classifier.start_read(&mut state, Some(read.len()));
+    assert_eq!(
+        Decision::NeedsMoreData,
+        classifier.add_chunk(&mut state, &read[..7]).0
+    );
+}
@@ -0,0 +1,88 @@
+// Not every test uses every fixture
+#![allow(dead_code)]
+
+use roaring::RoaringTreemap;
+use skim::database::Database;
+use skim::kmer_iter::CanonicalKmerIter;
+use std::fs;
+use std::path::Path;
+
+// (tax id, parent tax id, rank, scientific name)
+pub const NODES: [(usize, usize, &str, &str); 10] = [
+    (1, 1, "no rank", "root"),
+    (2, 131567, "superkingdom", "Bacteria"),
+    (543, 91347, "family", "Enterobacteriaceae"),
+    (561, 543, "genus", "Escherichia"),
+    (562, 561, "species", "Escherichia coli"),
+    (1224, 2, "phylum", "Pseudomonadota"),
+    (1236, 1224, "class", "Gammaproteobacteria"),
+    (10239, 1, "superkingdom", "Viruses"),
+    (91347, 1236, "order", "Enterobacterales"),
+    (131567, 1, "no rank", "cellular organisms"),
+];
+
+// (old tax id, tax id it was merged into)
+pub const MERGED: [(usize, usize); 1] = [(1278, 1224)];
+
+// Writes nodes.dmp, names.dmp and merged.dmp of a small part of the NCBI taxonomy
+pub fn write_ncbi_taxonomy(dir: &Path) {
+    let nodes = NODES
+        .iter()
+        .map(|(tax_id, parent, rank, _name)| {
+            format!(
+                "{}\t|\t{}\t|\t{}\t|\t\t|\t0\t|\t0\t|\t11\t|\t0\t|\t0\t|\t0\t|\t0\t|\t0\t|\t\t|\n",
+                tax_id, parent, rank
+            )
+        })
+        .collect::<String>();
+    let names = NODES
+        .iter()
+        .map(|(tax_id, _parent, _rank, name)| {
+            format!(
+                "{}\t|\t{} synonym\t|\t\t|\tsynonym\t|\n{}\t|\t{}\t|\t\t|\tscientific name\t|\n",
+                tax_id, name, tax_id, name
+            )
+        })
+        .collect::<String>();
+    fs::write(dir.join("nodes.dmp"), nodes).unwrap();
+    fs::write(dir.join("names.dmp"), names).unwrap();
+    let merged = MERGED
+        .iter()
+        .map(|(old_tax_id, new_tax_id)| format!("{}\t|\t{}\t|\n", old_tax_id, new_tax_id))
+        .collect::<String>();
+    fs::write(dir.join("merged.dmp"), merged).unwrap();
+}
+
+pub const KMER_LEN: usize = 11;
+
+pub const REFERENCES: [&str; 2] = [
+    "ACGTTGCATGCATCGATCGGGATCGATCGTAGCTAGCTAGCATCGACTAGCTAGCGGCGATCGATTTAGCGAGCTACG",
+    "GGGCATCGACTAGCATCAGCGACTACGCGCGCATCTACGACTAGCAGCTACGACATCGACGTTTAGCGCGAGCAGCAT",
+];
+
+// The first reference is in both an E. coli (a.fna) and a Gammaproteobacteria (b.fna) file, but
+// the second file only has half of it. The second reference is a virus (c.fna).
+pub fn database() -> Database {
+    let sequences = [REFERENCES[0], &REFERENCES[0][..40], REFERENCES[1]];
+    let bitmaps = sequences
+        .iter()
+        .map(|sequence| {
+            CanonicalKmerIter::from(sequence.as_bytes(), KMER_LEN, None)
+                .map(|kmer| kmer as u64)
+                .collect::<RoaringTreemap>()
+        })
+        .collect::<Vec<RoaringTreemap>>();
+    Database::from(
+        bitmaps,
+        vec![
+            "a.fna".to_string(),
+            "b.fna".to_string(),
+            "c.fna".to_string(),
+        ],
+        vec![562, 1236, 10239],
+        KMER_LEN,
+        None,
+        None,
+    )
+    .unwrap()
+}
@@ -0,0 +1,579 @@
+use flate2::write::GzEncoder;
+use roaring::RoaringTreemap;
+use skim::big_exp_float::BigExpFloat;
+use skim::consts::BinomialConsts;
+use skim::database::Database;
+use skim::kmer_index::KmerIndexKind;
+use skim::kmer_iter::CanonicalKmerIter;
+use skim::rle::RunLengthEncoding;
+use skim::shard::ShardConfig;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use tempfile::tempdir;
+
+const KMER_LEN: usize = 7;
+
+const REFERENCES: [&str; 3] = [
+    "ACGTTGCATGCATCGATCGGGATCGATCGTAGCTAGCTAGCATCGACTAGCTAGCGGCGATCGATTTAGCGAGCTACG",
+    "TTGACGATCGACTGACTAGCATCGACGGGATCGACTGACTTTTGACGATCGACTAGCTACGACGATCAGCATCGACGA",
+    "GGGCATCGACTAGCATCAGCGACTACGCGCGCATCTACGACTAGCAGCTACGACATCGACGTTTAGCGCGAGCAGCAT",
+];
+
+const FILES: [&str; 3] = ["a.fna", "b.fna", "c.fna"];
+
+fn create_bitmaps(references: &[&str]) -> Vec<RoaringTreemap> {
+    create_bitmaps_of_len(references, KMER_LEN)
+}
+
+fn create_bitmaps_of_len(references: &[&str], kmer_len: usize) -> Vec<RoaringTreemap> {
+    references
+        .iter()
+        .map(|reference| {
+            CanonicalKmerIter::from(reference.as_bytes(), kmer_len, None)
+                .map(|kmer| kmer as u64)
+                .collect::<RoaringTreemap>()
+        })
+        .collect()
+}
+
+fn create_files(files: &[&str]) -> Vec<String> {
+    files.iter().map(|file| file.to_string()).collect()
+}
+
+fn create_database(index_kind: Option<KmerIndexKind>) -> Database {
+    Database::from(
+        create_bitmaps(&REFERENCES),
+        create_files(&FILES),
+        vec![1, 2, 3],
+        KMER_LEN,
+        None,
+        index_kind,
+    )
+    .unwrap()
+}
+
+fn dump_to_bytes(database: &Database) -> Vec<u8> {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("test.skim.db");
+    database.dump_to_file(File::create(&path).unwrap()).unwrap();
+    fs::read(path).unwrap()
+}
+
+fn classify_all(database: &Database) -> Vec<Option<(String, usize)>> {
+    let lookup_table = database.compute_loookup_table(100);
+    REFERENCES
+        .iter()
+        .map(|read| {
+            database
+                .classify(
+                    read.as_bytes(),
+                    BigExpFloat::from_f64(1e-3),
+                    100,
+                    &lookup_table,
+                )
+                .0
+                .map(|(file, taxid)| (file.to_string(), taxid))
+        })
+        .collect()
+}
+
+#[test]
+fn classifies_references() {
+    let database = create_database(None);
+    assert_eq!(
+        vec![
+            Some(("a.fna".to_string(), 1)),
+            Some(("b.fna".to_string(), 2)),
+            Some(("c.fna".to_string(), 3)),
+        ],
+        classify_all(&database)
+    );
+}
+
+#[test]
+fn classifies_mates() {
+    let database = create_database(None);
+    let lookup_table = database.compute_loookup_table(100);
+    let classify_mates = |mates: &[&[u8]], cutoff: f64| {
+        database
+            .classify_mates(mates, BigExpFloat::from_f64(cutoff), 100, &lookup_table)
+            .0
+    };
+
+    // The mates of a paired-end read come from opposite strands
+    let reference = REFERENCES[1].as_bytes();
+    let mate_1 = &reference[..20];
+    let mate_2 = reference[58..]
+        .iter()
+        .rev()
+        .map(|base| match base {
+            b'A' => b'T',
+            b'C' => b'G',
+            b'G' => b'C',
+            _ => b'A',
+        })
+        .collect::<Vec<u8>>();
+
+    // Neither mate is significant on its own, but their pooled hits are
+    assert_eq!(None, classify_mates(&[mate_1], 1e-20));
+    assert_eq!(None, classify_mates(&[&mate_2], 1e-20));
+    assert_eq!(
+        Some(("b.fna", 2)),
+        classify_mates(&[mate_1, &mate_2], 1e-20)
+    );
+    assert_eq!(
+        database
+            .classify(reference, BigExpFloat::from_f64(1e-3), 100, &lookup_table)
+            .0,
+        classify_mates(&[reference], 1e-3)
+    );
+}
+
+#[test]
+fn top_candidates() {
+    let database = create_database(None);
+    let lookup_table = database.compute_loookup_table(100);
+    let read = REFERENCES[0].as_bytes();
+    let (candidates, n_total) = database.top_candidates(&[read], 100, &lookup_table, 3);
+
+    // Every k-mer of the read is in its reference, and there are fewer than n_fixed of them
+    assert_eq!(REFERENCES[0].len() - KMER_LEN + 1, n_total);
+    assert_eq!(0, candidates[0].file_index);
+    assert_eq!(n_total, candidates[0].hits);
+    assert_eq!(candidates[0].hits, candidates[0].scaled_hits);
+    assert!(candidates
+        .windows(2)
+        .all(|pair| pair[0].p_value <= pair[1].p_value));
+
+    let (top, _n_total) = database.top_candidates(&[read], 100, &lookup_table, 1);
+    assert_eq!(candidates[..1], top[..]);
+
+    // Scaling to fewer trials than k-mers queried scales the hits down
+    let lookup_table = database.compute_loookup_table(10);
+    let (scaled, n_total) = database.top_candidates(&[read], 10, &lookup_table, 3);
+    assert!(scaled.iter().all(|candidate| {
+        candidate.scaled_hits == (candidate.hits as f64 * 10.0 / n_total as f64).round() as usize
+    }));
+}
+
+#[test]
+fn dump_and_load() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("test.skim.db");
+    let database = create_database(None);
+    database.dump_to_file(File::create(&path).unwrap()).unwrap();
+
+    let loaded = Database::load_from_file(&path).unwrap();
+    assert!(!loaded.is_mapped());
+    assert_eq!(database.header(), loaded.header());
+    assert_eq!(classify_all(&database), classify_all(&loaded));
+
+    let mapped = Database::load_from_file_mmap(&path).unwrap();
+    assert!(mapped.is_mapped());
+    assert_eq!(database.header(), mapped.header());
+    assert_eq!(classify_all(&database), classify_all(&mapped));
+}
+
+#[test]
+fn rejects_corrupted_lengths() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("test.skim.db");
+    let bytes = dump_to_bytes(&create_database(Some(KmerIndexKind::Sorted)));
+
+    // In the metadata, the file names are followed by the index kind (u32), k (u64), the number of
+    // blocks (u64) and the number of k-mers (u64)
+    let files_end = bytes
+        .windows(5)
+        .position(|window| window == b"c.fna")
+        .unwrap()
+        + 5;
+    let num_kmers_start = files_end + 4 + 8 + 8;
+    assert_eq!(
+        (KMER_LEN as u64).to_le_bytes(),
+        bytes[files_end + 4..files_end + 12]
+    );
+
+    // The array lengths are checked against the file before the arrays are allocated or mapped,
+    // including lengths whose size in bytes overflows
+    for num_kmers in [1_u64 << 40, u64::MAX / 2] {
+        let mut corrupted = bytes.clone();
+        corrupted[num_kmers_start..num_kmers_start + 8].copy_from_slice(&num_kmers.to_le_bytes());
+        fs::write(&path, corrupted).unwrap();
+        assert!(Database::load_from_file(&path).is_err());
+        assert!(Database::load_from_file_mmap(&path).is_err());
+    }
+}
+
+#[test]
+fn index_kinds_agree() {
+    let direct = create_database(Some(KmerIndexKind::Direct));
+    for index_kind in [KmerIndexKind::BitVector, KmerIndexKind::Sorted] {
+        let database = create_database(Some(index_kind));
+        assert_eq!(index_kind, database.kmer_index().kind());
+        assert_eq!(
+            direct.kmer_index().num_kmers(),
+            database.kmer_index().num_kmers()
+        );
+        for kmer in 0..1 << (2 * KMER_LEN) {
+            assert_eq!(
+                direct.kmer_index().get(kmer),
+                database.kmer_index().get(kmer)
+            );
+        }
+        assert_eq!(classify_all(&direct), classify_all(&database));
+    }
+
+    // A table over all 4^31 k-mers does not fit, but a sorted one does
+    let force = |index_kind| {
+        Database::from(
+            create_bitmaps_of_len(&REFERENCES, 31),
+            create_files(&FILES),
+            vec![1, 2, 3],
+            31,
+            None,
+            Some(index_kind),
+        )
+    };
+    assert!(force(KmerIndexKind::Direct).is_err());
+    assert!(force(KmerIndexKind::BitVector).is_err());
+    assert!(force(KmerIndexKind::Sorted).is_ok());
+    assert!(KmerIndexKind::BitVector
+        .check(31, u32::MAX as usize + 1)
+        .is_err());
+
+    // Too many k-mers for the ranks of a bit vector are never put in one
+    assert_eq!(
+        KmerIndexKind::BitVector,
+        KmerIndexKind::choose(16, u32::MAX as usize)
+    );
+    assert_eq!(
+        KmerIndexKind::Sorted,
+        KmerIndexKind::choose(16, u32::MAX as usize + 1)
+    );
+}
+
+#[test]
+fn deduplicated_rows() {
+    let mut database = create_database(None);
+    let num_kmers = database.kmer_index().num_kmers();
+
+    // k-mers found only in the same single file share one row
+    assert!(database.num_rles() < num_kmers);
+    let rle_uses = database.kmer_index().count_rle_uses(database.num_rles());
+    assert!(rle_uses.iter().all(|uses| *uses > 0));
+    assert_eq!(num_kmers, rle_uses.iter().sum::<u32>() as usize);
+
+    // Deduplicating again changes nothing
+    let num_rles = database.num_rles();
+    let classifications = classify_all(&database);
+    database.deduplicate_rows();
+    assert_eq!(num_rles, database.num_rles());
+    assert_eq!(classifications, classify_all(&database));
+}
+
+#[test]
+fn add_files() {
+    for index_kind in [
+        KmerIndexKind::BitVector,
+        KmerIndexKind::Direct,
+        KmerIndexKind::Sorted,
+    ] {
+        let mut database = Database::from(
+            create_bitmaps(&REFERENCES[..1]),
+            create_files(&FILES[..1]),
+            vec![1],
+            KMER_LEN,
+            None,
+            Some(index_kind),
+        )
+        .unwrap();
+        database
+            .add_files(
+                create_bitmaps(&REFERENCES[1..]),
+                create_files(&FILES[1..]),
+                vec![2, 3],
+            )
+            .unwrap();
+
+        // The result is exactly the same as building the database with all files at once
+        let fresh = create_database(Some(index_kind));
+        assert_eq!(dump_to_bytes(&fresh), dump_to_bytes(&database));
+
+        // Files cannot be added twice
+        let error = database
+            .add_files(
+                create_bitmaps(&REFERENCES[..1]),
+                create_files(&FILES[..1]),
+                vec![1],
+            )
+            .unwrap_err();
+        assert!(error.contains("already in the database"));
+    }
+}
+
+#[test]
+fn index_kind_after_adding_kmers() {
+    // The index kind is kept while it fits, but a bit vector whose ranks would overflow is
+    // replaced
+    assert_eq!(
+        KmerIndexKind::BitVector,
+        KmerIndexKind::BitVector.check_or_choose(16, u32::MAX as usize)
+    );
+    assert_eq!(
+        KmerIndexKind::Sorted,
+        KmerIndexKind::BitVector.check_or_choose(16, u32::MAX as usize + 1)
+    );
+    assert_eq!(
+        KmerIndexKind::Sorted,
+        KmerIndexKind::Sorted.check_or_choose(KMER_LEN, 100)
+    );
+}
+
+#[test]
+fn remove_files() {
+    // The result is exactly the same as building the database without the file, with the index
+    // kind chosen again for the remaining k-mers
+    let fresh = Database::from(
+        create_bitmaps(&[REFERENCES[0], REFERENCES[2]]),
+        create_files(&[FILES[0], FILES[2]]),
+        vec![1, 3],
+        KMER_LEN,
+        None,
+        None,
+    )
+    .unwrap();
+    let mut sorted = create_database(Some(KmerIndexKind::Sorted));
+    sorted.remove_files(&[1]).unwrap();
+    assert_ne!(KmerIndexKind::Sorted, fresh.kmer_index().kind());
+    assert_eq!(dump_to_bytes(&fresh), dump_to_bytes(&sorted));
+
+    let mut database = create_database(None);
+    database.remove_files(&[1]).unwrap();
+    assert_eq!(dump_to_bytes(&fresh), dump_to_bytes(&database));
+
+    assert!(database.remove_files(&[2]).is_err());
+    assert!(database.remove_files(&[0, 1]).is_err());
+}
+
+#[test]
+fn merge() {
+    let mut database = Database::from(
+        create_bitmaps(&REFERENCES[..2]),
+        create_files(&FILES[..2]),
+        vec![1, 2],
+        KMER_LEN,
+        None,
+        None,
+    )
+    .unwrap();
+    let other = Database::from(
+        create_bitmaps(&REFERENCES[2..]),
+        create_files(&FILES[2..]),
+        vec![3],
+        KMER_LEN,
+        None,
+        Some(KmerIndexKind::Sorted),
+    )
+    .unwrap();
+    database.merge(&other).unwrap();
+
+    // The result is exactly the same as building the database with all files at once
+    assert_eq!(
+        dump_to_bytes(&create_database(None)),
+        dump_to_bytes(&database)
+    );
+
+    // The index kind of the first database is kept while it fits the merged k-mers
+    let mut bit_vector = Database::from(
+        create_bitmaps(&REFERENCES[..2]),
+        create_files(&FILES[..2]),
+        vec![1, 2],
+        KMER_LEN,
+        None,
+        Some(KmerIndexKind::BitVector),
+    )
+    .unwrap();
+    bit_vector.merge(&other).unwrap();
+    assert_eq!(
+        dump_to_bytes(&create_database(Some(KmerIndexKind::BitVector))),
+        dump_to_bytes(&bit_vector)
+    );
+
+    // The same files cannot be merged twice
+    assert!(database
+        .merge(&other)
+        .unwrap_err()
+        .contains("in both databases"));
+
+    let incompatible = Database::from(
+        create_bitmaps(&REFERENCES[2..]),
+        create_files(&FILES[2..]),
+        vec![3],
+        KMER_LEN,
+        Some((3, 1)),
+        None,
+    )
+    .unwrap();
+    assert!(database
+        .merge(&incompatible)
+        .unwrap_err()
+        .contains("different parameters"));
+}
+
+#[test]
+fn sharded_build() {
+    let dir = tempdir().unwrap();
+    for (file, reference) in FILES.iter().zip(REFERENCES) {
+        fs::write(dir.path().join(file), format!(">{}\n{}\n", file, reference)).unwrap();
+    }
+
+    // Small enough that the k-mers are spread across many shards
+    let config = ShardConfig {
+        max_memory: 64,
+        temp_dir: dir.path().to_path_buf(),
+    };
+    let database = Database::from_files_sharded(
+        dir.path(),
+        create_files(&FILES),
+        vec![1, 2, 3],
+        KMER_LEN,
+        None,
+        None,
+        &config,
+    )
+    .unwrap();
+
+    assert_eq!(
+        dump_to_bytes(&create_database(None)),
+        dump_to_bytes(&database)
+    );
+}
+
+#[test]
+fn sharded_build_of_compressed_references() {
+    let dir = tempdir().unwrap();
+    let files = FILES.map(|file| format!("{}.gz", file));
+    for (file, reference) in files.iter().zip(REFERENCES) {
+        let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+        write!(encoder, ">{}\n{}\n", file, reference).unwrap();
+        fs::write(dir.path().join(file), encoder.finish().unwrap()).unwrap();
+    }
+
+    // Only one pair fits, so shards are split until each has a single k-mer
+    let config = ShardConfig {
+        max_memory: 16,
+        temp_dir: dir.path().to_path_buf(),
+    };
+    let database = Database::from_files_sharded(
+        dir.path(),
+        files.to_vec(),
+        vec![1, 2, 3],
+        KMER_LEN,
+        None,
+        None,
+        &config,
+    )
+    .unwrap();
+
+    let expected = Database::from(
+        create_bitmaps(&REFERENCES),
+        files.to_vec(),
+        vec![1, 2, 3],
+        KMER_LEN,
+        None,
+        None,
+    )
+    .unwrap();
+    assert_eq!(dump_to_bytes(&expected), dump_to_bytes(&database));
+}
+
+#[test]
+fn converts_legacy_database() {
+    // Legacy databases are a bincode Database without a header, with an rle for every k-mer
+    let database = create_database(None);
+    let bitmaps = create_bitmaps(&REFERENCES);
+    let kmers = bitmaps
+        .iter()
+        .flatten()
+        .collect::<RoaringTreemap>()
+        .into_iter()
+        .collect::<Vec<u64>>();
+    let kmer_to_rle_index = kmers
+        .iter()
+        .enumerate()
+        .map(|(rle_index, kmer)| (*kmer as u32, rle_index as u32))
+        .collect::<HashMap<u32, u32>>();
+    let rles = kmers
+        .iter()
+        .map(|kmer| {
+            let indices = (0..bitmaps.len()).filter(|index| bitmaps[*index].contains(*kmer));
+            RunLengthEncoding::from_indices(indices).into_raw_blocks()
+        })
+        .collect::<Vec<Box<[u16]>>>();
+    let legacy = bincode::serialize(&(
+        BinomialConsts::new(),
+        create_files(&FILES),
+        KMER_LEN,
+        kmer_to_rle_index,
+        database.p_values(),
+        rles,
+        None::<(usize, usize)>,
+        vec![1_usize, 2, 3],
+    ))
+    .unwrap();
+
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("legacy.db");
+    fs::write(&path, legacy).unwrap();
+    let converted = Database::load_legacy_from_file(&path, None).unwrap();
+    assert_eq!(dump_to_bytes(&database), dump_to_bytes(&converted));
+
+    // Databases in the current format are not legacy databases
+    database.dump_to_file(File::create(&path).unwrap()).unwrap();
+    match Database::load_legacy_from_file(&path, None) {
+        Err(e) => assert!(e.contains("not a legacy database")),
+        Ok(_) => panic!("a current database was read as a legacy database"),
+    }
+}
+
+#[test]
+fn long_kmers() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("test.skim.db");
+    for kmer_len in [17, 21, 31] {
+        let database = Database::from(
+            create_bitmaps_of_len(&REFERENCES, kmer_len),
+            create_files(&FILES),
+            vec![1, 2, 3],
+            kmer_len,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(KmerIndexKind::Sorted, database.kmer_index().kind());
+
+        // K-mers above 32 bits are not truncated
+        let kmers = database
+            .kmer_index()
+            .iter()
+            .map(|(kmer, _rle_index)| kmer)
+            .collect::<Vec<u64>>();
+        assert!(kmers.iter().any(|kmer| *kmer > u32::MAX as u64));
+        assert!(kmers
+            .iter()
+            .all(|kmer| database.kmer_index().get(*kmer).is_some()));
+
+        database.dump_to_file(File::create(&path).unwrap()).unwrap();
+        let loaded = Database::load_from_file_mmap(&path).unwrap();
+        assert_eq!(classify_all(&database), classify_all(&loaded));
+        assert_eq!(
+            vec![
+                Some(("a.fna".to_string(), 1)),
+                Some(("b.fna".to_string(), 2)),
+                Some(("c.fna".to_string(), 3)),
+            ],
+            classify_all(&loaded)
+        );
+    }
+}
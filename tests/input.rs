@@ -0,0 +1,201 @@
+use bzip2::write::BzEncoder;
+use flate2::write::GzEncoder;
+use skim::input::{mate_id, Compression, PairedRecords, SequenceFormat, SequenceRecords};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::tempdir;
+
+const FASTA: &str = ">r1 first\nACGT\nACGT\n";
+const FASTQ: &str = "@r2\nGGCC\n+\nIIII\n";
+
+// FASTA compressed with `xz`
+const FASTA_XZ: [u8; 84] = [
+    0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00, 0x00, 0x04, 0xe6, 0xd6, 0xb4, 0x46, 0x04, 0xc0, 0x18, 0x14,
+    0x21, 0x01, 0x16, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xfa, 0xdb, 0x09, 0xf5,
+    0x01, 0x00, 0x13, 0x3e, 0x72, 0x31, 0x20, 0x66, 0x69, 0x72, 0x73, 0x74, 0x0a, 0x41, 0x43, 0x47,
+    0x54, 0x0a, 0x41, 0x43, 0x47, 0x54, 0x0a, 0x00, 0xcc, 0x04, 0x22, 0x7d, 0x20, 0xa0, 0x51, 0xd5,
+    0x00, 0x01, 0x34, 0x14, 0xa1, 0x92, 0x76, 0x81, 0x1f, 0xb6, 0xf3, 0x7d, 0x01, 0x00, 0x00, 0x00,
+    0x00, 0x04, 0x59, 0x5a,
+];
+
+// FASTA and then ">r3\nTTTT\n" compressed with `zstd` as two frames
+const FASTA_ZSTD_FRAMES: [u8; 55] = [
+    0x28, 0xb5, 0x2f, 0xfd, 0x24, 0x14, 0xa1, 0x00, 0x00, 0x3e, 0x72, 0x31, 0x20, 0x66, 0x69, 0x72,
+    0x73, 0x74, 0x0a, 0x41, 0x43, 0x47, 0x54, 0x0a, 0x41, 0x43, 0x47, 0x54, 0x0a, 0x16, 0x20, 0x7b,
+    0x08, 0x28, 0xb5, 0x2f, 0xfd, 0x04, 0x58, 0x49, 0x00, 0x00, 0x3e, 0x72, 0x33, 0x0a, 0x54, 0x54,
+    0x54, 0x54, 0x0a, 0x03, 0x6d, 0x84, 0xf1,
+];
+
+fn gzip(contents: &str) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+    encoder.write_all(contents.as_bytes()).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn bzip2(contents: &str) -> Vec<u8> {
+    let mut encoder = BzEncoder::new(vec![], bzip2::Compression::default());
+    encoder.write_all(contents.as_bytes()).unwrap();
+    encoder.finish().unwrap()
+}
+
+// The (format, id, sequence, has quality scores) of each record of the file
+fn read_records(path: &Path) -> (SequenceFormat, Vec<(String, String, bool)>) {
+    let records = SequenceRecords::from_file(path).unwrap();
+    let format = records.format();
+    let records = records
+        .map(|record| {
+            let record = record.unwrap();
+            (
+                record.id().to_string(),
+                String::from_utf8(record.seq().to_vec()).unwrap(),
+                record.qual().is_some(),
+            )
+        })
+        .collect();
+    (format, records)
+}
+
+#[test]
+fn detects_compression() {
+    assert_eq!(Compression::Gzip, Compression::detect(&gzip(FASTA)));
+    assert_eq!(Compression::Bzip2, Compression::detect(&bzip2(FASTA)));
+    assert_eq!(Compression::Xz, Compression::detect(&FASTA_XZ));
+    assert_eq!(Compression::Zstd, Compression::detect(&FASTA_ZSTD_FRAMES));
+    assert_eq!(Compression::None, Compression::detect(FASTA.as_bytes()));
+    assert_eq!(Compression::None, Compression::detect(&[]));
+}
+
+#[test]
+fn reads_compressed_files() {
+    let dir = tempdir().unwrap();
+    let fasta_record = ("r1".to_string(), "ACGTACGT".to_string(), false);
+    let fastq_record = ("r2".to_string(), "GGCC".to_string(), true);
+
+    // Like bgzip, the gzip file has several members
+    let fastq_gz = [gzip(FASTQ), gzip(FASTQ)].concat();
+    for (name, contents, expected) in [
+        (
+            "plain.fa",
+            FASTA.as_bytes().to_vec(),
+            (SequenceFormat::Fasta, vec![fasta_record.clone()]),
+        ),
+        (
+            "members.fq.gz",
+            fastq_gz,
+            (
+                SequenceFormat::Fastq,
+                vec![fastq_record.clone(), fastq_record.clone()],
+            ),
+        ),
+        (
+            "reads.fq.bz2",
+            bzip2(FASTQ),
+            (SequenceFormat::Fastq, vec![fastq_record]),
+        ),
+        (
+            "reads.fa.xz",
+            FASTA_XZ.to_vec(),
+            (SequenceFormat::Fasta, vec![fasta_record.clone()]),
+        ),
+        (
+            "frames.fa.zst",
+            FASTA_ZSTD_FRAMES.to_vec(),
+            (
+                SequenceFormat::Fasta,
+                vec![fasta_record, ("r3".to_string(), "TTTT".to_string(), false)],
+            ),
+        ),
+    ] {
+        let path = dir.path().join(name);
+        fs::write(&path, contents).unwrap();
+        assert_eq!(expected, read_records(&path), "{}", name);
+    }
+
+    // Neither FASTA nor FASTQ
+    let path = dir.path().join("reads.txt");
+    fs::write(&path, "ACGT\n").unwrap();
+    assert!(SequenceRecords::from_file(&path).is_err());
+}
+
+#[test]
+fn pairs_mates() {
+    let dir = tempdir().unwrap();
+    let write = |name: &str, contents: &str| {
+        let path = dir.path().join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    };
+    let ids = |pairs: PairedRecords| {
+        pairs
+            .map(|pair| {
+                pair.map(|(mate_1, mate_2)| (mate_1.id().to_string(), mate_2.id().to_string()))
+            })
+            .collect::<Result<Vec<(String, String)>, String>>()
+    };
+    let expected = vec![
+        ("p1/1".to_string(), "p1/2".to_string()),
+        ("p2".to_string(), "p2".to_string()),
+    ];
+
+    assert_eq!("p1", mate_id("p1/1"));
+    assert_eq!("p1", mate_id("p1/2"));
+    assert_eq!("p1/3", mate_id("p1/3"));
+
+    let r1 = write("r1.fq", "@p1/1\nACGT\n+\nIIII\n@p2 1:N:0\nACGT\n+\nIIII\n");
+    let r2 = write("r2.fq", "@p1/2\nTTTT\n+\nIIII\n@p2 2:N:0\nTTTT\n+\nIIII\n");
+    assert_eq!(
+        Ok(expected.clone()),
+        ids(PairedRecords::from_files(&r1, &r2).unwrap())
+    );
+
+    let interleaved = write(
+        "interleaved.fa",
+        ">p1/1\nACGT\n>p1/2\nTTTT\n>p2\nACGT\n>p2\nTTTT\n",
+    );
+    assert_eq!(
+        Ok(expected),
+        ids(PairedRecords::from_interleaved_file(&interleaved).unwrap())
+    );
+
+    // The mates are out of sync, or a read has no mate
+    let swapped = write("swapped.fa", ">p2\nTTTT\n>p1/2\nTTTT\n");
+    let odd = write("odd.fa", ">p1/1\nACGT\n>p1/2\nTTTT\n>p2\nACGT\n");
+    assert!(ids(PairedRecords::from_files(&r1, &swapped).unwrap()).is_err());
+    assert!(ids(PairedRecords::from_interleaved_file(&odd).unwrap()).is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn reads_named_pipes() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("reads.fifo");
+    assert!(Command::new("mkfifo")
+        .arg(&path)
+        .status()
+        .unwrap()
+        .success());
+
+    // Each read of the pipe returns a single byte, fewer than are needed to detect the compression
+    let writer = {
+        let path = path.clone();
+        thread::spawn(move || {
+            let mut fifo = File::create(path).unwrap();
+            for byte in gzip(FASTQ) {
+                fifo.write_all(&[byte]).unwrap();
+                thread::sleep(Duration::from_millis(1));
+            }
+        })
+    };
+    assert_eq!(
+        (
+            SequenceFormat::Fastq,
+            vec![("r2".to_string(), "GGCC".to_string(), true)]
+        ),
+        read_records(&path)
+    );
+    writer.join().unwrap();
+}
@@ -0,0 +1,122 @@
+use skim::header::{FileHeader, FileKind};
+use skim::io::{create_output_file, dump_data_to_file, load_data_from_file, load_header_from_file};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use tempfile::tempdir;
+
+fn distances_header() -> FileHeader {
+    FileHeader {
+        compression_level: None,
+        kind: FileKind::PairwiseDistances,
+        kmer_len: 15,
+        num_files: 2,
+        syncmer_info: Some((9, 2)),
+    }
+}
+
+#[test]
+fn round_trip() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("test.skim.pd");
+    let data = (
+        vec![vec![0_u32], vec![4, 0]],
+        vec![("a.fna".to_string(), 1_usize)],
+    );
+
+    dump_data_to_file(&distances_header(), &data, File::create(&path).unwrap()).unwrap();
+
+    assert_eq!(distances_header(), load_header_from_file(&path).unwrap());
+    assert_eq!(
+        (distances_header(), data),
+        load_data_from_file(&path, FileKind::PairwiseDistances).unwrap()
+    );
+}
+
+#[test]
+fn wrong_kind() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("test.skim.pd");
+
+    dump_data_to_file(
+        &distances_header(),
+        &vec![0_u32],
+        File::create(&path).unwrap(),
+    )
+    .unwrap();
+
+    let error = load_data_from_file::<Vec<u32>>(&path, FileKind::Database).unwrap_err();
+    assert!(error.contains("pairwise distances (.pd) file"));
+}
+
+#[test]
+fn corrupted_payload() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("test.skim.pd");
+
+    dump_data_to_file(
+        &distances_header(),
+        &vec![0_u32; 8],
+        File::create(&path).unwrap(),
+    )
+    .unwrap();
+
+    // Flip a bit in the payload, just before the trailing checksum
+    let mut bytes = fs::read(&path).unwrap();
+    let payload_byte = bytes.len() - 5;
+    bytes[payload_byte] ^= 1;
+    fs::write(&path, bytes).unwrap();
+
+    let error = load_data_from_file::<Vec<u32>>(&path, FileKind::PairwiseDistances).unwrap_err();
+    assert!(error.contains("checksum mismatch"));
+}
+
+#[test]
+fn missing_magic() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("test.skim.pd");
+
+    // Data written without a header, as older versions of skim did
+    bincode::serialize_into(File::create(&path).unwrap(), &vec![0_u32; 8]).unwrap();
+
+    let error = load_header_from_file(&path).unwrap_err();
+    assert!(error.contains("is not a skim file"));
+}
+
+#[test]
+fn output_locations() {
+    let dir = tempdir().unwrap();
+    create_output_file(dir.path(), "skim.r2f");
+    create_output_file(&dir.path().join("example"), "skim.r2f");
+    assert!(dir.path().join("skim.r2f").exists());
+    assert!(dir.path().join("example.skim.r2f").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn writes_to_named_pipes() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("output.fifo");
+    assert!(std::process::Command::new("mkfifo")
+        .arg(&path)
+        .status()
+        .unwrap()
+        .success());
+
+    // The pipe is written to as is, without adding the extension
+    let reader = {
+        let path = path.clone();
+        std::thread::spawn(move || {
+            let mut contents = String::new();
+            File::open(path)
+                .unwrap()
+                .read_to_string(&mut contents)
+                .unwrap();
+            contents
+        })
+    };
+    create_output_file(&path, "skim.r2f")
+        .write_all(b"U\tr1\t0\t-\n")
+        .unwrap();
+    assert_eq!("U\tr1\t0\t-\n", reader.join().unwrap());
+    assert!(!dir.path().join("output.skim.r2f").exists());
+}
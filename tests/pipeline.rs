@@ -0,0 +1,188 @@
+use skim::pipeline::{run_pipeline, Batch, Batches, ReorderBuffer};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn batches() {
+    let batches = Batches::new(0..5, 2).collect::<Vec<Batch<usize>>>();
+    assert_eq!(
+        vec![
+            Batch {
+                index: 0,
+                items: vec![0, 1]
+            },
+            Batch {
+                index: 1,
+                items: vec![2, 3]
+            },
+            Batch {
+                index: 2,
+                items: vec![4]
+            },
+        ],
+        batches
+    );
+    assert_eq!(0, Batches::new(0..0, 2).count());
+}
+
+#[test]
+fn reorders_batches() {
+    let mut buffer = ReorderBuffer::default();
+
+    // Batches are held until every batch before them has finished
+    assert!(buffer.push(2, "c").is_empty());
+    assert!(buffer.push(1, "b").is_empty());
+    assert_eq!(vec!["a", "b", "c"], buffer.push(0, "a"));
+    assert_eq!(vec!["d"], buffer.push(3, "d"));
+    assert!(buffer.push(5, "f").is_empty());
+    assert_eq!(vec!["e", "f"], buffer.push(4, "e"));
+}
+
+#[test]
+fn bounds_batches_in_flight() {
+    // The first batch is slow, so the reader would otherwise read every batch while it waits to
+    // be written
+    let num_read = AtomicUsize::new(0);
+    let items = (0..100).inspect(|_| {
+        num_read.fetch_add(1, Ordering::Relaxed);
+    });
+    let mut results = vec![];
+    run_pipeline(
+        Batches::new(items, 1),
+        2,
+        1,
+        true,
+        || (),
+        |_state, items| {
+            if items[0] == 0 {
+                thread::sleep(Duration::from_millis(100));
+            }
+            num_read.load(Ordering::Relaxed)
+        },
+        |num_read| results.push(num_read),
+    );
+    assert_eq!(100, results.len());
+    assert!(results[0] <= 4);
+}
+
+// Earlier batches take longer, so they finish after later ones
+fn slow_sum(_state: &mut (), items: Vec<u64>) -> u64 {
+    thread::sleep(Duration::from_millis(20 - items[0] / 10));
+    items.iter().sum()
+}
+
+#[test]
+fn runs_pipeline() {
+    let expected = (0..200)
+        .step_by(10)
+        .map(|start| 10 * start + 45)
+        .collect::<Vec<u64>>();
+
+    let mut ordered = vec![];
+    let stats = run_pipeline(
+        Batches::new(0..200, 10),
+        4,
+        2,
+        true,
+        || (),
+        slow_sum,
+        |sum| ordered.push(sum),
+    );
+    assert_eq!(expected, ordered);
+    assert_eq!(20, stats.num_batches);
+    assert!(stats.work_time >= Duration::from_millis(200));
+
+    let mut unordered = vec![];
+    run_pipeline(
+        Batches::new(0..200, 10),
+        4,
+        2,
+        false,
+        || (),
+        slow_sum,
+        |sum| unordered.push(sum),
+    );
+    unordered.sort();
+    assert_eq!(expected, unordered);
+}
+
+#[test]
+#[should_panic(expected = "bad batch")]
+fn propagates_panics() {
+    run_pipeline(
+        Batches::new(0..100, 1),
+        2,
+        1,
+        true,
+        || (),
+        |_state, items: Vec<u64>| {
+            if items[0] == 50 {
+                panic!("bad batch");
+            }
+            items[0]
+        },
+        |_| {},
+    );
+}
+
+#[test]
+fn stops_after_panics() {
+    // The other worker and the reader stop soon after the first batch panics, instead of going
+    // through the rest of the input while the writer holds every later batch
+    let num_read = AtomicUsize::new(0);
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        run_pipeline(
+            Batches::new(
+                (0..100_000).inspect(|_| {
+                    num_read.fetch_add(1, Ordering::Relaxed);
+                }),
+                1,
+            ),
+            2,
+            1,
+            true,
+            || (),
+            |_state, items: Vec<u64>| {
+                if items[0] == 0 {
+                    panic!("bad batch");
+                }
+                thread::sleep(Duration::from_millis(1));
+                items[0]
+            },
+            |_| {},
+        )
+    }));
+    assert!(result.is_err());
+    assert!(num_read.load(Ordering::Relaxed) < 1000);
+}
+
+#[test]
+fn stops_after_write_panics() {
+    // The writer panics on the slow first batch, while the reader waits for it to be written
+    let (done_sender, done_receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            run_pipeline(
+                Batches::new(0..1000, 1),
+                2,
+                1,
+                true,
+                || (),
+                |_state, items: Vec<u64>| {
+                    if items[0] == 0 {
+                        thread::sleep(Duration::from_millis(50));
+                    }
+                    items[0]
+                },
+                |_| panic!("bad write"),
+            )
+        }));
+        done_sender.send(result.is_err()).unwrap();
+    });
+    assert!(done_receiver
+        .recv_timeout(Duration::from_secs(20))
+        .expect("the pipeline did not stop"));
+}
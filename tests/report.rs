@@ -0,0 +1,85 @@
+use common::write_ncbi_taxonomy;
+use skim::report::{write_file_report, write_taxonomy_report, ReadCounts};
+use skim::taxonomy::Taxonomy;
+use std::fs;
+use tempfile::tempdir;
+
+mod common;
+
+const R2F: &str = "C\tread_1\t562\ta.fna
+U\tread_2\t0\t-
+C\tread_3\t10239\tc.fna
+C\tread_4\t562\ta.fna
+C\tread_5\t1236\t-
+C\tread_6\t10239\tc.fna
+U\tread_7\t0\t-
+C\tread_8\t562\ta.fna
+C\tread_9\t10239\tc.fna
+C\tread_10\t10239\tc.fna
+";
+
+fn read_counts() -> ReadCounts {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("test.skim.r2f");
+    fs::write(&path, R2F).unwrap();
+    ReadCounts::from_r2f(&path).unwrap()
+}
+
+#[test]
+fn counts_r2f() {
+    let counts = read_counts();
+    assert_eq!(10, counts.num_reads());
+    assert_eq!(8, counts.num_classified());
+    assert_eq!(2, counts.unclassified);
+    assert_eq!(Some(&4), counts.tax_ids.get(&10239));
+    assert_eq!(Some(&1), counts.files.get("-"));
+
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("bad.skim.r2f");
+    fs::write(&path, "C\tread_1\tnot_a_taxid\ta.fna\n").unwrap();
+    assert!(ReadCounts::from_r2f(&path).is_err());
+}
+
+#[test]
+fn taxonomy_report() {
+    let dir = tempdir().unwrap();
+    write_ncbi_taxonomy(dir.path());
+    let taxonomy = Taxonomy::from_ncbi(dir.path(), &[562, 1236, 10239]).unwrap();
+
+    let mut report = vec![];
+    write_taxonomy_report(&mut report, &read_counts(), &taxonomy).unwrap();
+    let expected = [
+        " 20.00\t2\t2\tU\t0\tunclassified",
+        " 80.00\t8\t0\tR\t1\troot",
+        " 40.00\t4\t4\tD\t10239\t  Viruses",
+        " 40.00\t4\t0\tR1\t131567\t  cellular organisms",
+        " 40.00\t4\t0\tD\t2\t    Bacteria",
+        " 40.00\t4\t0\tP\t1224\t      Pseudomonadota",
+        " 40.00\t4\t1\tC\t1236\t        Gammaproteobacteria",
+        " 30.00\t3\t0\tO\t91347\t          Enterobacterales",
+        " 30.00\t3\t0\tF\t543\t            Enterobacteriaceae",
+        " 30.00\t3\t0\tG\t561\t              Escherichia",
+        " 30.00\t3\t3\tS\t562\t                Escherichia coli",
+    ];
+    assert_eq!(
+        expected.join("\n") + "\n",
+        String::from_utf8(report).unwrap()
+    );
+}
+
+#[test]
+fn file_report() {
+    let files = ["a.fna", "b.fna", "c.fna"].map(String::from);
+    let mut report = vec![];
+    write_file_report(&mut report, &read_counts(), &files, &[562, 1224, 10239]).unwrap();
+    let expected = [
+        " 20.00\t2\t0\tunclassified",
+        " 40.00\t4\t10239\tc.fna",
+        " 30.00\t3\t562\ta.fna",
+        " 10.00\t1\t0\t-",
+    ];
+    assert_eq!(
+        expected.join("\n") + "\n",
+        String::from_utf8(report).unwrap()
+    );
+}
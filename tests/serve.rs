@@ -0,0 +1,238 @@
+use common::{database, write_ncbi_taxonomy, REFERENCES};
+use skim::big_exp_float::BigExpFloat;
+use skim::classifier::{Classification, Classifier, Decision};
+use skim::serve::{
+    read_request, read_response, serve_connection, write_request, write_response, Action,
+    ActionCounts, Request, Response, Session, TargetMode, Targets,
+};
+use skim::taxonomy::Taxonomy;
+use std::io::{BufReader, Cursor, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::thread;
+use tempfile::tempdir;
+
+mod common;
+
+fn classification(file_indices: Vec<usize>, tax_id: Option<usize>) -> Classification {
+    Classification {
+        file_indices,
+        hit_lookup_time: 0.0,
+        n_total: 0,
+        prob_calc_time: 0.0,
+        tax_id,
+    }
+}
+
+#[test]
+fn chooses_actions() {
+    let dir = tempdir().unwrap();
+    write_ncbi_taxonomy(dir.path());
+    let database = database();
+    let taxonomy = Taxonomy::from_ncbi(dir.path(), database.tax_ids()).unwrap();
+
+    // A tax id also targets its descendants if the taxonomy is known
+    let targets = |taxonomy, mode| Targets::new(&database, &[1236], &[], taxonomy, mode);
+    assert_eq!(
+        2,
+        targets(Some(&taxonomy), TargetMode::Enrich).num_targets()
+    );
+    assert_eq!(1, targets(None, TargetMode::Enrich).num_targets());
+    let files = Targets::new(
+        &database,
+        &[],
+        &["c.fna".to_string()],
+        None,
+        TargetMode::Enrich,
+    );
+    assert!(files.is_target(&classification(vec![2], Some(10239))));
+    assert!(!files.is_target(&classification(vec![], None)));
+
+    let e_coli = classification(vec![0], Some(562));
+    let virus = classification(vec![2], Some(10239));
+    let unclassified = classification(vec![], None);
+    for (mode, decision, classification, action) in [
+        (
+            TargetMode::Enrich,
+            Decision::NeedsMoreData,
+            &unclassified,
+            Action::Proceed,
+        ),
+        (
+            TargetMode::Enrich,
+            Decision::Classified,
+            &e_coli,
+            Action::StopReceiving,
+        ),
+        (
+            TargetMode::Enrich,
+            Decision::Classified,
+            &virus,
+            Action::Unblock,
+        ),
+        (
+            TargetMode::Enrich,
+            Decision::Rejected,
+            &unclassified,
+            Action::Unblock,
+        ),
+        (
+            TargetMode::Deplete,
+            Decision::NeedsMoreData,
+            &unclassified,
+            Action::Proceed,
+        ),
+        (
+            TargetMode::Deplete,
+            Decision::Classified,
+            &e_coli,
+            Action::Unblock,
+        ),
+        (
+            TargetMode::Deplete,
+            Decision::Classified,
+            &virus,
+            Action::StopReceiving,
+        ),
+        (
+            TargetMode::Deplete,
+            Decision::Rejected,
+            &unclassified,
+            Action::StopReceiving,
+        ),
+    ] {
+        assert_eq!(
+            action,
+            targets(Some(&taxonomy), mode).action(decision, classification)
+        );
+    }
+}
+
+#[test]
+fn reads_and_writes_messages() {
+    let response = Response {
+        action: Action::StopReceiving,
+        file: Some("c.fna".to_string()),
+        read_id: "r1".to_string(),
+        tax_id: Some(10239),
+    };
+    let unclassified = Response {
+        action: Action::Unblock,
+        file: None,
+        read_id: "r2".to_string(),
+        tax_id: None,
+    };
+    let mut bytes = vec![];
+    write_response(&mut bytes, &response).unwrap();
+    write_response(&mut bytes, &unclassified).unwrap();
+    let mut reader = Cursor::new(&bytes);
+    assert_eq!(Ok(Some(response)), read_response(&mut reader));
+    assert_eq!(Ok(Some(unclassified)), read_response(&mut reader));
+    assert_eq!(Ok(None), read_response(&mut reader));
+
+    let mut bytes = vec![];
+    write_request(&mut bytes, "r1", b"ACGT").unwrap();
+    assert_eq!(
+        Ok(Some(Request {
+            chunk: b"ACGT".to_vec(),
+            read_id: "r1".to_string()
+        })),
+        read_request(&mut Cursor::new(&bytes))
+    );
+
+    // The stream ends in the middle of a request, or an action is unknown
+    assert!(read_request(&mut Cursor::new(&bytes[..bytes.len() - 1])).is_err());
+    assert_eq!(Ok(Action::Unblock), Action::from_code(2));
+    assert_eq!("stop_receiving", Action::StopReceiving.as_str());
+    assert!(Action::from_code(3).is_err());
+}
+
+#[test]
+fn serves_mock_client() {
+    let database = database();
+    let classifier = Classifier::new(&database, BigExpFloat::from_f64(1e-20), 100);
+    let targets = Targets::new(&database, &[10239], &[], None, TargetMode::Enrich);
+    let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let address = listener.local_addr().unwrap();
+
+    thread::scope(|scope| {
+        let server = scope.spawn(|| {
+            let (stream, _) = listener.accept().unwrap();
+            let mut session = Session::new(&classifier, &targets, Some(200), 100);
+            serve_connection(&mut session, stream.try_clone().unwrap(), stream).unwrap();
+            (session.action_counts(), session.num_reads())
+        });
+
+        // Sends the chunks of a read until it gets an action other than proceed
+        let stream = TcpStream::connect(address).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut send_read = |read_id: &str, chunks: Vec<&[u8]>| {
+            for chunk in chunks {
+                write_request(&mut writer, read_id, chunk).unwrap();
+                writer.flush().unwrap();
+                let response = read_response(&mut reader).unwrap().unwrap();
+                assert_eq!(read_id, response.read_id);
+                if response.action != Action::Proceed {
+                    return response;
+                }
+            }
+            panic!("{} got no decision", read_id);
+        };
+
+        // The virus is a target, but the E. coli is not (and is decided while it only hits the start
+        // of the sequence, which the smaller Gammaproteobacteria file has), and the short read ends
+        // before it can be classified
+        let virus = send_read("virus", REFERENCES[1].as_bytes().chunks(7).collect());
+        assert_eq!(Action::StopReceiving, virus.action);
+        assert_eq!(Some(10239), virus.tax_id);
+        assert_eq!(Some("c.fna".to_string()), virus.file);
+        let e_coli = send_read("e_coli", REFERENCES[0].as_bytes().chunks(7).collect());
+        assert_eq!(Action::Unblock, e_coli.action);
+        assert_eq!(Some("b.fna".to_string()), e_coli.file);
+        let short = send_read("short", vec![b"ACGTACGT", b""]);
+        assert_eq!(Action::Unblock, short.action);
+        assert_eq!(None, short.tax_id);
+
+        // The client starts a read and disconnects
+        write_request(&mut writer, "unfinished", b"ACGTACGT").unwrap();
+        writer.flush().unwrap();
+        assert_eq!(
+            Action::Proceed,
+            read_response(&mut reader).unwrap().unwrap().action
+        );
+        stream.shutdown(Shutdown::Write).unwrap();
+        let (counts, num_reads) = server.join().unwrap();
+        assert_eq!(1, counts.stop_receiving);
+        assert_eq!(2, counts.unblock);
+        assert!(counts.proceed > 2);
+        assert_eq!(1, num_reads);
+        assert_ne!(ActionCounts::default(), counts);
+    });
+}
+
+#[test]
+fn evicts_oldest_reads() {
+    let database = database();
+    let classifier = Classifier::new(&database, BigExpFloat::from_f64(1e-20), 100);
+    let targets = Targets::new(&database, &[10239], &[], None, TargetMode::Enrich);
+    let mut session = Session::new(&classifier, &targets, None, 2);
+    let mut send = |read_id: &str, chunk: &[u8]| {
+        let request = Request {
+            chunk: chunk.to_vec(),
+            read_id: read_id.to_string(),
+        };
+        assert_eq!(Action::Proceed, session.handle(&request).action);
+        (
+            session.num_reads(),
+            ["r1", "r2", "r3"].map(|read_id| session.is_reading(read_id)),
+        )
+    };
+
+    // The client stops sending chunks of r2 without ending it, so r3 evicts it (although r1 is
+    // older), and a later chunk of r2 starts it again
+    assert_eq!((1, [true, false, false]), send("r1", b"ACGTACGT"));
+    assert_eq!((2, [true, true, false]), send("r2", b"ACGTACGT"));
+    assert_eq!((2, [true, true, false]), send("r1", b"ACGTACGT"));
+    assert_eq!((2, [true, false, true]), send("r3", b"ACGTACGT"));
+    assert_eq!((2, [false, true, true]), send("r2", b"ACGTACGT"));
+}
@@ -0,0 +1,192 @@
+use common::write_ncbi_taxonomy;
+use roaring::RoaringTreemap;
+use skim::big_exp_float::BigExpFloat;
+use skim::database::Database;
+use skim::kmer_iter::CanonicalKmerIter;
+use skim::taxonomy::{load_ncbi_merged, Taxonomy};
+use std::collections::HashMap;
+use std::fs::File;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn prunes_ncbi_taxonomy() {
+    let dir = tempdir().unwrap();
+    write_ncbi_taxonomy(dir.path());
+
+    let taxonomy = Taxonomy::from_ncbi(dir.path(), &[562, 1224]).unwrap();
+    assert_eq!(9, taxonomy.len());
+    assert!(!taxonomy.contains(10239));
+    assert_eq!(
+        vec![1, 131567, 2, 1224, 1236, 91347, 543, 561, 562],
+        taxonomy.lineage(562)
+    );
+    assert_eq!(Some("Escherichia"), taxonomy.name(561));
+    assert_eq!(Some("species"), taxonomy.rank(562));
+    assert_eq!(Some(561), taxonomy.parent(562));
+    assert_eq!(None, taxonomy.parent(1));
+    assert_eq!(Some(1224), taxonomy.lca(&[562, 1224]));
+    assert_eq!(Some(562), taxonomy.lca(&[562]));
+    assert_eq!(None, taxonomy.lca(&[562, 10239]));
+
+    let pruned = taxonomy.pruned(&[1224]).unwrap();
+    assert_eq!(vec![1, 131567, 2, 1224], pruned.lineage(1224));
+    assert!(pruned.lineage(562).is_empty());
+    assert_eq!(taxonomy, pruned.merged(&taxonomy));
+
+    assert!(Taxonomy::from_ncbi(dir.path(), &[562, 3])
+        .unwrap_err()
+        .contains("tax id 3"));
+}
+
+#[test]
+fn embedded_in_database() {
+    let dir = tempdir().unwrap();
+    write_ncbi_taxonomy(dir.path());
+
+    let bitmaps = vec![
+        RoaringTreemap::from_iter([1, 2, 3]),
+        RoaringTreemap::from_iter([2, 3, 4]),
+    ];
+    let tax_ids = vec![562, 10239];
+    let mut database = Database::from(
+        bitmaps,
+        vec!["a.fna".to_string(), "b.fna".to_string()],
+        tax_ids.clone(),
+        7,
+        None,
+        None,
+    )
+    .unwrap();
+    assert!(database.taxonomy().is_none());
+
+    // Every tax id of the database must be in the taxonomy
+    let partial = Taxonomy::from_ncbi(dir.path(), &[562]).unwrap();
+    assert!(database.set_taxonomy(partial).is_err());
+    let taxonomy = Taxonomy::from_ncbi(dir.path(), &tax_ids).unwrap();
+    database.set_taxonomy(taxonomy.clone()).unwrap();
+
+    let path = dir.path().join("test.skim.db");
+    database.dump_to_file(File::create(&path).unwrap()).unwrap();
+    let mut loaded = Database::load_from_file(&path).unwrap();
+    assert_eq!(Some(&taxonomy), loaded.taxonomy());
+
+    // Removing a file also removes the part of the taxonomy only it needed
+    loaded.remove_files(&[1]).unwrap();
+    assert!(!loaded.taxonomy().unwrap().contains(10239));
+    assert_eq!(
+        Some("Escherichia coli"),
+        loaded.taxonomy().unwrap().name(562)
+    );
+}
+
+#[test]
+fn update_tax_ids() {
+    let dir = tempdir().unwrap();
+    write_ncbi_taxonomy(dir.path());
+    assert_eq!(
+        HashMap::from([(1278, 1224)]),
+        load_ncbi_merged(dir.path()).unwrap()
+    );
+
+    let tax_ids = vec![1236, 10239];
+    let mut database = Database::from(
+        vec![
+            RoaringTreemap::from_iter([1, 2, 3]),
+            RoaringTreemap::from_iter([2, 3, 4]),
+        ],
+        vec!["a.fna".to_string(), "b.fna".to_string()],
+        tax_ids.clone(),
+        7,
+        None,
+        None,
+    )
+    .unwrap();
+    database
+        .set_taxonomy(Taxonomy::from_ncbi(dir.path(), &tax_ids).unwrap())
+        .unwrap();
+
+    // Nothing is updated if any file or tax id is bad
+    let update =
+        |file: &str, tax_id| vec![("b.fna".to_string(), 10239), (file.to_string(), tax_id)];
+    for (file2taxid, error) in [
+        (update("c.fna", 562), "file c.fna"),
+        (update("b.fna", 562), "more than once"),
+        (update("a.fna", 562), "tax id 562"),
+    ] {
+        assert!(database
+            .update_tax_ids(&file2taxid, None)
+            .unwrap_err()
+            .contains(error));
+    }
+    assert_eq!(&tax_ids[..], database.tax_ids());
+
+    // Tax ids outside the embedded taxonomy need a new taxonomy, which is pruned
+    let taxonomy = Taxonomy::from_ncbi(dir.path(), &[562, 1224, 10239]).unwrap();
+    let changes = database
+        .update_tax_ids(&update("a.fna", 562), Some(taxonomy))
+        .unwrap();
+    assert_eq!(vec![(0, 1236)], changes);
+    assert_eq!(&[562, 10239], database.tax_ids());
+    assert_eq!(
+        &Taxonomy::from_ncbi(dir.path(), &[562, 10239]).unwrap(),
+        database.taxonomy().unwrap()
+    );
+}
+
+#[test]
+fn lca_classification() {
+    const KMER_LEN: usize = 11;
+    let dir = tempdir().unwrap();
+    write_ncbi_taxonomy(dir.path());
+
+    let references = [
+        "ACGTTGCATGCATCGATCGGGATCGATCGTAGCTAGCTAGCATCGACTAGCTAGCGGCGATCGATTTAGCGAGCTACG",
+        "GGGCATCGACTAGCATCAGCGACTACGCGCGCATCTACGACTAGCAGCTACGACATCGACGTTTAGCGCGAGCAGCAT",
+    ];
+    // The first reference is in both an E. coli and a Gammaproteobacteria file, but the second
+    // file only has half of it
+    let sequences = [references[0], &references[0][..40], references[1]];
+    let bitmaps = sequences
+        .iter()
+        .map(|sequence| {
+            CanonicalKmerIter::from(sequence.as_bytes(), KMER_LEN, None)
+                .map(|kmer| kmer as u64)
+                .collect::<RoaringTreemap>()
+        })
+        .collect::<Vec<RoaringTreemap>>();
+    let tax_ids = vec![562, 1236, 10239];
+    let database = Database::from(
+        bitmaps,
+        vec![
+            "a.fna".to_string(),
+            "b.fna".to_string(),
+            "c.fna".to_string(),
+        ],
+        tax_ids.clone(),
+        KMER_LEN,
+        None,
+        None,
+    )
+    .unwrap();
+    let taxonomy = Taxonomy::from_ncbi(dir.path(), &tax_ids).unwrap();
+
+    let lookup_table = database.compute_loookup_table(100);
+    let classify_lca = |read: &str, max_log_ratio| {
+        database
+            .classify_lca(
+                read.as_bytes(),
+                BigExpFloat::from_f64(1e-12),
+                100,
+                &lookup_table,
+                max_log_ratio,
+                &taxonomy,
+            )
+            .0
+    };
+    assert_eq!(Some((vec![0, 1], 1236)), classify_lca(references[0], None));
+    assert_eq!(Some((vec![0], 562)), classify_lca(references[0], Some(1.0)));
+    assert_eq!(Some((vec![2], 10239)), classify_lca(references[1], None));
+    assert_eq!(None, classify_lca("ACGT", None));
+}
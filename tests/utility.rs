@@ -0,0 +1,29 @@
+use skim::utility::{compute_total_kmers, parse_kmer_len, parse_memory_size};
+
+#[test]
+fn memory_sizes() {
+    assert_eq!(Ok(100), parse_memory_size("100"));
+    assert_eq!(Ok(512 << 20), parse_memory_size("512M"));
+    assert_eq!(Ok(16 << 30), parse_memory_size("16GiB"));
+    assert_eq!(Ok(2 << 40), parse_memory_size("2tb"));
+    assert!(parse_memory_size("16X").is_err());
+    assert!(parse_memory_size("G").is_err());
+}
+
+#[test]
+fn kmer_lengths() {
+    assert_eq!(Ok(15), parse_kmer_len("15"));
+    assert_eq!(Ok(31), parse_kmer_len("31"));
+    assert!(parse_kmer_len("0").is_err());
+    assert!(parse_kmer_len("32").is_err());
+}
+
+#[test]
+fn total_kmers() {
+    // A/T and C/G
+    assert_eq!(2, compute_total_kmers(1, None));
+    // 16 2-mers, 4 of which (e.g. AT) are their own reverse complement
+    assert_eq!(10, compute_total_kmers(2, None));
+    assert_eq!(1 << 29, compute_total_kmers(15, None));
+    assert_eq!(1 << 61, compute_total_kmers(31, None));
+}